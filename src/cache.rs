@@ -11,21 +11,26 @@ error_chain! {
     }
 }
 
-use crate::{config::Config, local::model::Card as LocalCard, remote::model::Card as RemoteCard};
+use crate::{
+    config::Config,
+    local::{self, model::Card as LocalCard},
+    remote::model::Card as RemoteCard,
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CacheItem {
     pub name: String,
     pub etag: String,
     pub local_date: DateTime<Utc>,
     pub remote_date: DateTime<Utc>,
+    pub local_hash: String,
 }
 
 impl ToString for CacheItem {
     fn to_string(&self) -> String {
         format!(
-            "{};{};{};{}",
-            self.name, self.etag, self.local_date, self.remote_date
+            "{};{};{};{};{}",
+            self.name, self.etag, self.local_date, self.remote_date, self.local_hash
         )
     }
 }
@@ -57,6 +62,9 @@ impl FromStr for CacheItem {
                 .ok_or(ErrorKind::ParseCacheItemRemoteDateNotFoundErr)?
                 .parse()
                 .chain_err(|| "Could not parse cache item remote date")?,
+            // Caches written before content hashing do not have this field,
+            // in which case change detection falls back to the date only.
+            local_hash: tokens.next().unwrap_or_default().trim().to_string(),
         })
     }
 }
@@ -68,38 +76,65 @@ pub struct Cache {
 }
 
 impl Cache {
-    pub fn build_and_write(
-        config: &Config,
+    /// Builds the cache from the cards existing on both sides.
+    pub fn build(
         ctag: String,
-        lcards: HashMap<String, LocalCard>,
-        rcards: HashMap<String, RemoteCard>,
-    ) -> Result<()> {
-        let cards = lcards
-            .iter()
-            .fold(HashMap::new(), |mut cache, (name, lcard)| {
-                match rcards.get(name) {
-                    None => cache,
-                    Some(rcard) => {
-                        cache.insert(
-                            name.to_owned(),
-                            CacheItem {
-                                name: name.to_owned(),
-                                etag: rcard.etag.to_owned(),
-                                local_date: lcard.date,
-                                remote_date: rcard.date,
-                            },
-                        );
-                        cache
-                    }
-                }
-            })
-            .iter()
-            .map(|(_, card)| card.to_string())
-            .collect::<Vec<_>>()
-            .join("\n");
-
-        fs::write(config.file_path(".cache"), format!("{}\n{}", ctag, cards))
-            .chain_err(|| "Could not write cache")
+        lcards: &HashMap<String, LocalCard>,
+        rcards: &HashMap<String, RemoteCard>,
+    ) -> Self {
+        let mut cards = HashMap::new();
+
+        for (name, lcard) in lcards.iter() {
+            if let Some(rcard) = rcards.get(name) {
+                let card = CacheItem {
+                    name: name.to_owned(),
+                    etag: rcard.etag.to_owned(),
+                    local_date: lcard.date,
+                    remote_date: rcard.date,
+                    local_hash: local::repository::hash_card(lcard),
+                };
+                cards.insert(name.to_owned(), card);
+            }
+        }
+
+        Self { ctag, cards }
+    }
+
+    /// Renames the items of a cache written before the cards were named by
+    /// their UID, when they were still named after their file stem.
+    pub fn migrate_names(&self, lcards: &HashMap<String, LocalCard>) -> Self {
+        let mut cards = self.cards.clone();
+
+        for (name, lcard) in lcards.iter() {
+            let stem = match lcard.path.file_stem() {
+                Some(stem) => stem.to_string_lossy().to_string(),
+                None => continue,
+            };
+            if cards.contains_key(name) || lcards.contains_key(&stem) {
+                continue;
+            }
+            if let Some(mut card) = cards.remove(&stem) {
+                card.name = name.to_owned();
+                cards.insert(name.to_owned(), card);
+            }
+        }
+
+        Self {
+            ctag: self.ctag.to_owned(),
+            cards,
+        }
+    }
+
+    pub fn write(&self, config: &Config) -> Result<()> {
+        let mut cards: Vec<_> = self.cards.values().map(|card| card.to_string()).collect();
+        cards.sort();
+        let cards = cards.join("\n");
+
+        fs::write(
+            config.file_path(".cache"),
+            format!("{}\n{}", self.ctag, cards),
+        )
+        .chain_err(|| "Could not write cache")
     }
 
     pub fn from_file(config: &Config) -> Result<Self> {
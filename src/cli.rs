@@ -1,56 +1,1110 @@
-use clap::{self, SubCommand};
-use error_chain::error_chain;
-use reqwest::Client;
-use std::env;
+use clap::{self, Arg, SubCommand};
+use error_chain::{bail, error_chain};
+use log::warn;
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+use uuid::Uuid;
 
-use crate::config::Config;
-use crate::{cache::Cache, local, remote};
+use crate::{
+    cache::Cache,
+    config::Config,
+    configure,
+    contact::{self, Contact},
+    convert, credentials, dedupe,
+    format::{csv, jcard, ldif},
+    local::{self, repository::LocalRepository},
+    lock::Lock,
+    photo, remote, sync,
+    validate::{self, Severity},
+    vcard::{self, Version},
+    vdirsyncer,
+};
 
 error_chain! {
     links {
         Config(crate::config::Error, crate::config::ErrorKind);
+        Configure(configure::Error, configure::ErrorKind);
+        Contact(crate::contact::Error, crate::contact::ErrorKind);
+        Credentials(credentials::Error, credentials::ErrorKind);
+        Csv(csv::Error, csv::ErrorKind);
+        Jcard(jcard::Error, jcard::ErrorKind);
+        Ldif(ldif::Error, ldif::ErrorKind);
         Cache(crate::cache::Error, crate::cache::ErrorKind);
         LocalRepository(local::repository::Error, local::repository::ErrorKind);
         RemoteRepository(remote::repository::Error, remote::repository::ErrorKind);
+        Lock(crate::lock::Error, crate::lock::ErrorKind);
+        Photo(crate::photo::Error, crate::photo::ErrorKind);
+        Sync(crate::sync::Error, crate::sync::ErrorKind);
+        Validate(crate::validate::Error, crate::validate::ErrorKind);
+        Vdirsyncer(crate::vdirsyncer::Error, crate::vdirsyncer::ErrorKind);
     }
 }
 
+fn output_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("output")
+        .long("output")
+        .short("o")
+        .help("Defines the output format")
+        .value_name("FORMAT")
+        .possible_values(&["table", "plain", "json", "jcard"])
+        .default_value("table")
+}
+
+fn format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("format")
+        .help("Format of the contacts")
+        .possible_values(&["csv", "jcard", "ldif"])
+        .required(true)
+}
+
+/// Reads the local cards as contacts, sorted by name.
+fn read_contacts(config: &Config) -> Result<Vec<Contact>> {
+    let local_repo = local::repository::from_config(config)?;
+    let mut contacts: Vec<_> = local_repo
+        .list()?
+        .values()
+        .map(|card| Contact::from_vcard(&card.content))
+        .collect();
+    contacts.sort_by_key(|contact| contact.name.to_lowercase());
+
+    Ok(contacts)
+}
+
+/// Finds a local card by its name, or by a query matching only one contact.
+fn find_card(local_repo: &dyn LocalRepository, query: &str) -> Result<local::model::Card> {
+    if let Some(card) = local_repo.read(query)? {
+        return Ok(card);
+    }
+
+    let mut cards: Vec<_> = local_repo
+        .list()?
+        .into_values()
+        .filter(|card| Contact::from_vcard(&card.content).matches(query, None))
+        .collect();
+
+    match cards.len() {
+        0 => bail!("No card matching {:?}", query),
+        1 => Ok(cards.remove(0)),
+        _ => {
+            let mut names: Vec<_> = cards.into_iter().map(|card| card.name).collect();
+            names.sort();
+            bail!("Many cards matching {:?}: {}", query, names.join(", "))
+        }
+    }
+}
+
+/// Checks if a card is designated exactly by a query: by its name, its UID
+/// or its full name.
+fn is_exact_match(card: &local::model::Card, query: &str) -> bool {
+    let contact = Contact::from_vcard(&card.content);
+    card.name == query || contact.uid.as_deref() == Some(query) || contact.name == query
+}
+
+/// Opens the content in the editor from `$EDITOR`, then returns the edited
+/// content. The draft is a temporary file only readable by its owner.
+fn edit_content(name: &str, content: &str) -> Result<String> {
+    let mut draft = tempfile::Builder::new()
+        .prefix(&format!("cardamom-{}-", name))
+        .suffix(".vcf")
+        .tempfile()
+        .chain_err(|| "Could not create draft")?;
+    let path = draft.path().to_owned();
+    draft
+        .write_all(content.as_bytes())
+        .and_then(|_| draft.flush())
+        .chain_err(|| format!("Could not write draft {:?}", path))?;
+
+    let editor = env::var("EDITOR").unwrap_or_else(|_| String::from("vi"));
+    let status = Command::new(&editor)
+        .arg(&path)
+        .status()
+        .chain_err(|| format!("Could not run editor {:?}", editor))?;
+    if !status.success() {
+        bail!("Editor {:?} exited with {}", editor, status);
+    }
+
+    // Editors may replace the draft instead of writing it, so it is read
+    // again from its path.
+    let content =
+        fs::read_to_string(&path).chain_err(|| format!("Could not read draft {:?}", path))?;
+    draft
+        .close()
+        .chain_err(|| format!("Could not remove draft {:?}", path))?;
+
+    Ok(content)
+}
+
+/// Checks that an edited content is still one valid card.
+fn check_edited_card(content: &str) -> Result<()> {
+    let cards = vcard::split(content);
+    if cards.len() != 1 {
+        bail!("Expected one card, found {}", cards.len());
+    }
+
+    let errors: Vec<_> = validate::check(content)
+        .into_iter()
+        .filter(|problem| problem.severity() == Severity::Error)
+        .map(|problem| problem.to_string())
+        .collect();
+    if !errors.is_empty() {
+        bail!("{}", errors.join(", "));
+    }
+
+    Ok(())
+}
+
+/// Adds a card built from the contact, with a new UID as name. Returns the
+/// name of the card.
+fn add_card(local_repo: &mut dyn LocalRepository, mut contact: Contact) -> Result<String> {
+    let uid = Uuid::new_v4().to_string();
+    contact.uid = Some(uid.to_owned());
+    let mut cards = HashMap::new();
+    cards.insert(uid.to_owned(), contact.to_vcard());
+    local_repo.write(&cards)?;
+
+    Ok(uid)
+}
+
+/// Writes the edited content of a card, unless the card changed since it
+/// was read, like when a sync ran during the edition. The lock must be held
+/// by the caller.
+fn write_edited_card(
+    local_repo: &mut dyn LocalRepository,
+    card: &local::model::Card,
+    content: String,
+) -> Result<()> {
+    match local_repo.read(&card.name)? {
+        Some(ref current) if current.content == card.content => (),
+        Some(_) => bail!(
+            "Card {} changed during the edition, edit it again",
+            card.name
+        ),
+        None => bail!("Card {} deleted during the edition", card.name),
+    }
+
+    let mut cards = HashMap::new();
+    cards.insert(card.name.to_owned(), content);
+    local_repo.write(&cards)?;
+
+    Ok(())
+}
+
+/// Deletes the card matching the query. A card that is not designated
+/// exactly is only deleted once confirmed. Returns the deleted card.
+fn delete_card(
+    local_repo: &mut dyn LocalRepository,
+    query: &str,
+    confirm: impl FnOnce(&local::model::Card) -> Result<bool>,
+) -> Result<Option<local::model::Card>> {
+    let card = find_card(local_repo, query)?;
+    if !is_exact_match(&card, query) && !confirm(&card)? {
+        return Ok(None);
+    }
+    local_repo.delete(&[card.name.to_owned()])?;
+
+    Ok(Some(card))
+}
+
+/// Synchronizes the local cards with the remote ones. The lock must be held
+/// by the caller.
+async fn sync(config: &Config) -> Result<()> {
+    let cache = Cache::from_file(config)?;
+    let client = remote::repository::client(config)?;
+    let mut remote_repo = remote::repository::from_config(config, &client).await?;
+    let mut local_repo = local::repository::from_config(config)?;
+
+    sync::sync(config, &cache, local_repo.as_mut(), remote_repo.as_mut()).await?;
+
+    Ok(())
+}
+
+/// Asks a question on the standard output and reads the answer from the
+/// standard input.
+fn prompt(question: &str) -> Result<String> {
+    print!("{} ", question);
+    io::stdout()
+        .flush()
+        .chain_err(|| "Could not flush stdout")?;
+
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .chain_err(|| "Could not read answer")?;
+
+    Ok(answer.trim().to_owned())
+}
+
+/// Asks to pick one of the given choices, the first one being the default.
+/// Returns `None` if the user chose to skip.
+fn prompt_choice(question: &str, choices: &[String]) -> Result<Option<usize>> {
+    for (i, choice) in choices.iter().enumerate() {
+        println!("  [{}] {}", i + 1, choice);
+    }
+
+    loop {
+        let answer = prompt(&format!(
+            "{} [1-{}, s to skip, default 1]",
+            question,
+            choices.len()
+        ))?;
+        match answer.as_str() {
+            "" => return Ok(Some(0)),
+            "s" | "S" => return Ok(None),
+            answer => match answer.parse::<usize>() {
+                Ok(i) if i >= 1 && i <= choices.len() => return Ok(Some(i - 1)),
+                _ => println!("Invalid choice {:?}", answer),
+            },
+        }
+    }
+}
+
+fn push_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("push")
+        .long("push")
+        .short("p")
+        .help("Synchronizes cards right after the change")
+}
+
+fn wait_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("wait")
+        .long("wait")
+        .short("w")
+        .help("Waits for other cardamom runs to finish instead of exiting")
+}
+
 pub async fn run() -> Result<()> {
     let matches = clap::App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
         .author(env!("CARGO_PKG_AUTHORS"))
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .short("c")
+                .global(true)
+                .help("Reads the config from this file (defaults to `CARDAMOM_CONFIG`, then the XDG location)")
+                .value_name("PATH"),
+        )
+        .subcommand(
+            SubCommand::with_name("configure")
+                .about("Asks for the server and the credentials, then writes the config file"),
+        )
         .subcommand(
             SubCommand::with_name("init")
                 .aliases(&["i"])
-                .about("Inits local sync dir"),
+                .about("Inits local sync dir")
+                .arg(wait_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("login")
+                .about("Stores the password, in the password file if `passwd-file` is set or in the Secret Service"),
+        )
+        .subcommand(
+            SubCommand::with_name("import-state")
+                .about("Builds the cache from a vdirsyncer status file")
+                .arg(
+                    Arg::with_name("path")
+                        .help("Path of the status file (`<pair>/<collection>.items`)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("swap")
+                        .long("swap")
+                        .help("Considers the side `b` of the pair as local instead of `a`"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .short("f")
+                        .help("Overrides the existing cache"),
+                )
+                .arg(wait_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .aliases(&["l"])
+                .about("Lists local contacts")
+                .arg(output_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("search")
+                .about("Searches local contacts")
+                .arg(
+                    Arg::with_name("query")
+                        .help("Text to search, case insensitive")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("field")
+                        .long("field")
+                        .short("f")
+                        .help("Searches only in name, email, phone, org or any property")
+                        .value_name("FIELD"),
+                )
+                .arg(output_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("query")
+                .about("Searches contact emails, in the mutt and aerc query format")
+                .arg(
+                    Arg::with_name("query")
+                        .help("Text to search, case insensitive")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("add")
+                .aliases(&["a"])
+                .about("Adds a new contact")
+                .arg(Arg::with_name("name").help("Full name").required(true))
+                .arg(
+                    Arg::with_name("email")
+                        .long("email")
+                        .short("e")
+                        .help("Adds an email, can be repeated")
+                        .value_name("EMAIL")
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("phone")
+                        .long("phone")
+                        .short("t")
+                        .help("Adds a phone number, can be repeated")
+                        .value_name("PHONE")
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("org")
+                        .long("org")
+                        .help("Defines the organization")
+                        .value_name("ORG"),
+                )
+                .arg(push_arg())
+                .arg(wait_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("edit")
+                .aliases(&["e"])
+                .about("Edits a contact with $EDITOR")
+                .arg(
+                    Arg::with_name("card")
+                        .help("Name of the card, or query matching one contact")
+                        .required(true),
+                )
+                .arg(push_arg())
+                .arg(wait_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .aliases(&["d"])
+                .about("Deletes a contact")
+                .arg(
+                    Arg::with_name("card")
+                        .help("Name of the card, or query matching one contact")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("yes")
+                        .long("yes")
+                        .short("y")
+                        .help("Deletes a contact matched by a query without confirmation"),
+                )
+                .arg(push_arg())
+                .arg(wait_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("photo")
+                .about("Gets or sets the photo of a contact")
+                .subcommand(
+                    SubCommand::with_name("get")
+                        .about("Saves the photo of a contact to a file")
+                        .arg(
+                            Arg::with_name("card")
+                                .help("Name of the card, or query matching one contact")
+                                .required(true),
+                        )
+                        .arg(Arg::with_name("path").help("Writes to this file instead of stdout")),
+                )
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Sets the photo of a contact from an image file")
+                        .arg(
+                            Arg::with_name("card")
+                                .help("Name of the card, or query matching one contact")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("path")
+                                .help("Image file (JPEG, PNG, GIF, WebP or BMP)")
+                                .required(true),
+                        )
+                        .arg(push_arg())
+                        .arg(wait_arg()),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Converts cards to another vCard version")
+                .arg(
+                    Arg::with_name("version")
+                        .help("Target version")
+                        .possible_values(&["2.1", "3.0", "4.0"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .help("Converts this file to stdout instead of the local cards"),
+                )
+                .arg(push_arg())
+                .arg(wait_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Exports the local cards to another format")
+                .arg(format_arg())
+                .arg(Arg::with_name("path").help("Writes to this file instead of stdout")),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Imports contacts from another format as new cards")
+                .arg(format_arg())
+                .arg(
+                    Arg::with_name("path")
+                        .help("File to import, - for stdin")
+                        .required(true),
+                )
+                .arg(push_arg())
+                .arg(wait_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("dedupe")
+                .about("Finds and merges duplicate contacts")
+                .arg(
+                    Arg::with_name("auto")
+                        .long("auto")
+                        .short("a")
+                        .help(
+                            "Merges cards sharing an email without asking, \
+                             into the most complete card",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .short("n")
+                        .help("Only shows the duplicates"),
+                )
+                .arg(push_arg())
+                .arg(wait_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .aliases(&["check"])
+                .about("Checks the local cards for structural problems")
+                .arg(
+                    Arg::with_name("fix")
+                        .long("fix")
+                        .help("Fixes what can be fixed, in place"),
+                )
+                .arg(wait_arg()),
         )
         .subcommand(
             SubCommand::with_name("sync")
                 .aliases(&["s"])
-                .about("Synchronizes cards"),
+                .about("Synchronizes cards")
+                .arg(wait_arg()),
         )
         .get_matches();
+    let config_path = matches.value_of("config").map(Path::new);
+
+    if matches.subcommand_matches("configure").is_some() {
+        configure::run(config_path).await?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("init") {
+        let config = Config::from_file(config_path)?;
+        fs::create_dir_all(&config.sync_dir)
+            .chain_err(|| format!("Could not create sync dir {:?}", config.sync_dir))?;
+        let _lock = Lock::acquire(&config, matches.is_present("wait")).await?;
+        let client = remote::repository::client(&config)?;
+        let remote_repo = remote::repository::from_config(&config, &client).await?;
+        let mut local_repo = local::repository::from_config(&config)?;
+
+        let ctag = remote_repo.change_token().await?;
+        let mut remote_cards = remote_repo.list().await?;
+        if let Some(ref dir) = config.photo_dir() {
+            let photos = photo::extract_cards(&mut remote_cards, dir)?;
+            photo::write(&photos.into_values().flatten().collect::<Vec<_>>())?;
+        }
+        let contents = remote_cards
+            .values()
+            .map(|card| (card.name.to_owned(), card.content.to_owned()))
+            .collect();
+        local_repo.write(&contents)?;
+        local_repo.write_metadata(&remote_repo.metadata().await?)?;
+        let local_cards = local_repo.list()?;
+
+        Cache::build(ctag, &local_cards, &remote_cards).write(&config)?;
+    }
+
+    if matches.subcommand_matches("login").is_some() {
+        let config = Config::from_file(config_path)?;
+        let prompt = format!("Password for {}@{}: ", config.login, config.host);
+        let passwd =
+            rpassword::prompt_password_stderr(&prompt).chain_err(|| "Could not read password")?;
+
+        match config.passwd_file {
+            Some(ref path) => {
+                credentials::store_file_passwd(path, &passwd)?;
+                println!("Password stored in {:?}", path);
+            }
+            None => {
+                credentials::store_keyring_passwd(&config, &passwd).await?;
+                println!("Password stored in the Secret Service");
+            }
+        }
+        if config.passwd_env.is_some()
+            || (config.passwd_file.is_none() && !config.passwd_cmd.is_empty())
+        {
+            warn!("The stored password is not used as long as `passwd-env` or `passwd-cmd` is set");
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("import-state") {
+        let config = Config::from_file(config_path)?;
+        let _lock = Lock::acquire(&config, matches.is_present("wait")).await?;
+
+        if config.file_path(".cache").exists() && !matches.is_present("force") {
+            bail!("Cache already exists (use --force to override it)");
+        }
+
+        let local_repo = local::repository::from_config(&config)?;
+        let local_cards = local_repo.list()?;
+        let path = PathBuf::from(matches.value_of("path").unwrap_or_default());
+        let cache = vdirsyncer::import_state(
+            &config.sync_dir,
+            &local_cards,
+            &path,
+            matches.is_present("swap"),
+        )?;
+        cache.write(&config)?;
+        println!("{} card(s) imported", cache.cards.len());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("list") {
+        let config = Config::from_file(config_path)?;
+        let output = matches.value_of("output").unwrap_or_default().parse()?;
+        let contacts = read_contacts(&config)?;
+        println!("{}", contact::render(&contacts, output)?);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("search") {
+        let config = Config::from_file(config_path)?;
+        let output = matches.value_of("output").unwrap_or_default().parse()?;
+        let query = matches.value_of("query").unwrap_or_default();
+        let field = matches.value_of("field");
+        let contacts: Vec<_> = read_contacts(&config)?
+            .into_iter()
+            .filter(|contact| contact.matches(query, field))
+            .collect();
+        println!("{}", contact::render(&contacts, output)?);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("query") {
+        let config = Config::from_file(config_path)?;
+        let query = matches.value_of("query").unwrap_or_default();
+        let contacts: Vec<_> = read_contacts(&config)?
+            .into_iter()
+            .filter(|contact| !contact.emails.is_empty() && contact.matches(query, None))
+            .collect();
+        println!("{}", contact::to_query(&contacts));
+    }
+
+    if let Some(matches) = matches.subcommand_matches("add") {
+        let config = Config::from_file(config_path)?;
+        let _lock = Lock::acquire(&config, matches.is_present("wait")).await?;
+        let mut local_repo = local::repository::from_config(&config)?;
 
-    if let Some(_) = matches.subcommand_matches("init") {
-        let config = Config::from_file()?;
-        let client = Client::new();
+        let values = |name| -> Vec<String> {
+            matches
+                .values_of(name)
+                .map(|vals| vals.map(String::from).collect())
+                .unwrap_or_default()
+        };
+        let contact = Contact {
+            name: matches.value_of("name").unwrap_or_default().to_owned(),
+            uid: None,
+            emails: values("email"),
+            phones: values("phone"),
+            org: matches.value_of("org").map(String::from),
+            props: vec![],
+        };
+        let name = add_card(local_repo.as_mut(), contact)?;
+        println!("Card {} added", name);
 
-        let path = remote::repository::addressbook_path(&config, &client).await?;
-        let ctag = remote::repository::fetch_ctag(&config, &client, &path).await?;
-        let remote_cards =
-            remote::repository::fetch_and_write_cards(&config, &client, &path).await?;
-        let local_cards = local::repository::read_cards(&config)?;
+        if matches.is_present("push") {
+            sync(&config).await?;
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("edit") {
+        let config = Config::from_file(config_path)?;
+        let card = {
+            let local_repo = local::repository::from_config(&config)?;
+            find_card(
+                local_repo.as_ref(),
+                matches.value_of("card").unwrap_or_default(),
+            )?
+        };
+
+        let content = edit_content(&card.name, &card.content)?;
+        if content == card.content {
+            println!("Card {} unchanged", card.name);
+            return Ok(());
+        }
+        check_edited_card(&content).chain_err(|| format!("Invalid card {}", card.name))?;
+
+        let _lock = Lock::acquire(&config, matches.is_present("wait")).await?;
+        let mut local_repo = local::repository::from_config(&config)?;
+        write_edited_card(local_repo.as_mut(), &card, content)?;
+        println!("Card {} edited", card.name);
+
+        if matches.is_present("push") {
+            sync(&config).await?;
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("delete") {
+        let config = Config::from_file(config_path)?;
+        let _lock = Lock::acquire(&config, matches.is_present("wait")).await?;
+        let mut local_repo = local::repository::from_config(&config)?;
+
+        let query = matches.value_of("card").unwrap_or_default();
+        let card = delete_card(local_repo.as_mut(), query, |card| {
+            if matches.is_present("yes") {
+                return Ok(true);
+            }
+            let contact = Contact::from_vcard(&card.content);
+            let question = format!("Delete card {} ({})? [y/N]", card.name, contact.name);
+            Ok(prompt(&question)?.eq_ignore_ascii_case("y"))
+        })?;
+        match card {
+            Some(card) => println!("Card {} deleted", card.name),
+            None => {
+                println!("Card not deleted");
+                return Ok(());
+            }
+        }
+
+        if matches.is_present("push") {
+            sync(&config).await?;
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("photo") {
+        let config = Config::from_file(config_path)?;
 
-        Cache::build_and_write(&config, ctag, local_cards, remote_cards)?;
+        if let Some(matches) = matches.subcommand_matches("get") {
+            let local_repo = local::repository::from_config(&config)?;
+            let card = find_card(
+                local_repo.as_ref(),
+                matches.value_of("card").unwrap_or_default(),
+            )?;
+            let data = match photo::get(&card.content)
+                .chain_err(|| format!("Could not get photo of card {}", card.name))?
+            {
+                Some(data) => data,
+                None => bail!("Card {} has no photo", card.name),
+            };
+
+            match matches.value_of("path") {
+                Some(path) => {
+                    fs::write(path, data).chain_err(|| format!("Could not write {:?}", path))?;
+                    println!("Photo of card {} saved to {:?}", card.name, path);
+                }
+                None => io::stdout()
+                    .write_all(&data)
+                    .chain_err(|| "Could not write photo to stdout")?,
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("set") {
+            let path = PathBuf::from(matches.value_of("path").unwrap_or_default());
+            let media_type = match photo::media_type(&path) {
+                Some(media_type) => media_type,
+                None => bail!("Unknown image type for {:?}", path),
+            };
+            let data = fs::read(&path).chain_err(|| format!("Could not read {:?}", path))?;
+
+            let _lock = Lock::acquire(&config, matches.is_present("wait")).await?;
+            let mut local_repo = local::repository::from_config(&config)?;
+            let card = find_card(
+                local_repo.as_ref(),
+                matches.value_of("card").unwrap_or_default(),
+            )?;
+            let photo_dir = config.photo_dir();
+            let (content, photos) = photo::set(
+                &card.content,
+                &card.name,
+                data,
+                Some(media_type),
+                photo_dir.as_deref(),
+            )?;
+            photo::write(&photos)?;
+            let mut cards = HashMap::new();
+            cards.insert(card.name.to_owned(), content);
+            local_repo.write(&cards)?;
+            println!("Photo of card {} set", card.name);
+
+            if let Some(ref dir) = photo_dir {
+                let cards = local_repo.list()?;
+                let contents: Vec<_> = cards.values().map(|card| card.content.as_str()).collect();
+                photo::clean(dir, &contents)?;
+            }
+
+            if matches.is_present("push") {
+                sync(&config).await?;
+            }
+        }
     }
 
-    if let Some(_) = matches.subcommand_matches("sync") {
-        let config = Config::from_file()?;
-        let cache = Cache::from_file(&config)?;
+    if let Some(matches) = matches.subcommand_matches("convert") {
+        let version: Version = matches
+            .value_of("version")
+            .unwrap_or_default()
+            .parse()
+            .map_err(Error::from)?;
+
+        if let Some(path) = matches.value_of("path") {
+            let content =
+                fs::read_to_string(path).chain_err(|| format!("Could not read {:?}", path))?;
+            print!("{}", convert::convert_all(&content, version));
+            return Ok(());
+        }
+
+        let config = Config::from_file(config_path)?;
+        let _lock = Lock::acquire(&config, matches.is_present("wait")).await?;
+        let mut local_repo = local::repository::from_config(&config)?;
+        let cards: HashMap<String, String> = local_repo
+            .list()?
+            .into_iter()
+            .map(|(name, card)| (name, convert::convert(&card.content, version)))
+            .collect();
+        local_repo.write(&cards)?;
+        println!("{} card(s) converted to vCard {}", cards.len(), version);
+
+        if matches.is_present("push") {
+            sync(&config).await?;
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("export") {
+        let config = Config::from_file(config_path)?;
+        let local_repo = local::repository::from_config(&config)?;
+        let cards = local_repo.list()?;
+        let mut names: Vec<_> = cards.keys().collect();
+        names.sort();
+        let contents: Vec<_> = names
+            .iter()
+            .map(|name| cards[*name].content.as_str())
+            .collect();
+
+        let output = match matches.value_of("format") {
+            Some("csv") => csv::export(&contents, &config.csv_columns(), config.csv_delimiter())?,
+            Some("jcard") => {
+                let jcards: Vec<_> = contents.iter().map(|card| jcard::to_jcard(card)).collect();
+                serde_json::to_string_pretty(&jcards).chain_err(|| "Could not serialize jCards")?
+                    + "\n"
+            }
+            _ => ldif::export(&contents),
+        };
 
-        println!("{:#?}", cache);
+        match matches.value_of("path") {
+            Some(path) => {
+                fs::write(path, output).chain_err(|| format!("Could not write {:?}", path))?;
+                println!("{} card(s) exported to {:?}", contents.len(), path);
+            }
+            None => print!("{}", output),
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("import") {
+        let path = matches.value_of("path").unwrap_or_default();
+        let content = if path == "-" {
+            let mut content = String::new();
+            io::stdin()
+                .read_to_string(&mut content)
+                .chain_err(|| "Could not read stdin")?;
+            content
+        } else {
+            fs::read_to_string(path).chain_err(|| format!("Could not read {:?}", path))?
+        };
+
+        let config = Config::from_file(config_path)?;
+        let cards = match matches.value_of("format") {
+            Some("csv") => csv::import(&content, &config.csv_columns(), config.csv_delimiter())?,
+            Some("jcard") => jcard::parse(&content)?,
+            _ => ldif::import(&content)?,
+        };
+
+        let _lock = Lock::acquire(&config, matches.is_present("wait")).await?;
+        let mut local_repo = local::repository::from_config(&config)?;
+
+        // Imported cards are new cards: the ones whose UID is already taken
+        // get a new one instead of overwriting an existing card.
+        let mut uids: HashSet<String> = HashSet::new();
+        for (name, card) in local_repo.list()? {
+            uids.extend(vcard::uid(&card.content));
+            uids.insert(name);
+        }
+        let mut contents: HashMap<String, String> = HashMap::new();
+        for card in cards {
+            let (uid, card) = match vcard::uid(&card) {
+                Some(uid) if !uids.contains(&uid) => (uid, card),
+                _ => {
+                    let uid = Uuid::new_v4().to_string();
+                    let card = vcard::replace_uid(&card, &uid);
+                    (uid, card)
+                }
+            };
+            uids.insert(uid.to_owned());
+            contents.insert(uid, card);
+        }
+        let cards = contents;
+        local_repo.write(&cards)?;
+        println!("{} card(s) imported", cards.len());
+
+        if !cards.is_empty() && matches.is_present("push") {
+            sync(&config).await?;
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("dedupe") {
+        let config = Config::from_file(config_path)?;
+        let _lock = Lock::acquire(&config, matches.is_present("wait")).await?;
+        let mut local_repo = local::repository::from_config(&config)?;
+        let cards = local_repo.list()?;
+        let groups = dedupe::find_duplicates(&cards);
+        let mut merged = 0;
+
+        if groups.is_empty() {
+            println!("No duplicate found");
+        }
+
+        for (i, group) in groups.iter().enumerate() {
+            // The most complete card comes first, as the default target.
+            let mut names = group.names.to_owned();
+            names.sort_by_key(|name| std::cmp::Reverse(vcard::parse(&cards[name].content).len()));
+            let choices: Vec<_> = names
+                .iter()
+                .map(|name| {
+                    let contact = Contact::from_vcard(&cards[name].content);
+                    let mut details = contact.emails.to_owned();
+                    details.extend(contact.phones.to_owned());
+                    format!("{} ({}) {}", contact.name, name, details.join(", "))
+                })
+                .collect();
+
+            println!();
+            println!(
+                "Duplicates {}/{}: {}",
+                i + 1,
+                groups.len(),
+                group.reasons.iter().cloned().collect::<Vec<_>>().join(", ")
+            );
+            if matches.is_present("dry-run") {
+                choices.iter().for_each(|choice| println!("  {}", choice));
+                continue;
+            }
+
+            // Phone numbers and names can be shared by different people, so
+            // such groups are never merged without asking.
+            let is_auto = matches.is_present("auto") && group.is_exact;
+            let target = if is_auto {
+                choices.iter().for_each(|choice| println!("  {}", choice));
+                0
+            } else {
+                match prompt_choice("Merge into", &choices)? {
+                    Some(target) => target,
+                    None => continue,
+                }
+            };
+            names.swap(0, target);
+
+            let contents: Vec<_> = names
+                .iter()
+                .map(|name| cards[name].content.as_str())
+                .collect();
+            let mut choose_err = None;
+            let content = dedupe::merge(&contents, |prop, values| {
+                if is_auto || choose_err.is_some() {
+                    return 0;
+                }
+                println!("Conflicting {} values:", prop);
+                match prompt_choice("Keep", values) {
+                    Ok(choice) => choice.unwrap_or(0),
+                    Err(err) => {
+                        choose_err = Some(err);
+                        0
+                    }
+                }
+            });
+            if let Some(err) = choose_err {
+                return Err(err);
+            }
+
+            let mut contents = HashMap::new();
+            contents.insert(names[0].to_owned(), content);
+            local_repo.write(&contents)?;
+            local_repo.delete(&names[1..])?;
+            merged += 1;
+            println!("Cards {} merged into {}", names[1..].join(", "), names[0]);
+        }
+
+        if merged > 0 && matches.is_present("push") {
+            sync(&config).await?;
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("validate") {
+        let config = Config::from_file(config_path)?;
+        let local_repo = local::repository::from_config(&config)?;
+        let paths = local_repo.files()?;
+        let mut reports = validate::check_files(&paths)?;
+
+        if matches.is_present("fix") {
+            let _lock = Lock::acquire(&config, matches.is_present("wait")).await?;
+            for report in reports
+                .iter()
+                .filter(|r| r.problems.iter().any(|p| p.fixable))
+            {
+                fs::write(&report.path, validate::fix(&report.path, &report.content))
+                    .chain_err(|| format!("Could not write {:?}", report.path))?;
+                println!("File {:?} fixed", report.path);
+            }
+            reports = validate::check_files(&paths)?;
+        }
+
+        let problems: Vec<_> = reports
+            .iter()
+            .flat_map(|report| report.problems.iter().map(move |p| (&report.path, p)))
+            .collect();
+        for (path, problem) in problems.iter() {
+            println!("{}: {}", path.display(), problem);
+        }
+
+        let errors = problems
+            .iter()
+            .filter(|(_, p)| p.severity() == Severity::Error)
+            .count();
+        println!(
+            "{} file(s) checked, {} error(s), {} warning(s)",
+            reports.len(),
+            errors,
+            problems.len() - errors
+        );
+        if errors > 0 {
+            bail!("Invalid cards found");
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("sync") {
+        let config = Config::from_file(config_path)?;
+        let _lock = Lock::acquire(&config, matches.is_present("wait")).await?;
+
+        sync(&config).await?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local::memory::MemoryRepository;
+
+    fn card(uid: &str, name: &str) -> String {
+        format!(
+            "BEGIN:VCARD\r\nVERSION:3.0\r\nUID:{}\r\nFN:{}\r\nEND:VCARD\r\n",
+            uid, name
+        )
+    }
+
+    fn repository() -> MemoryRepository {
+        let mut local_repo = MemoryRepository::new();
+        let cards = vec![
+            (String::from("a"), card("a", "Alice Doe")),
+            (String::from("b"), card("b", "Bob Doe")),
+        ];
+        local_repo.write(&cards.into_iter().collect()).unwrap();
+        local_repo
+    }
+
+    #[test]
+    fn add() {
+        let mut local_repo = MemoryRepository::new();
+        let contact = Contact {
+            name: String::from("Jane Doe"),
+            uid: None,
+            emails: vec![String::from("jane@example.com")],
+            phones: vec![],
+            org: None,
+            props: vec![],
+        };
+        let name = add_card(&mut local_repo, contact).unwrap();
+
+        let contact = Contact::from_vcard(&local_repo.read(&name).unwrap().unwrap().content);
+        assert_eq!(contact.uid.as_deref(), Some(name.as_str()));
+        assert_eq!(contact.name, "Jane Doe");
+        assert_eq!(contact.emails, vec!["jane@example.com"]);
+    }
+
+    #[test]
+    fn edit() {
+        let mut local_repo = repository();
+        let card_a = local_repo.read("a").unwrap().unwrap();
+        write_edited_card(&mut local_repo, &card_a, card("a", "Alice B")).unwrap();
+        assert_eq!(
+            local_repo.read("a").unwrap().unwrap().content,
+            card("a", "Alice B")
+        );
+
+        // The card changed since it was read, like during a sync.
+        let err = write_edited_card(&mut local_repo, &card_a, card("a", "Alice C"));
+        assert!(err.is_err());
+        assert_eq!(
+            local_repo.read("a").unwrap().unwrap().content,
+            card("a", "Alice B")
+        );
+
+        local_repo.delete(&[String::from("a")]).unwrap();
+        assert!(write_edited_card(&mut local_repo, &card_a, card("a", "Alice C")).is_err());
+        assert!(local_repo.read("a").unwrap().is_none());
+    }
+
+    #[test]
+    fn delete() {
+        let mut local_repo = repository();
+
+        let card = delete_card(&mut local_repo, "Alice", |_| Ok(false)).unwrap();
+        assert!(card.is_none());
+        assert!(local_repo.read("a").unwrap().is_some());
+
+        let card = delete_card(&mut local_repo, "Alice", |_| Ok(true)).unwrap();
+        assert_eq!(card.unwrap().name, "a");
+        assert!(local_repo.read("a").unwrap().is_none());
+
+        let card = delete_card(&mut local_repo, "Bob Doe", |_| panic!("asked")).unwrap();
+        assert_eq!(card.unwrap().name, "b");
+        assert!(local_repo.list().unwrap().is_empty());
+
+        assert!(delete_card(&mut local_repo, "Bob", |_| Ok(true)).is_err());
+    }
+}
@@ -1,16 +1,73 @@
-use error_chain::error_chain;
+use error_chain::{bail, error_chain};
+use log::warn;
 use serde::Deserialize;
 use std::{
     env,
-    fs::File,
+    fs::{self, File},
     io::Read,
     path::{Path, PathBuf},
     process::Command,
+    time::Duration,
 };
-use toml;
+use toml::{value::Table, Value};
+
+use crate::vcard::Version;
 
 error_chain! {}
 
+/// Env var giving the path of the config file.
+const CONFIG_ENV_VAR: &str = "CARDAMOM_CONFIG";
+
+/// Prefix of the env vars overriding config keys.
+const ENV_VAR_PREFIX: &str = "CARDAMOM_";
+
+/// Type of the value of a key overridden by an env var.
+enum EnvKind {
+    Str,
+    Int,
+    Bool,
+    /// TOML array, like `["*.vcf", "work/*"]`.
+    List,
+}
+
+/// Keys that can be overridden by env vars.
+const ENV_KEYS: &[(&str, EnvKind)] = &[
+    ("host", EnvKind::Str),
+    ("port", EnvKind::Int),
+    ("ssl", EnvKind::Bool),
+    ("login", EnvKind::Str),
+    ("passwd-cmd", EnvKind::Str),
+    ("passwd-env", EnvKind::Str),
+    ("passwd-file", EnvKind::Str),
+    ("auth", EnvKind::Str),
+    ("oauth2-token-url", EnvKind::Str),
+    ("oauth2-client-id", EnvKind::Str),
+    ("oauth2-client-secret", EnvKind::Str),
+    ("oauth2-refresh-token-cmd", EnvKind::Str),
+    ("tls-ca-file", EnvKind::Str),
+    ("tls-client-cert", EnvKind::Str),
+    ("tls-client-key", EnvKind::Str),
+    ("tls-fingerprint", EnvKind::Str),
+    ("tls-insecure", EnvKind::Bool),
+    ("proxy", EnvKind::Str),
+    ("connect-timeout", EnvKind::Int),
+    ("timeout", EnvKind::Int),
+    ("retries", EnvKind::Int),
+    ("sync-dir", EnvKind::Str),
+    ("recursive", EnvKind::Bool),
+    ("include", EnvKind::List),
+    ("exclude", EnvKind::List),
+    ("layout", EnvKind::Str),
+    ("sync-file", EnvKind::Str),
+    ("remote", EnvKind::Str),
+    ("remote-path", EnvKind::Str),
+    ("remote-dir", EnvKind::Str),
+    ("version", EnvKind::Str),
+    ("jcard", EnvKind::Bool),
+    ("photo-dir", EnvKind::Str),
+    ("csv-delimiter", EnvKind::Str),
+];
+
 pub fn run_cmd(cmd: &str) -> Result<String> {
     let output = if cfg!(target_os = "windows") {
         Command::new("cmd").args(&["/C", cmd]).output()
@@ -22,15 +79,121 @@ pub fn run_cmd(cmd: &str) -> Result<String> {
     Ok(String::from_utf8(output.stdout).chain_err(|| "Invalid utf8 output")?)
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layout {
+    /// One or many `.vcf` files in the sync dir.
+    Dir,
+    /// A single `.vcf` file containing all the cards.
+    File,
+    /// A vdir collection, as used by vdirsyncer and khard.
+    Vdir,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RemoteKind {
+    /// CardDAV addressbook.
+    Carddav,
+    /// Plain WebDAV folder of `.vcf` files.
+    Webdav,
+    /// Another local directory of `.vcf` files.
+    Dir,
+    /// REST service in the style of the Google People API, authenticated
+    /// with OAuth2.
+    People,
+}
+
+/// How requests are authenticated.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthKind {
+    /// HTTP Basic authentication with `login` and `passwd-cmd`, switching to
+    /// Digest when the server answers with a Digest challenge.
+    Basic,
+    /// HTTP Digest authentication with `login` and `passwd-cmd`.
+    Digest,
+    /// OAuth2 bearer tokens, obtained from a refresh token.
+    Oauth2,
+}
+
+/// Column of a CSV file, mapped to a card field like `given`, `family`,
+/// `email`, `tel:cell` or `city:home`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CsvColumn {
+    pub header: String,
+    pub field: String,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
+    #[serde(default)]
     pub host: String,
+    #[serde(default)]
     pub port: u16,
     pub ssl: Option<bool>,
+    #[serde(default)]
     pub login: String,
+    #[serde(default)]
     pub passwd_cmd: String,
+    /// Environment variable holding the password.
+    pub passwd_env: Option<String>,
+    /// File holding the password, only readable by its owner.
+    pub passwd_file: Option<PathBuf>,
+    pub auth: Option<AuthKind>,
+    /// Endpoint where OAuth2 access tokens are requested.
+    pub oauth2_token_url: Option<String>,
+    pub oauth2_client_id: Option<String>,
+    pub oauth2_client_secret: Option<String>,
+    /// Command printing the OAuth2 refresh token, like `passwd-cmd`.
+    pub oauth2_refresh_token_cmd: Option<String>,
+    /// PEM bundle of the certificate authorities to trust, in addition to
+    /// the system ones and the Mozilla ones bundled with cardamom.
+    pub tls_ca_file: Option<PathBuf>,
+    /// PEM client certificate, for servers requiring mutual TLS.
+    pub tls_client_cert: Option<PathBuf>,
+    /// PEM key of the client certificate, if not in the certificate file.
+    pub tls_client_key: Option<PathBuf>,
+    /// SHA-256 fingerprint of the server certificate. When set, only this
+    /// certificate is accepted, whoever signed it, so it cannot be combined
+    /// with `tls-ca-file`.
+    pub tls_fingerprint: Option<String>,
+    /// Disables the verification of the server certificate. For testing
+    /// only.
+    pub tls_insecure: Option<bool>,
+    /// Proxy all the requests go through, like `http://proxy:3128` or
+    /// `socks5://localhost:1080`. Credentials can be given in the URL.
+    pub proxy: Option<String>,
+    /// Timeout for connecting to the server, in seconds. `0` disables it.
+    pub connect_timeout: Option<u64>,
+    /// Timeout of a whole request, from connecting to reading the response,
+    /// in seconds. `0` disables it.
+    pub timeout: Option<u64>,
+    /// Number of times an idempotent request is sent again after a network
+    /// failure or a `429`/`503` response.
+    pub retries: Option<u32>,
     pub sync_dir: PathBuf,
+    pub recursive: Option<bool>,
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    pub layout: Option<Layout>,
+    pub sync_file: Option<PathBuf>,
+    pub remote: Option<RemoteKind>,
+    /// Path of the cards on the server: the directory of the cards for
+    /// WebDAV, the addressbook for CardDAV (discovered when not set).
+    pub remote_path: Option<String>,
+    pub remote_dir: Option<PathBuf>,
+    /// vCard version all the cards are converted to during sync.
+    pub version: Option<Version>,
+    /// Requests the cards as jCard (`application/vcard+json`) from CardDAV
+    /// servers. They are stored as vCard 4.0.
+    pub jcard: Option<bool>,
+    /// Directory inline photos are extracted to, relative to the sync dir.
+    /// Photos stay inline when not set.
+    pub photo_dir: Option<PathBuf>,
+    pub csv_columns: Option<Vec<CsvColumn>>,
+    pub csv_delimiter: Option<char>,
 }
 
 impl Config {
@@ -62,26 +225,362 @@ impl Config {
         Ok(path)
     }
 
-    pub fn from_file() -> Result<Self> {
-        let mut file = File::open(
-            Self::path_from_xdg()
-                .or_else(|_| Self::path_from_xdg_alt())
-                .or_else(|_| Self::path_from_home())
-                .chain_err(|| "Cannot find config path")?,
-        )
-        .chain_err(|| "Cannot open config file")?;
+    /// Path of the config file given by the `CARDAMOM_CONFIG` env var.
+    fn path_from_env() -> Option<PathBuf> {
+        env::var_os(CONFIG_ENV_VAR).map(PathBuf::from)
+    }
+
+    /// Path where a new config file is written: the given one, the one from
+    /// `CARDAMOM_CONFIG`, or the XDG one.
+    pub fn new_path(path: Option<&Path>) -> Result<PathBuf> {
+        match path.map(PathBuf::from).or_else(Self::path_from_env) {
+            Some(path) => Ok(path),
+            None => Self::path_from_xdg().or_else(|_| Self::path_from_xdg_alt()),
+        }
+    }
+
+    /// Reads the keys overriding the config file from the env vars, like
+    /// `CARDAMOM_SYNC_DIR` for `sync-dir`. Env vars not matching a key are
+    /// ignored.
+    fn env_overrides() -> Result<Table> {
+        let mut table = Table::new();
+        for (name, val) in env::vars() {
+            let key = match name.strip_prefix(ENV_VAR_PREFIX) {
+                Some(key) => key.to_lowercase().replace('_', "-"),
+                None => continue,
+            };
+            let kind = match ENV_KEYS.iter().find(|(k, _)| *k == key) {
+                Some((_, kind)) => kind,
+                None => continue,
+            };
+            let val = Self::env_value(kind, &val)
+                .chain_err(|| format!("Invalid config from env var {}", name))?;
+            table.insert(key, val);
+        }
 
-        let mut content = vec![];
-        file.read_to_end(&mut content)
+        Ok(table)
+    }
+
+    /// Parses the value of an env var according to the type of its key, so
+    /// that a string key can hold something looking like a number.
+    fn env_value(kind: &EnvKind, val: &str) -> Result<Value> {
+        match kind {
+            EnvKind::Str => Ok(Value::String(val.to_owned())),
+            EnvKind::Int => val
+                .parse()
+                .map(Value::Integer)
+                .chain_err(|| format!("Expected an integer, found {:?}", val)),
+            EnvKind::Bool => val
+                .parse()
+                .map(Value::Boolean)
+                .chain_err(|| format!("Expected `true` or `false`, found {:?}", val)),
+            EnvKind::List => toml::from_str::<Table>(&format!("val = {}", val))
+                .ok()
+                .and_then(|mut table| table.remove("val"))
+                .filter(Value::is_array)
+                .chain_err(|| format!("Expected an array like [\"a\", \"b\"], found {:?}", val)),
+        }
+    }
+
+    fn read(path: &Path) -> Result<String> {
+        let mut file =
+            File::open(path).chain_err(|| format!("Cannot open config file {:?}", path))?;
+
+        let mut content = String::new();
+        file.read_to_string(&mut content)
             .chain_err(|| "Cannot read config file")?;
 
-        Ok(toml::from_slice(&content).chain_err(|| "Cannot parse config file")?)
+        Ok(content)
+    }
+
+    /// Candidate paths of the config file, by order of preference.
+    fn paths() -> Vec<PathBuf> {
+        vec![
+            Self::path_from_xdg(),
+            Self::path_from_xdg_alt(),
+            Self::path_from_home(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Finds the index of the line defining a key, among the given lines of
+    /// a config file.
+    fn find_key(lines: &[&str], key: &str) -> Option<usize> {
+        lines.iter().position(|line| {
+            line.trim_start()
+                .trim_start_matches('[')
+                .strip_prefix(key)
+                .map(|rest| {
+                    let rest = rest.trim_start();
+                    rest.starts_with('=') || rest.starts_with(']')
+                })
+                .unwrap_or(false)
+        })
+    }
+
+    /// Finds the line of a key in a config file, following tables and
+    /// arrays of tables (like `csv-columns[1].field`). Falls back to the line
+    /// of the closest parent found, like the line of an inline table.
+    fn key_line(content: &str, path: &str) -> Option<usize> {
+        let lines: Vec<&str> = content.lines().collect();
+        let mut segments = path
+            .split(&['.', '['][..])
+            .map(|segment| segment.trim_end_matches(']'))
+            .filter(|segment| !segment.is_empty())
+            .peekable();
+        let top = segments.next()?;
+        let mut line = Self::find_key(&lines, top)?;
+
+        if let Some(index) = segments
+            .peek()
+            .and_then(|index| index.parse::<usize>().ok())
+        {
+            segments.next();
+            let header = format!("[[{}]]", top);
+            match lines
+                .iter()
+                .enumerate()
+                .filter(|(_, l)| l.trim() == header)
+                .nth(index)
+            {
+                Some((start, _)) => line = start,
+                None => return Some(line + 1),
+            }
+        }
+        if let Some(key) = segments.next() {
+            if lines[line].trim_start().starts_with('[') {
+                line = Self::find_section_key(&lines, line, key).unwrap_or(line);
+            }
+        }
+
+        Some(line + 1)
+    }
+
+    /// Finds the index of the line defining a key in the table starting at
+    /// the given header line.
+    fn find_section_key(lines: &[&str], start: usize, key: &str) -> Option<usize> {
+        let end = lines[start + 1..]
+            .iter()
+            .position(|line| line.trim_start().starts_with('['))
+            .map_or(lines.len(), |end| start + 1 + end);
+        Self::find_key(&lines[start + 1..end], key).map(|i| start + 1 + i)
+    }
+
+    /// Parses the content of a config file, overridden by the keys from the
+    /// env vars. Invalid keys are reported with their origin, unknown keys
+    /// are only warned about.
+    fn parse(content: &str, path: Option<&Path>, overrides: Table) -> Result<Self> {
+        let origin = match path {
+            Some(path) => format!("Invalid config file {:?}", path),
+            None => String::from("Invalid config from env vars"),
+        };
+        let mut table: Table = toml::from_str(content).chain_err(|| origin.to_owned())?;
+        let env_keys: Vec<String> = overrides.keys().cloned().collect();
+        table.extend(overrides);
+
+        let mut unknown_keys = vec![];
+        let mut on_unknown_key = |key: serde_ignored::Path| unknown_keys.push(key.to_string());
+        let de = serde_ignored::Deserializer::new(Value::Table(table), &mut on_unknown_key);
+        let config = serde_path_to_error::deserialize(de).map_err(|err| {
+            let key = err.path().to_string();
+            let top_key = key.split(&['.', '['][..]).next().unwrap_or_default();
+            let location = if key == "." {
+                None
+            } else if env_keys.iter().any(|k| k == top_key) {
+                let var = top_key.to_uppercase().replace('-', "_");
+                Some(format!("from env var {}{}", ENV_VAR_PREFIX, var))
+            } else {
+                Self::key_line(content, &key).map(|line| format!("at line {}", line))
+            };
+            let err = match location {
+                Some(location) => format!("{} ({})", err.inner(), location),
+                None => err.inner().to_string(),
+            };
+            Error::from(err).chain_err(|| origin)
+        })?;
+
+        for key in unknown_keys {
+            match key.contains('_') {
+                true => warn!(
+                    "Unknown config key `{}` (did you mean `{}`?)",
+                    key,
+                    key.replace('_', "-")
+                ),
+                false => warn!("Unknown config key `{}`", key),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Checks that the sync dir exists and is writable, for the commands
+    /// writing to it.
+    pub fn check_sync_dir(&self) -> Result<()> {
+        let dir = &self.sync_dir;
+        if !dir.is_dir() {
+            bail!("Sync dir {:?} does not exist or is not a directory", dir);
+        }
+
+        let path = dir.join(".write-check");
+        File::create(&path)
+            .and_then(|_| fs::remove_file(&path))
+            .chain_err(|| format!("Sync dir {:?} is not writable", dir))
+    }
+
+    /// Checks that the TLS options do not contradict each other.
+    fn check_tls(&self) -> Result<()> {
+        if self.tls_fingerprint.is_some() && self.tls_ca_file.is_some() {
+            bail!(
+                "`tls-fingerprint` cannot be combined with `tls-ca-file`: \
+                 a pinned certificate is accepted whoever signed it"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the CSV delimiter fits in one byte, as the CSV reader and
+    /// writer expect.
+    fn check_csv_delimiter(&self) -> Result<()> {
+        match self.csv_delimiter {
+            Some(c) if !c.is_ascii() => bail!(
+                "Invalid `csv-delimiter` {:?} in config, expected an ASCII character",
+                c
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that the keys required by the remote kind are set, since they
+    /// are optional for the other kinds.
+    fn check_remote(&self) -> Result<()> {
+        match self.remote() {
+            RemoteKind::Carddav | RemoteKind::Webdav => {
+                if self.host.is_empty() {
+                    bail!("Missing `host` in config, required by CardDAV and WebDAV remotes");
+                }
+                if self.login.is_empty() && self.auth() != AuthKind::Oauth2 {
+                    bail!("Missing `login` in config, required unless `auth` is `oauth2`");
+                }
+            }
+            RemoteKind::Dir if self.remote_dir.is_none() => {
+                bail!("Missing `remote-dir` in config, required by directory remotes");
+            }
+            RemoteKind::Dir => (),
+            RemoteKind::People if self.auth() != AuthKind::Oauth2 => {
+                bail!("People remotes only support `auth = \"oauth2\"`");
+            }
+            RemoteKind::People => (),
+        }
+
+        Ok(())
+    }
+
+    /// Reads the config from the given file, or from the file given by
+    /// `CARDAMOM_CONFIG`, or from the first existing default location. Keys
+    /// can be overridden with env vars, in which case the file is optional.
+    pub fn from_file(path: Option<&Path>) -> Result<Self> {
+        let overrides = Self::env_overrides()?;
+        let path = match path.map(PathBuf::from).or_else(Self::path_from_env) {
+            Some(path) => Some(path),
+            None => {
+                let paths = Self::paths();
+                match paths.iter().find(|path| path.is_file()) {
+                    Some(path) => Some(path.to_owned()),
+                    None if !overrides.is_empty() => None,
+                    None => bail!(
+                        "Cannot find config file, searched {}",
+                        paths
+                            .iter()
+                            .map(|path| format!("{:?}", path))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                }
+            }
+        };
+        let content = match path {
+            Some(ref path) => Self::read(path)?,
+            None => String::new(),
+        };
+
+        let config = Self::parse(&content, path.as_deref(), overrides)?;
+        config.check_remote()?;
+        config.check_tls()?;
+        config.check_csv_delimiter()?;
+
+        Ok(config)
     }
 
     pub fn ssl(&self) -> bool {
         self.ssl.unwrap_or(true)
     }
 
+    pub fn jcard(&self) -> bool {
+        self.jcard.unwrap_or(false)
+    }
+
+    pub fn recursive(&self) -> bool {
+        self.recursive.unwrap_or(false)
+    }
+
+    pub fn include(&self) -> Vec<String> {
+        self.include
+            .to_owned()
+            .unwrap_or_else(|| vec![String::from("*.vcf")])
+    }
+
+    pub fn exclude(&self) -> Vec<String> {
+        self.exclude.to_owned().unwrap_or_default()
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout.unwrap_or(Layout::Dir)
+    }
+
+    pub fn sync_file(&self) -> PathBuf {
+        match self.sync_file {
+            Some(ref path) => Path::join(&self.sync_dir, path),
+            None => self.file_path("contacts.vcf"),
+        }
+    }
+
+    pub fn remote(&self) -> RemoteKind {
+        self.remote.unwrap_or(RemoteKind::Carddav)
+    }
+
+    pub fn remote_path(&self) -> String {
+        self.remote_path
+            .to_owned()
+            .unwrap_or_else(|| String::from("/"))
+    }
+
+    pub fn photo_dir(&self) -> Option<PathBuf> {
+        self.photo_dir
+            .as_ref()
+            .map(|path| Path::join(&self.sync_dir, path))
+    }
+
+    pub fn csv_columns(&self) -> Vec<CsvColumn> {
+        self.csv_columns.to_owned().unwrap_or_else(|| {
+            [
+                "fn", "given", "family", "email", "tel", "org", "title", "note",
+            ]
+            .iter()
+            .map(|field| CsvColumn {
+                header: field.to_string(),
+                field: field.to_string(),
+            })
+            .collect()
+        })
+    }
+
+    pub fn csv_delimiter(&self) -> u8 {
+        self.csv_delimiter.map(|c| c as u8).unwrap_or(b',')
+    }
+
     pub fn passwd(&self) -> Result<String> {
         let passwd = run_cmd(&self.passwd_cmd)?;
         let passwd = passwd.trim_end_matches("\n").to_owned();
@@ -89,12 +588,123 @@ impl Config {
         Ok(passwd)
     }
 
+    /// Authentication of the requests, OAuth2 for People remotes and Basic
+    /// for the others unless set.
+    pub fn auth(&self) -> AuthKind {
+        self.auth.unwrap_or(match self.remote() {
+            RemoteKind::People => AuthKind::Oauth2,
+            _ => AuthKind::Basic,
+        })
+    }
+
+    /// Host of the server, which defaults to the Google one for People
+    /// remotes.
+    pub fn host(&self) -> &str {
+        match self.remote() {
+            RemoteKind::People if self.host.is_empty() => "people.googleapis.com",
+            _ => &self.host,
+        }
+    }
+
+    /// Port of the server, which defaults to the one of the scheme.
+    pub fn port(&self) -> u16 {
+        match self.port {
+            0 if self.ssl() => 443,
+            0 => 80,
+            port => port,
+        }
+    }
+
+    pub fn oauth2_refresh_token(&self) -> Result<String> {
+        let cmd = self
+            .oauth2_refresh_token_cmd
+            .as_ref()
+            .chain_err(|| "Missing `oauth2-refresh-token-cmd` in config")?;
+        let token = run_cmd(cmd)?;
+        let token = token.trim_end_matches('\n').to_owned();
+
+        Ok(token)
+    }
+
+    pub fn tls_insecure(&self) -> bool {
+        self.tls_insecure.unwrap_or(false)
+    }
+
+    pub fn has_tls_options(&self) -> bool {
+        self.tls_ca_file.is_some()
+            || self.tls_client_cert.is_some()
+            || self.tls_fingerprint.is_some()
+            || self.tls_insecure()
+    }
+
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        Some(self.connect_timeout.unwrap_or(10))
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        Some(self.timeout.unwrap_or(300))
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(3)
+    }
+
     pub fn url(&self, path: &str) -> String {
         let scheme = if self.ssl() { "https" } else { "http" };
-        format!("{}://{}:{}{}", &scheme, &self.host, &self.port, &path)
+        format!("{}://{}:{}{}", &scheme, self.host(), self.port(), &path)
     }
 
     pub fn file_path(&self, path: &str) -> PathBuf {
         Path::join(&self.sync_dir, path)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_lines() {
+        let content = "host = \"h\"\n\
+                       [[csv-columns]]\n\
+                       header = \"Name\"\n\
+                       field = \"fn\"\n\
+                       [[csv-columns]]\n\
+                       header = \"Mail\"\n\
+                       field = \"email\"\n";
+
+        assert_eq!(Config::key_line(content, "host"), Some(1));
+        assert_eq!(Config::key_line(content, "csv-columns"), Some(2));
+        assert_eq!(Config::key_line(content, "csv-columns[0].field"), Some(4));
+        assert_eq!(Config::key_line(content, "csv-columns[1].header"), Some(6));
+        assert_eq!(Config::key_line(content, "csv-columns[1].unknown"), Some(5));
+        assert_eq!(Config::key_line(content, "port"), None);
+    }
+
+    #[test]
+    fn env_values() {
+        let val = |kind, val| Config::env_value(kind, val).ok();
+
+        assert_eq!(
+            val(&EnvKind::Str, "1234"),
+            Some(Value::String("1234".into()))
+        );
+        assert_eq!(
+            val(&EnvKind::Str, "true"),
+            Some(Value::String("true".into()))
+        );
+        assert_eq!(val(&EnvKind::Int, "8443"), Some(Value::Integer(8443)));
+        assert_eq!(val(&EnvKind::Int, "port"), None);
+        assert_eq!(val(&EnvKind::Bool, "false"), Some(Value::Boolean(false)));
+        assert_eq!(val(&EnvKind::Bool, "1"), None);
+        assert_eq!(
+            val(&EnvKind::List, r#"["*.vcf"]"#),
+            Some(Value::Array(vec![Value::String("*.vcf".into())]))
+        );
+        assert_eq!(val(&EnvKind::List, "*.vcf"), None);
+    }
+}
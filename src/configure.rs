@@ -0,0 +1,182 @@
+//! Interactive setup of the config file: asks for the server and the
+//! credentials, checks them by discovering the addressbooks, then writes the
+//! config file.
+
+use error_chain::{bail, error_chain};
+use std::{
+    env, fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+use url::Url;
+
+use crate::{
+    config::Config,
+    credentials,
+    remote::{self, carddav, dav},
+};
+
+error_chain! {
+    links {
+        Config(crate::config::Error, crate::config::ErrorKind);
+        Credentials(credentials::Error, credentials::ErrorKind);
+        RemoteRepository(remote::repository::Error, remote::repository::ErrorKind);
+    }
+}
+
+/// Asks a question until it gets an answer, or uses the default answer if
+/// the answer is empty.
+fn ask(question: &str, default: Option<&str>) -> Result<String> {
+    loop {
+        match default {
+            Some(default) => eprint!("{} [{}]: ", question, default),
+            None => eprint!("{}: ", question),
+        }
+        io::stderr()
+            .flush()
+            .chain_err(|| "Could not write question")?;
+
+        let mut answer = String::new();
+        let len = io::stdin()
+            .lock()
+            .read_line(&mut answer)
+            .chain_err(|| "Could not read answer")?;
+        if len == 0 {
+            bail!("Setup aborted");
+        }
+
+        match (answer.trim(), default) {
+            ("", Some(default)) => return Ok(default.to_owned()),
+            ("", None) => continue,
+            (answer, _) => return Ok(answer.to_owned()),
+        }
+    }
+}
+
+fn confirm(question: &str) -> Result<bool> {
+    let answer = ask(&format!("{} (y/n)", question), Some("n"))?;
+    Ok(answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"))
+}
+
+/// Formats a TOML string, with quotes and escapes.
+fn toml_str(val: &str) -> String {
+    toml::Value::String(val.to_owned()).to_string()
+}
+
+fn default_sync_dir() -> Option<PathBuf> {
+    let dir = match env::var("XDG_DATA_HOME") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => PathBuf::from(env::var("HOME").ok()?)
+            .join(".local")
+            .join("share"),
+    };
+    Some(dir.join("cardamom"))
+}
+
+fn parse(lines: &[String]) -> Result<Config> {
+    toml::from_str(&lines.join("\n")).chain_err(|| "Invalid config")
+}
+
+/// Asks for the config, checks it against the server, then writes it.
+pub async fn run(path: Option<&Path>) -> Result<()> {
+    let path = Config::new_path(path)?;
+    let dir = path.parent().map(PathBuf::from).unwrap_or_default();
+    if path.exists() && !confirm(&format!("{:?} already exists, overwrite it?", path))? {
+        bail!("Setup aborted");
+    }
+
+    let url = ask("Server URL", None)?;
+    let url = Url::parse(&url)
+        .or_else(|_| Url::parse(&format!("https://{}", url)))
+        .chain_err(|| format!("Invalid server URL {:?}", url))?;
+    let ssl = match url.scheme() {
+        "https" => true,
+        "http" => false,
+        scheme => bail!("Unsupported URL scheme {:?}", scheme),
+    };
+    let host = url
+        .host_str()
+        .chain_err(|| format!("Missing host in {}", url))?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let login = ask("Login", None)?;
+
+    let mut lines = vec![
+        format!("host = {}", toml_str(host)),
+        format!("port = {}", port),
+        format!("ssl = {}", ssl),
+        format!("login = {}", toml_str(&login)),
+    ];
+
+    let storage = ask("Password storage (keyring, file, cmd)", Some("keyring"))?;
+    let passwd_file = dir.join("passwd");
+    let passwd = match storage.as_str() {
+        "keyring" => Some(rpassword::prompt_password_stderr("Password: ")),
+        "file" => {
+            let file = ask("Password file", Some(&passwd_file.to_string_lossy()))?;
+            lines.push(format!("passwd-file = {}", toml_str(&file)));
+            Some(rpassword::prompt_password_stderr("Password: "))
+        }
+        "cmd" => {
+            let cmd = ask("Command printing the password", None)?;
+            lines.push(format!("passwd-cmd = {}", toml_str(&cmd)));
+            None
+        }
+        storage => bail!("Unknown password storage {:?}", storage),
+    };
+    let passwd = passwd.transpose().chain_err(|| "Could not read password")?;
+    if let Some(ref passwd) = passwd {
+        credentials::set_passwd(passwd);
+    }
+
+    let sync_dir = default_sync_dir();
+    let sync_dir = ask(
+        "Sync directory",
+        sync_dir
+            .as_ref()
+            .map(|dir| dir.to_string_lossy())
+            .as_deref(),
+    )?;
+    lines.push(format!("sync-dir = {}", toml_str(&sync_dir)));
+
+    eprintln!("Looking for addressbooks…");
+    let config = parse(&lines)?;
+    let client = remote::repository::client(&config)?;
+    let hrefs = carddav::addressbooks(&config, &client, url.path())
+        .await
+        .chain_err(|| "Could not discover the addressbooks, check the URL and the credentials")?;
+    let href = match hrefs.len() {
+        0 => bail!("No addressbook found"),
+        1 => hrefs[0].to_owned(),
+        _ => {
+            for (i, href) in hrefs.iter().enumerate() {
+                let metadata = dav::fetch_metadata(&config, &client, href).await?;
+                let name = metadata.displayname.unwrap_or_else(|| href.to_owned());
+                eprintln!("{}. {} ({})", i + 1, name, href);
+            }
+            let choice = ask("Addressbook to sync", Some("1"))?;
+            match choice.parse::<usize>() {
+                Ok(i) if i >= 1 && i <= hrefs.len() => hrefs[i - 1].to_owned(),
+                _ => bail!("Invalid addressbook {:?}", choice),
+            }
+        }
+    };
+    lines.push(format!("remote-path = {}", toml_str(&href)));
+    let config = parse(&lines)?;
+
+    fs::create_dir_all(&dir).chain_err(|| format!("Could not create {:?}", dir))?;
+    match (passwd, config.passwd_file.as_ref()) {
+        (Some(ref passwd), Some(path)) => credentials::store_file_passwd(path, passwd)?,
+        (Some(ref passwd), None) => credentials::store_keyring_passwd(&config, passwd).await?,
+        (None, _) => (),
+    }
+    fs::create_dir_all(&config.sync_dir)
+        .chain_err(|| format!("Could not create sync dir {:?}", config.sync_dir))?;
+    fs::write(&path, lines.join("\n") + "\n")
+        .chain_err(|| format!("Could not write config file {:?}", path))?;
+
+    println!(
+        "Config written to {:?}, run `cardamom init` to fetch the cards",
+        path
+    );
+    Ok(())
+}
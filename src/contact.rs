@@ -0,0 +1,361 @@
+use error_chain::error_chain;
+use serde::Serialize;
+use std::{cmp::max, str::FromStr};
+
+use crate::{
+    format::jcard,
+    vcard::{self, Property},
+};
+
+error_chain! {
+    foreign_links {
+        Json(serde_json::Error);
+    }
+
+    errors {
+        UnknownOutputErr(output: String) {
+            description("Unknown output")
+            display("Unknown output {:?}", output)
+        }
+    }
+}
+
+/// Contact built from a parsed card, used for display and lookups.
+#[derive(Debug, Clone, Serialize)]
+pub struct Contact {
+    pub name: String,
+    pub uid: Option<String>,
+    pub emails: Vec<String>,
+    pub phones: Vec<String>,
+    pub org: Option<String>,
+    #[serde(skip)]
+    pub props: Vec<Property>,
+}
+
+impl Contact {
+    /// Converts the contact back to jCard, with all the properties of its
+    /// card.
+    pub fn to_jcard(&self) -> serde_json::Value {
+        let props: Vec<_> = self
+            .props
+            .iter()
+            .filter(|prop| prop.name != "BEGIN" && prop.name != "END")
+            .cloned()
+            .collect();
+        jcard::to_jcard(&vcard::build(&props))
+    }
+
+    pub fn from_vcard(content: &str) -> Self {
+        let props = vcard::parse(content);
+        let values = |name: &str| -> Vec<String> {
+            props
+                .iter()
+                .filter(|prop| prop.name == name)
+                .map(|prop| prop.text().trim().to_owned())
+                .filter(|val| !val.is_empty())
+                .collect()
+        };
+
+        let emails = values("EMAIL");
+        let org = props
+            .iter()
+            .find(|prop| prop.name == "ORG")
+            .map(|prop| prop.components().join(", "))
+            .filter(|org| !org.trim_matches(&[',', ' '][..]).is_empty());
+        let name = values("FN")
+            .into_iter()
+            .next()
+            .or_else(|| {
+                // Falls back to `N`, whose first components are the family
+                // and the given names.
+                let n = props.iter().find(|prop| prop.name == "N")?.components();
+                let name = vec![n.get(1), n.first()]
+                    .into_iter()
+                    .flatten()
+                    .filter(|part| !part.is_empty())
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                Some(name).filter(|name| !name.is_empty())
+            })
+            .or_else(|| org.to_owned())
+            .or_else(|| emails.first().cloned())
+            .unwrap_or_default();
+
+        Self {
+            name,
+            uid: values("UID").into_iter().next(),
+            emails,
+            phones: values("TEL"),
+            org,
+            props,
+        }
+    }
+
+    /// Builds a vCard 3.0 from the contact fields.
+    pub fn to_vcard(&self) -> String {
+        let mut props = vec![
+            Property::new("VERSION", "3.0"),
+            Property::new("FN", &vcard::escape(&self.name)),
+            Property::new("N", &structured_name(&self.name)),
+        ];
+        if let Some(ref uid) = self.uid {
+            props.push(Property::new("UID", uid));
+        }
+        for email in self.emails.iter() {
+            props.push(Property::new("EMAIL", &vcard::escape(email)));
+        }
+        for phone in self.phones.iter() {
+            props.push(Property::new("TEL", &vcard::escape(phone)));
+        }
+        if let Some(ref org) = self.org {
+            props.push(Property::new("ORG", &vcard::escape(org)));
+        }
+
+        vcard::build(&props)
+    }
+
+    /// Checks if the contact matches the query, case insensitively. The field
+    /// can be `name`, `email`, `phone`, `org` or any property name. Without
+    /// field, the name, emails, phones and org are searched.
+    pub fn matches(&self, query: &str, field: Option<&str>) -> bool {
+        let query = query.to_lowercase();
+        let contains = |val: &str| val.to_lowercase().contains(&query);
+        let digits = |val: &str| val.chars().filter(char::is_ascii_digit).collect::<String>();
+        let query_digits = digits(&query);
+        let matches_phone = |phone: &String| {
+            contains(phone) || (!query_digits.is_empty() && digits(phone).contains(&query_digits))
+        };
+
+        match field.map(|field| field.to_lowercase()).as_deref() {
+            Some("name") => contains(&self.name),
+            Some("email") => self.emails.iter().any(|email| contains(email)),
+            Some("phone") => self.phones.iter().any(matches_phone),
+            Some("org") => self.org.as_deref().map(contains).unwrap_or(false),
+            Some(field) => self
+                .props
+                .iter()
+                .filter(|prop| prop.name.eq_ignore_ascii_case(field))
+                .any(|prop| contains(&prop.text())),
+            None => {
+                contains(&self.name)
+                    || self.emails.iter().any(|email| contains(email))
+                    || self.phones.iter().any(matches_phone)
+                    || self.org.as_deref().map(contains).unwrap_or(false)
+            }
+        }
+    }
+}
+
+/// Builds the raw value of a `N` property from a full name, guessing the
+/// family name from its last word.
+pub fn structured_name(name: &str) -> String {
+    let mut names: Vec<_> = name.split_whitespace().collect();
+    let family = if names.len() > 1 { names.pop() } else { None };
+
+    [
+        vcard::escape(family.unwrap_or_default()),
+        vcard::escape(&names.join(" ")),
+        String::new(),
+        String::new(),
+        String::new(),
+    ]
+    .join(";")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Output {
+    Table,
+    Plain,
+    Json,
+    Jcard,
+}
+
+impl FromStr for Output {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "table" => Ok(Self::Table),
+            "plain" => Ok(Self::Plain),
+            "json" => Ok(Self::Json),
+            "jcard" => Ok(Self::Jcard),
+            _ => Err(ErrorKind::UnknownOutputErr(s.to_owned()).into()),
+        }
+    }
+}
+
+/// Replaces the tabs and line breaks of a value with spaces, so that it
+/// fits in one cell of a line.
+fn one_line(val: &str) -> String {
+    val.replace(&['\t', '\r', '\n'][..], " ")
+}
+
+fn to_table(contacts: &[Contact]) -> String {
+    let header = ["NAME", "EMAIL", "PHONE", "ORG"];
+    let rows: Vec<[String; 4]> = contacts
+        .iter()
+        .map(|contact| {
+            [
+                one_line(&contact.name),
+                one_line(&contact.emails.join(", ")),
+                one_line(&contact.phones.join(", ")),
+                one_line(contact.org.as_deref().unwrap_or_default()),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.map(|cell| cell.chars().count());
+    for row in rows.iter() {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = max(widths[i], cell.chars().count());
+        }
+    }
+
+    let line = |cells: Vec<&str>| {
+        cells
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join(" │ ")
+            .trim_end()
+            .to_owned()
+    };
+
+    let mut table = vec![line(header.to_vec())];
+    table.push(
+        widths
+            .iter()
+            .map(|width| "─".repeat(*width))
+            .collect::<Vec<_>>()
+            .join("─┼─"),
+    );
+    for row in rows.iter() {
+        table.push(line(row.iter().map(String::as_str).collect()));
+    }
+
+    table.join("\n")
+}
+
+fn to_plain(contacts: &[Contact]) -> String {
+    contacts
+        .iter()
+        .map(|contact| {
+            [
+                one_line(&contact.name),
+                one_line(&contact.emails.join(",")),
+                one_line(&contact.phones.join(",")),
+                one_line(contact.org.as_deref().unwrap_or_default()),
+            ]
+            .join("\t")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Renders the contacts as expected by the `query_command` of mutt and
+/// aerc: a header line, then one `email<TAB>name<TAB>extra` line per email.
+pub fn to_query(contacts: &[Contact]) -> String {
+    let mut lines = vec![format!("Searching contacts... {} found", contacts.len())];
+
+    for contact in contacts {
+        for email in contact.emails.iter() {
+            lines.push(
+                [
+                    one_line(email),
+                    one_line(&contact.name),
+                    one_line(contact.org.as_deref().unwrap_or_default()),
+                ]
+                .join("\t"),
+            );
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// Renders the contacts in the given output format.
+pub fn render(contacts: &[Contact], output: Output) -> Result<String> {
+    Ok(match output {
+        Output::Table => to_table(contacts),
+        Output::Plain => to_plain(contacts),
+        Output::Json => serde_json::to_string_pretty(contacts)?,
+        Output::Jcard => {
+            let jcards: Vec<_> = contacts.iter().map(Contact::to_jcard).collect();
+            serde_json::to_string_pretty(&jcards)?
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contacts() -> Vec<Contact> {
+        vec![
+            Contact::from_vcard(
+                "BEGIN:VCARD\r\n\
+                 VERSION:3.0\r\n\
+                 FN:Jane\tDoe\r\n\
+                 EMAIL:jane@example.com\r\n\
+                 EMAIL:jd@example.org\r\n\
+                 TEL:+33 6 00 00 00 00\r\n\
+                 ORG:Example\\nInc.\r\n\
+                 END:VCARD\r\n",
+            ),
+            Contact::from_vcard(
+                "BEGIN:VCARD\r\n\
+                 VERSION:3.0\r\n\
+                 FN:Bob\r\n\
+                 EMAIL:bob@example.com\r\n\
+                 END:VCARD\r\n",
+            ),
+        ]
+    }
+
+    #[test]
+    fn matches() {
+        let contact = &contacts()[0];
+
+        assert!(contact.matches("JANE", None));
+        assert!(contact.matches("inc", None));
+        assert!(contact.matches("6-00-00", None));
+        assert!(contact.matches("EXAMPLE.ORG", Some("email")));
+        assert!(!contact.matches("jane", Some("phone")));
+        assert!(!contact.matches("doe", Some("email")));
+        assert!(contact.matches("doe", Some("fn")));
+        assert!(!contact.matches("bob", None));
+    }
+
+    #[test]
+    fn query_output() {
+        assert_eq!(
+            to_query(&contacts()),
+            "Searching contacts... 2 found\n\
+             jane@example.com\tJane Doe\tExample Inc.\n\
+             jd@example.org\tJane Doe\tExample Inc.\n\
+             bob@example.com\tBob\t"
+        );
+    }
+
+    #[test]
+    fn table_output() {
+        assert_eq!(
+            to_table(&contacts()),
+            "NAME     │ EMAIL                            │ PHONE             │ ORG\n\
+             ─────────┼──────────────────────────────────┼───────────────────┼─────────────\n\
+             Jane Doe │ jane@example.com, jd@example.org │ +33 6 00 00 00 00 │ Example Inc.\n\
+             Bob      │ bob@example.com                  │                   │"
+        );
+    }
+
+    #[test]
+    fn plain_output() {
+        assert_eq!(
+            to_plain(&contacts()),
+            "Jane Doe\tjane@example.com,jd@example.org\t+33 6 00 00 00 00\tExample Inc.\n\
+             Bob\tbob@example.com\t\t"
+        );
+    }
+}
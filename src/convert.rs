@@ -0,0 +1,477 @@
+//! Conversion of cards between vCard 2.1, 3.0 and 4.0.
+//!
+//! Values are first brought to the 3.0/4.0 escaped form, then rendered
+//! with the encodings and the parameter syntax of the target version.
+
+use crate::{
+    contact::{self, Contact},
+    vcard::{self, Property, Version},
+};
+
+/// Properties holding binary data, either inline or as URI.
+const BINARY_PROPS: [&str; 4] = ["PHOTO", "LOGO", "SOUND", "KEY"];
+
+/// Properties whose values are not escaped text (lists, URIs, dates…).
+const RAW_PROPS: [&str; 9] = [
+    "CATEGORIES",
+    "NICKNAME",
+    "URL",
+    "SOURCE",
+    "GEO",
+    "REV",
+    "BDAY",
+    "ANNIVERSARY",
+    "MEMBER",
+];
+
+/// Types that do not exist anymore in vCard 4.0.
+const OBSOLETE_TYPES: [&str; 6] = ["internet", "x400", "dom", "intl", "postal", "parcel"];
+
+/// Parameters rebuilt by the conversion.
+const CONVERTED_PARAMS: [&str; 6] = ["TYPE", "ENCODING", "CHARSET", "VALUE", "PREF", "MEDIATYPE"];
+
+const ENCODINGS: [&str; 5] = ["b", "base64", "quoted-printable", "8bit", "7bit"];
+
+/// Value of a binary property.
+#[derive(Debug, PartialEq)]
+pub enum Binary {
+    Inline {
+        media_type: Option<String>,
+        data: String,
+    },
+    Uri(String),
+}
+
+fn decode_quoted_printable(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'=', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    decoded
+}
+
+fn encode_quoted_printable(text: &str) -> String {
+    text.replace('\n', "\r\n")
+        .bytes()
+        .map(|byte| match byte {
+            b'=' => format!("={:02X}", byte),
+            b' '..=b'~' => (byte as char).to_string(),
+            _ => format!("={:02X}", byte),
+        })
+        .collect()
+}
+
+/// Decodes bytes in the given charset. Only UTF-8 and Latin-1 are supported,
+/// other charsets are decoded as UTF-8.
+fn decode_charset(bytes: Vec<u8>, charset: Option<&str>) -> String {
+    match charset.map(str::to_lowercase).as_deref() {
+        Some("iso-8859-1") | Some("latin1") | Some("windows-1252") => {
+            bytes.into_iter().map(char::from).collect()
+        }
+        _ => String::from_utf8_lossy(&bytes).to_string(),
+    }
+}
+
+/// Escapes a vCard 2.1 value the way 3.0 and 4.0 expect it: commas and new
+/// lines are escaped, semicolons are already.
+fn escape_v21(value: &str) -> String {
+    let mut escaped = String::new();
+    let mut chars = value.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                escaped.push(c);
+                escaped.extend(chars.next());
+            }
+            ',' => escaped.push_str("\\,"),
+            '\r' if chars.peek() == Some(&'\n') => (),
+            '\r' | '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Reverts [`escape_v21`].
+fn unescape_v21(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some(',')) => {
+                unescaped.push(',');
+                chars.next();
+            }
+            ('\\', Some('n')) | ('\\', Some('N')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            ('\\', Some(next)) => {
+                unescaped.push(c);
+                unescaped.push(next);
+                chars.next();
+            }
+            (c, _) => unescaped.push(c),
+        }
+    }
+
+    unescaped
+}
+
+fn media_type(prop: &str, t: &str) -> String {
+    if t.contains('/') {
+        return t.to_lowercase();
+    }
+    let kind = match prop {
+        "SOUND" => "audio",
+        "KEY" => "application",
+        _ => "image",
+    };
+    format!("{}/{}", kind, t.to_lowercase())
+}
+
+fn parse_binary(prop: &Property, types: &[String]) -> Binary {
+    let is_inline = prop
+        .encoding()
+        .map(|encoding| encoding == "b" || encoding == "base64")
+        .unwrap_or(false);
+
+    if is_inline {
+        return Binary::Inline {
+            media_type: types.first().map(|t| media_type(&prop.name, t)),
+            data: prop.value.split_whitespace().collect(),
+        };
+    }
+
+    // Data URIs (vCard 4.0): `data:image/jpeg;base64,…`.
+    if let Some(uri) = prop.value.strip_prefix("data:") {
+        if let Some(i) = uri.find(',') {
+            let media_type = uri[..i].trim_end_matches(";base64");
+            return Binary::Inline {
+                media_type: Some(media_type.to_owned()).filter(|t| !t.is_empty()),
+                data: uri[i + 1..].to_owned(),
+            };
+        }
+    }
+
+    Binary::Uri(prop.value.to_owned())
+}
+
+fn render_binary(binary: Binary, to: Version, params: &mut Vec<(String, String)>) -> String {
+    let short_type = |media_type: &str| {
+        let t = media_type.rsplit('/').next().unwrap_or(media_type);
+        t.to_uppercase()
+    };
+
+    match (binary, to) {
+        (Binary::Inline { media_type, data }, Version::V40) => format!(
+            "data:{};base64,{}",
+            media_type.unwrap_or_else(|| String::from("application/octet-stream")),
+            data
+        ),
+        (Binary::Inline { media_type, data }, _) => {
+            let encoding = if to == Version::V21 { "BASE64" } else { "b" };
+            params.push((String::from("ENCODING"), String::from(encoding)));
+            if let Some(media_type) = media_type {
+                params.push((String::from("TYPE"), short_type(&media_type)));
+            }
+            data
+        }
+        (Binary::Uri(uri), Version::V40) => uri,
+        (Binary::Uri(uri), _) => {
+            let value = if to == Version::V21 { "URL" } else { "uri" };
+            params.push((String::from("VALUE"), String::from(value)));
+            uri
+        }
+    }
+}
+
+/// Reads the value of a binary property (like `PHOTO`), in any version.
+pub fn binary(prop: &Property) -> Binary {
+    let types: Vec<String> = prop
+        .types()
+        .into_iter()
+        .filter(|t| !ENCODINGS.contains(&t.as_str()))
+        .collect();
+    parse_binary(prop, &types)
+}
+
+/// Builds a binary property with the given value, in the syntax of the given
+/// version. Parameters other than the encoding ones are kept.
+pub fn binary_prop(prop: &Property, binary: Binary, to: Version) -> Property {
+    let mut params: Vec<(String, String)> = prop
+        .params
+        .iter()
+        .filter(|(key, _)| !CONVERTED_PARAMS.contains(&key.as_str()))
+        .cloned()
+        .collect();
+    let value = render_binary(binary, to, &mut params);
+
+    Property {
+        group: prop.group.to_owned(),
+        name: prop.name.to_owned(),
+        params,
+        value,
+    }
+}
+
+/// Converts one property. Returns `None` for properties that are rebuilt
+/// by the conversion itself.
+fn convert_prop(prop: &Property, from: Version, to: Version) -> Option<String> {
+    if ["BEGIN", "END", "VERSION"].contains(&prop.name.as_str()) {
+        return None;
+    }
+
+    let mut types: Vec<String> = prop
+        .types()
+        .into_iter()
+        .filter(|t| !ENCODINGS.contains(&t.as_str()))
+        .collect();
+    let mut is_pref = prop.param("PREF").is_some();
+    if let Some(i) = types.iter().position(|t| t == "pref") {
+        types.remove(i);
+        is_pref = true;
+    }
+    let is_uri = prop
+        .param("VALUE")
+        .map(|value| value.eq_ignore_ascii_case("uri") || value.eq_ignore_ascii_case("url"))
+        .unwrap_or(false);
+
+    let mut params: Vec<(String, String)> = prop
+        .params
+        .iter()
+        .filter(|(key, _)| !CONVERTED_PARAMS.contains(&key.as_str()))
+        .cloned()
+        .collect();
+    let mut value = if BINARY_PROPS.contains(&prop.name.as_str()) {
+        let binary = parse_binary(prop, &types);
+        types.clear();
+        render_binary(binary, to, &mut params)
+    } else {
+        // Value in the 3.0/4.0 escaped form.
+        let value = match prop.encoding().as_deref() {
+            Some("quoted-printable") => {
+                decode_charset(decode_quoted_printable(&prop.value), prop.param("CHARSET"))
+            }
+            _ => prop.value.to_owned(),
+        };
+        let is_raw = is_uri || RAW_PROPS.contains(&prop.name.as_str());
+        let value = match from {
+            Version::V21 if !is_raw => escape_v21(&value),
+            _ => value,
+        };
+        // vCard 4.0 phone numbers can be `tel:` URIs.
+        let value = match (prop.name.as_str(), to) {
+            ("TEL", Version::V40) => value,
+            ("TEL", _) => value.trim_start_matches("tel:").to_owned(),
+            _ => value,
+        };
+        if is_uri && (prop.name != "TEL" || to == Version::V40) {
+            let uri = if to == Version::V21 { "URL" } else { "uri" };
+            params.push((String::from("VALUE"), String::from(uri)));
+        }
+
+        match to {
+            Version::V21 if !is_raw => {
+                let value = unescape_v21(&value);
+                if value.is_ascii() && !value.contains('\n') {
+                    value
+                } else {
+                    params.push((String::from("CHARSET"), String::from("UTF-8")));
+                    params.push((String::from("ENCODING"), String::from("QUOTED-PRINTABLE")));
+                    encode_quoted_printable(&value)
+                }
+            }
+            _ => value,
+        }
+    };
+
+    if to == Version::V40 {
+        types.retain(|t| !OBSOLETE_TYPES.contains(&t.as_str()));
+    }
+    let mut line = String::new();
+    if let Some(ref group) = prop.group {
+        line.push_str(group);
+        line.push('.');
+    }
+    line.push_str(&prop.name);
+    match to {
+        // vCard 2.1 types are bare parameters: `TEL;WORK;VOICE:…`.
+        Version::V21 => {
+            if is_pref {
+                types.push(String::from("pref"));
+            }
+            for t in types {
+                line.push(';');
+                line.push_str(&t.to_uppercase());
+            }
+        }
+        Version::V30 => {
+            if is_pref {
+                types.push(String::from("pref"));
+            }
+            if !types.is_empty() {
+                line.push_str(&format!(";TYPE={}", types.join(",")));
+            }
+        }
+        Version::V40 => {
+            if !types.is_empty() {
+                line.push_str(&format!(";TYPE={}", types.join(",")));
+            }
+            if is_pref {
+                let pref = prop.param("PREF").unwrap_or("1");
+                line.push_str(&format!(";PREF={}", pref));
+            }
+        }
+    }
+    for (key, val) in params {
+        line.push_str(&format!(";{}={}", key, val));
+    }
+    line.push(':');
+    value.insert_str(0, &line);
+
+    Some(value)
+}
+
+/// Converts a card to the given version. Cards without version are
+/// considered as vCard 3.0. Missing `FN` (required since 3.0) and `N`
+/// (required until 3.0) are generated from the other properties.
+pub fn convert(content: &str, to: Version) -> String {
+    let from = vcard::version(content).unwrap_or(Version::V30);
+    let props = vcard::parse(content);
+    let has = |name: &str| {
+        props
+            .iter()
+            .any(|prop| prop.name == name && !prop.value.trim().is_empty())
+    };
+
+    let mut lines = vec![String::from("BEGIN:VCARD"), format!("VERSION:{}", to)];
+
+    // The name is guessed from decoded values, whatever the source version.
+    let decoded: Vec<_> = props
+        .iter()
+        .filter_map(|prop| convert_prop(prop, from, Version::V30))
+        .collect();
+    let name = Contact::from_vcard(&decoded.join("\r\n")).name;
+    if !name.is_empty() {
+        if to != Version::V21 && !has("FN") {
+            let prop = Property::new("FN", &vcard::escape(&name));
+            lines.extend(convert_prop(&prop, Version::V30, to));
+        }
+        if to != Version::V40 && !has("N") {
+            let prop = Property::new("N", &contact::structured_name(&name));
+            lines.extend(convert_prop(&prop, Version::V30, to));
+        }
+    }
+
+    lines.extend(props.iter().filter_map(|prop| convert_prop(prop, from, to)));
+    lines.push(String::from("END:VCARD"));
+
+    lines.into_iter().map(|line| line + "\r\n").collect()
+}
+
+/// Converts all the cards of a content, as found in a `.vcf` file.
+pub fn convert_all(content: &str, to: Version) -> String {
+    let cards: Vec<_> = vcard::split(content)
+        .iter()
+        .map(|card| convert(card, to))
+        .collect();
+    vcard::join(&cards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V21: &str = "BEGIN:VCARD\r\n\
+        VERSION:2.1\r\n\
+        N:Doe;Jérôme;;;\r\n\
+        NOTE;CHARSET=UTF-8;ENCODING=QUOTED-PRINTABLE:J=C3=A9r=C3=B4me, a friend=0D=0Aof Alice\r\n\
+        TEL;WORK;VOICE;PREF:+33 1 23 45 67 89\r\n\
+        EMAIL;INTERNET:jerome@doe.com\r\n\
+        PHOTO;ENCODING=BASE64;TYPE=JPEG:aGVsbG8=\r\n\
+        END:VCARD\r\n";
+
+    #[test]
+    fn v21_to_v30() {
+        assert_eq!(
+            convert(V21, Version::V30),
+            "BEGIN:VCARD\r\n\
+            VERSION:3.0\r\n\
+            FN:Jérôme Doe\r\n\
+            N:Doe;Jérôme;;;\r\n\
+            NOTE:Jérôme\\, a friend\\nof Alice\r\n\
+            TEL;TYPE=work,voice,pref:+33 1 23 45 67 89\r\n\
+            EMAIL;TYPE=internet:jerome@doe.com\r\n\
+            PHOTO;ENCODING=b;TYPE=JPEG:aGVsbG8=\r\n\
+            END:VCARD\r\n"
+        );
+    }
+
+    #[test]
+    fn v21_to_v40() {
+        assert_eq!(
+            convert(V21, Version::V40),
+            "BEGIN:VCARD\r\n\
+            VERSION:4.0\r\n\
+            FN:Jérôme Doe\r\n\
+            N:Doe;Jérôme;;;\r\n\
+            NOTE:Jérôme\\, a friend\\nof Alice\r\n\
+            TEL;TYPE=work,voice;PREF=1:+33 1 23 45 67 89\r\n\
+            EMAIL:jerome@doe.com\r\n\
+            PHOTO:data:image/jpeg;base64,aGVsbG8=\r\n\
+            END:VCARD\r\n"
+        );
+    }
+
+    #[test]
+    fn v40_to_v21_and_back() {
+        let v40 = convert(V21, Version::V40);
+        let v21 = convert(&v40, Version::V21);
+
+        assert_eq!(
+            v21,
+            "BEGIN:VCARD\r\n\
+            VERSION:2.1\r\n\
+            FN;CHARSET=UTF-8;ENCODING=QUOTED-PRINTABLE:J=C3=A9r=C3=B4me Doe\r\n\
+            N;CHARSET=UTF-8;ENCODING=QUOTED-PRINTABLE:Doe;J=C3=A9r=C3=B4me;;;\r\n\
+            NOTE;CHARSET=UTF-8;ENCODING=QUOTED-PRINTABLE:J=C3=A9r=C3=B4me, a friend=0D=0Aof Alice\r\n\
+            TEL;WORK;VOICE;PREF:+33 1 23 45 67 89\r\n\
+            EMAIL:jerome@doe.com\r\n\
+            PHOTO;ENCODING=BASE64;TYPE=JPEG:aGVsbG8=\r\n\
+            END:VCARD\r\n"
+        );
+        assert_eq!(convert(&v21, Version::V40), v40);
+    }
+
+    #[test]
+    fn binary_uri() {
+        let prop = Property::parse("PHOTO;VALUE=uri:https://doe.com/jerome.jpg").unwrap();
+        let uri = Binary::Uri(String::from("https://doe.com/jerome.jpg"));
+        assert_eq!(binary(&prop), uri);
+        assert_eq!(
+            binary_prop(&prop, uri, Version::V21).to_string(),
+            "PHOTO;VALUE=URL:https://doe.com/jerome.jpg"
+        );
+    }
+}
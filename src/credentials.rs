@@ -0,0 +1,357 @@
+//! Sources of the password: an environment variable, a file, a command or
+//! the freedesktop Secret Service (over D-Bus), which is used when no other
+//! source is configured.
+
+use async_trait::async_trait;
+use error_chain::{bail, error_chain};
+use secret_service::{EncryptionType, SecretService};
+use std::{
+    collections::HashMap,
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+};
+
+use crate::config::Config;
+
+error_chain! {
+    links {
+        Config(crate::config::Error, crate::config::ErrorKind);
+    }
+}
+
+/// Password of the current run, so that it is retrieved only once.
+static PASSWD: Mutex<Option<String>> = Mutex::new(None);
+
+/// Attributes identifying the password of the account in the Secret
+/// Service.
+fn attributes(config: &Config) -> HashMap<&str, &str> {
+    let mut attrs = HashMap::new();
+    attrs.insert("service", "cardamom");
+    attrs.insert("host", config.host.as_str());
+    attrs.insert("login", config.login.as_str());
+    attrs
+}
+
+/// Store of secrets identified by attributes.
+#[async_trait]
+trait Keyring: Send + Sync {
+    /// Finds the secret matching the attributes.
+    async fn search(&self, attrs: HashMap<&str, &str>) -> Result<Option<String>>;
+
+    /// Stores a secret, replacing the one with the same attributes.
+    async fn store(&self, label: &str, attrs: HashMap<&str, &str>, secret: &str) -> Result<()>;
+}
+
+/// The freedesktop Secret Service, reached over D-Bus.
+struct SecretServiceKeyring;
+
+impl SecretServiceKeyring {
+    async fn connect<'a>() -> Result<SecretService<'a>> {
+        SecretService::connect(EncryptionType::Dh)
+            .await
+            .chain_err(|| "Could not connect to the Secret Service")
+    }
+}
+
+#[async_trait]
+impl Keyring for SecretServiceKeyring {
+    async fn search(&self, attrs: HashMap<&str, &str>) -> Result<Option<String>> {
+        let service = Self::connect().await?;
+        let items = service
+            .search_items(attrs)
+            .await
+            .chain_err(|| "Could not search the Secret Service")?;
+        let item = match items.unlocked.first().or_else(|| items.locked.first()) {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        item.ensure_unlocked()
+            .await
+            .chain_err(|| "Could not unlock the password")?;
+        let secret = item
+            .get_secret()
+            .await
+            .chain_err(|| "Could not read the password from the Secret Service")?;
+
+        String::from_utf8(secret)
+            .map(Some)
+            .chain_err(|| "Invalid utf8 password")
+    }
+
+    async fn store(&self, label: &str, attrs: HashMap<&str, &str>, secret: &str) -> Result<()> {
+        let service = Self::connect().await?;
+        let collection = service
+            .get_default_collection()
+            .await
+            .chain_err(|| "Could not find the default collection of the Secret Service")?;
+        collection
+            .ensure_unlocked()
+            .await
+            .chain_err(|| "Could not unlock the default collection")?;
+        collection
+            .create_item(label, attrs, secret.as_bytes(), true, "text/plain")
+            .await
+            .chain_err(|| "Could not store the password in the Secret Service")?;
+
+        Ok(())
+    }
+}
+
+/// Reads the password of the account from the keyring.
+async fn keyring_passwd(config: &Config, keyring: &dyn Keyring) -> Result<String> {
+    keyring.search(attributes(config)).await?.chain_err(|| {
+        format!(
+            "No password found in the Secret Service for {}@{} (run `cardamom login` first)",
+            config.login, config.host
+        )
+    })
+}
+
+/// Stores the password of the account in the keyring, replacing the
+/// previous one.
+async fn store_passwd_in(config: &Config, keyring: &dyn Keyring, passwd: &str) -> Result<()> {
+    let label = format!("Cardamom password for {}@{}", config.login, config.host);
+    keyring.store(&label, attributes(config), passwd).await
+}
+
+/// Stores the password of the account in the default collection of the
+/// Secret Service, replacing the previous one.
+pub async fn store_keyring_passwd(config: &Config, passwd: &str) -> Result<()> {
+    store_passwd_in(config, &SecretServiceKeyring, passwd).await
+}
+
+/// Checks that a password file is not accessible by other users.
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata =
+        fs::metadata(path).chain_err(|| format!("Could not read password file {:?}", path))?;
+    let mode = metadata.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        bail!(
+            "Password file {:?} is accessible by other users (mode {:o}, expected 600)",
+            path,
+            mode
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn file_passwd(path: &Path) -> Result<String> {
+    check_permissions(path)?;
+    let passwd = fs::read_to_string(path)
+        .chain_err(|| format!("Could not read password file {:?}", path))?;
+    Ok(passwd.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+/// Writes a password file, only readable by its owner.
+pub fn store_file_passwd(path: &Path, passwd: &str) -> Result<()> {
+    let mut opts = OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+
+    let mut file = opts
+        .open(path)
+        .chain_err(|| format!("Could not open password file {:?}", path))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))
+            .chain_err(|| format!("Could not restrict permissions of {:?}", path))?;
+    }
+    writeln!(file, "{}", passwd).chain_err(|| format!("Could not write password file {:?}", path))
+}
+
+/// Source of the password, by order of precedence.
+#[derive(Debug, PartialEq)]
+enum Source<'a> {
+    Env(&'a str),
+    File(&'a Path),
+    Cmd,
+    Keyring,
+}
+
+fn source(config: &Config) -> Source<'_> {
+    if let Some(ref var) = config.passwd_env {
+        return Source::Env(var);
+    }
+    if let Some(ref path) = config.passwd_file {
+        return Source::File(path);
+    }
+    if !config.passwd_cmd.is_empty() {
+        return Source::Cmd;
+    }
+    Source::Keyring
+}
+
+async fn fetch_passwd(config: &Config) -> Result<String> {
+    match source(config) {
+        Source::Env(var) => env::var(var)
+            .chain_err(|| format!("Could not read password from environment variable {}", var)),
+        Source::File(path) => file_passwd(path),
+        Source::Cmd => Ok(config.passwd()?),
+        Source::Keyring => keyring_passwd(config, &SecretServiceKeyring).await,
+    }
+}
+
+/// Uses the given password for the rest of the run, whatever the configured
+/// source.
+pub fn set_passwd(passwd: &str) {
+    if let Ok(mut prev) = PASSWD.lock() {
+        *prev = Some(passwd.to_owned());
+    }
+}
+
+/// Retrieves the password from the configured source.
+pub async fn passwd(config: &Config) -> Result<String> {
+    if let Some(passwd) = PASSWD.lock().ok().and_then(|passwd| passwd.clone()) {
+        return Ok(passwd);
+    }
+
+    let passwd = fetch_passwd(config).await?;
+    if let Ok(mut prev) = PASSWD.lock() {
+        *prev = Some(passwd.to_owned());
+    }
+    Ok(passwd)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn config(keys: &str) -> Config {
+        toml::from_str(&format!("sync-dir = \"/tmp\"\n{}", keys)).unwrap()
+    }
+
+    #[test]
+    fn sources() {
+        let config_env =
+            config("passwd-env = \"PASSWD\"\npasswd-file = \"/p\"\npasswd-cmd = \"echo\"");
+        let config_file = config("passwd-file = \"/p\"\npasswd-cmd = \"echo\"");
+        let config_cmd = config("passwd-cmd = \"echo\"");
+        let config_keyring = config("");
+
+        assert_eq!(source(&config_env), Source::Env("PASSWD"));
+        assert_eq!(source(&config_file), Source::File(Path::new("/p")));
+        assert_eq!(source(&config_cmd), Source::Cmd);
+        assert_eq!(source(&config_keyring), Source::Keyring);
+    }
+
+    #[tokio::test]
+    async fn env_and_cmd_passwords() {
+        env::set_var("TEST_CARDAMOM_PASSWD", "env secret");
+        let config_env = config("passwd-env = \"TEST_CARDAMOM_PASSWD\"");
+        let config_cmd = config("passwd-cmd = \"echo cmd secret\"");
+
+        assert_eq!(fetch_passwd(&config_env).await.unwrap(), "env secret");
+        assert_eq!(fetch_passwd(&config_cmd).await.unwrap(), "cmd secret");
+    }
+
+    struct Item {
+        attrs: HashMap<String, String>,
+        label: String,
+        secret: String,
+    }
+
+    /// Keyring kept in memory.
+    #[derive(Default)]
+    struct MemoryKeyring {
+        items: Mutex<Vec<Item>>,
+    }
+
+    fn owned(attrs: HashMap<&str, &str>) -> HashMap<String, String> {
+        attrs
+            .into_iter()
+            .map(|(key, val)| (key.to_owned(), val.to_owned()))
+            .collect()
+    }
+
+    #[async_trait]
+    impl Keyring for MemoryKeyring {
+        async fn search(&self, attrs: HashMap<&str, &str>) -> Result<Option<String>> {
+            let attrs = owned(attrs);
+            let items = self.items.lock().unwrap();
+            Ok(items
+                .iter()
+                .find(|item| item.attrs == attrs)
+                .map(|item| item.secret.to_owned()))
+        }
+
+        async fn store(&self, label: &str, attrs: HashMap<&str, &str>, secret: &str) -> Result<()> {
+            let attrs = owned(attrs);
+            let mut items = self.items.lock().unwrap();
+            items.retain(|item| item.attrs != attrs);
+            items.push(Item {
+                attrs,
+                label: label.to_owned(),
+                secret: secret.to_owned(),
+            });
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn keyring_passwords() {
+        let keyring = MemoryKeyring::default();
+        let config_jane = config("host = \"example.com\"\nlogin = \"jane\"");
+        let config_john = config("host = \"example.com\"\nlogin = \"john\"");
+
+        assert!(keyring_passwd(&config_jane, &keyring).await.is_err());
+
+        store_passwd_in(&config_jane, &keyring, "old secret")
+            .await
+            .unwrap();
+        store_passwd_in(&config_jane, &keyring, "jane secret")
+            .await
+            .unwrap();
+        assert_eq!(
+            keyring_passwd(&config_jane, &keyring).await.unwrap(),
+            "jane secret"
+        );
+        assert!(keyring_passwd(&config_john, &keyring).await.is_err());
+
+        let items = keyring.items.lock().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].attrs["service"], "cardamom");
+        assert_eq!(items[0].attrs["host"], "example.com");
+        assert_eq!(items[0].attrs["login"], "jane");
+        assert_eq!(items[0].label, "Cardamom password for jane@example.com");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn passwd_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("passwd");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        assert!(file_passwd(&path).is_err());
+
+        store_file_passwd(&path, "file secret").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o600);
+        assert_eq!(file_passwd(&path).unwrap(), "file secret");
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        assert!(file_passwd(&path).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+//! Detection and merge of duplicate contacts.
+
+use std::{
+    cmp::max,
+    collections::{BTreeSet, HashMap},
+};
+
+use crate::{
+    contact::Contact,
+    convert,
+    local::model::Card,
+    vcard::{self, Property},
+};
+
+/// Properties that should appear only once per card. When merged cards
+/// disagree on them, only one value is kept.
+const SINGULAR_PROPS: [&str; 10] = [
+    "VERSION",
+    "UID",
+    "FN",
+    "N",
+    "BDAY",
+    "ANNIVERSARY",
+    "GENDER",
+    "KIND",
+    "PHOTO",
+    "REV",
+];
+
+/// Properties that are regenerated or meaningless once merged.
+const SKIPPED_PROPS: [&str; 3] = ["BEGIN", "END", "PRODID"];
+
+/// Length of the normalized name prefix used to bucket cards before
+/// comparing their names.
+const NAME_PREFIX_LEN: usize = 2;
+
+/// Cards that are likely the same contact, with the reasons why.
+#[derive(Debug)]
+pub struct Group {
+    pub names: Vec<String>,
+    pub reasons: BTreeSet<String>,
+    /// Whether all the cards are linked by a shared email. Groups
+    /// linked by a phone number or a similar name may be different people.
+    pub is_exact: bool,
+}
+
+fn normalize_name(name: &str) -> String {
+    let name: String = name
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+    let mut words: Vec<_> = name.split_whitespace().collect();
+    words.sort_unstable();
+    words.join(" ")
+}
+
+/// Keeps the last 9 digits of a phone number, so that national and
+/// international forms of the same number match.
+fn normalize_phone(phone: &str) -> Option<String> {
+    let digits: Vec<_> = phone.chars().filter(char::is_ascii_digit).collect();
+    if digits.len() < 5 {
+        return None;
+    }
+    Some(digits[digits.len().saturating_sub(9)..].iter().collect())
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<_> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            curr.push((prev[j] + cost).min(prev[j + 1] + 1).min(curr[j] + 1));
+        }
+        prev = curr;
+    }
+
+    prev[b.len()]
+}
+
+/// Checks if two normalized names are similar: equal, or close enough for
+/// long names (a typo, a missing letter…).
+fn is_similar_name(a: &str, b: &str) -> bool {
+    if a.is_empty() || b.is_empty() {
+        return false;
+    }
+    let (len_a, len_b) = (a.chars().count(), b.chars().count());
+    let len = max(len_a, len_b);
+    a == b || (len >= 8 && len - len_a.min(len_b) <= len / 8 && levenshtein(a, b) <= len / 8)
+}
+
+fn find(parents: &mut Vec<usize>, i: usize) -> usize {
+    if parents[i] != i {
+        parents[i] = find(parents, parents[i]);
+    }
+    parents[i]
+}
+
+/// Groups the cards sharing an email, a phone number or a similar name.
+/// Names are only compared among cards whose names start alike. Cards
+/// sharing a UID cannot be found here, since the repositories keep only one
+/// card per UID.
+pub fn find_duplicates(cards: &HashMap<String, Card>) -> Vec<Group> {
+    let mut names: Vec<&String> = cards.keys().collect();
+    names.sort();
+    let contacts: Vec<_> = names
+        .iter()
+        .map(|name| Contact::from_vcard(&cards[*name].content))
+        .collect();
+
+    // Exact links (emails) are also tracked apart, to tell whether a
+    // group holds without the weaker ones.
+    let mut parents: Vec<usize> = (0..names.len()).collect();
+    let mut exact_parents: Vec<usize> = (0..names.len()).collect();
+    let mut reasons: Vec<(usize, usize, String)> = vec![];
+    let mut union = |parents: &mut Vec<usize>, i: usize, j: usize, reason: String| {
+        let (ri, rj) = (find(parents, i), find(parents, j));
+        parents[max(ri, rj)] = ri.min(rj);
+        reasons.push((i, j, reason));
+    };
+    let exact_union = |parents: &mut Vec<usize>, i: usize, j: usize| {
+        let (ri, rj) = (find(parents, i), find(parents, j));
+        parents[max(ri, rj)] = ri.min(rj);
+    };
+
+    let mut emails: HashMap<String, usize> = HashMap::new();
+    let mut phones: HashMap<String, usize> = HashMap::new();
+    for (i, contact) in contacts.iter().enumerate() {
+        for email in contact.emails.iter() {
+            let email = email.to_lowercase();
+            match emails.get(&email) {
+                Some(j) if *j != i => {
+                    union(&mut parents, *j, i, format!("same email {}", email));
+                    exact_union(&mut exact_parents, *j, i);
+                }
+                Some(_) => (),
+                None => {
+                    emails.insert(email, i);
+                }
+            }
+        }
+        for phone in contact.phones.iter() {
+            let key = match normalize_phone(phone) {
+                Some(key) => key,
+                None => continue,
+            };
+            match phones.get(&key) {
+                Some(j) if *j != i => union(&mut parents, *j, i, format!("same phone {}", phone)),
+                Some(_) => (),
+                None => {
+                    phones.insert(key, i);
+                }
+            }
+        }
+    }
+
+    // Comparing all the names is quadratic, so only names sharing a prefix
+    // are compared. Typos in the first letters are missed.
+    let normalized: Vec<_> = contacts.iter().map(|c| normalize_name(&c.name)).collect();
+    let mut buckets: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, name) in normalized.iter().enumerate() {
+        let prefix: String = name.chars().take(NAME_PREFIX_LEN).collect();
+        buckets.entry(prefix).or_default().push(i);
+    }
+    let mut buckets: Vec<_> = buckets.into_values().collect();
+    buckets.sort();
+    for bucket in buckets {
+        for (k, i) in bucket.iter().enumerate() {
+            for j in bucket[k + 1..].iter() {
+                if is_similar_name(&normalized[*i], &normalized[*j]) {
+                    let reason = format!(
+                        "similar names {:?} and {:?}",
+                        contacts[*i].name, contacts[*j].name
+                    );
+                    union(&mut parents, *i, *j, reason);
+                }
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Group> = HashMap::new();
+    let mut exact_roots: HashMap<usize, BTreeSet<usize>> = HashMap::new();
+    for (i, name) in names.iter().enumerate() {
+        let root = find(&mut parents, i);
+        groups
+            .entry(root)
+            .or_insert_with(|| Group {
+                names: vec![],
+                reasons: BTreeSet::new(),
+                is_exact: true,
+            })
+            .names
+            .push(name.to_string());
+        let exact_root = find(&mut exact_parents, i);
+        exact_roots.entry(root).or_default().insert(exact_root);
+    }
+    for (root, group) in groups.iter_mut() {
+        group.is_exact = exact_roots[root].len() == 1;
+    }
+    for (i, _, reason) in reasons {
+        let root = find(&mut parents, i);
+        if let Some(group) = groups.get_mut(&root) {
+            group.reasons.insert(reason);
+        }
+    }
+
+    let mut groups: Vec<_> = groups
+        .into_values()
+        .filter(|group| group.names.len() > 1)
+        .collect();
+    groups.sort_by(|a, b| a.names.cmp(&b.names));
+    groups
+}
+
+/// Value used to detect that two properties are the same.
+fn prop_key(prop: &Property) -> String {
+    let text = prop.text();
+    match prop.name.as_str() {
+        "EMAIL" => text.trim().to_lowercase(),
+        "TEL" => normalize_phone(&text).unwrap_or_else(|| text.trim().to_owned()),
+        _ => text.trim().to_owned(),
+    }
+}
+
+/// Merges cards property by property into the first one, after converting
+/// them to its version. Properties missing from the first card are added.
+/// When cards disagree on a singular property (like `FN` or `BDAY`),
+/// `choose` is given the property name and the distinct values, and returns
+/// the index of the one to keep.
+pub fn merge<F>(contents: &[&str], mut choose: F) -> String
+where
+    F: FnMut(&str, &[String]) -> usize,
+{
+    let version = contents.first().and_then(|content| vcard::version(content));
+    let cards: Vec<Vec<Property>> = contents
+        .iter()
+        .map(|content| match version {
+            Some(version) if vcard::version(content) != Some(version) => {
+                convert::convert(content, version)
+            }
+            _ => content.to_string(),
+        })
+        .map(|content| {
+            vcard::parse(&content)
+                .into_iter()
+                .filter(|prop| !SKIPPED_PROPS.contains(&prop.name.as_str()))
+                .collect()
+        })
+        .collect();
+    let mut props: Vec<Property> = cards.first().cloned().unwrap_or_default();
+
+    // Singular properties: one value among all the cards. The UID of the
+    // first card is kept as is, since it identifies the merged card.
+    for name in SINGULAR_PROPS.iter().filter(|name| **name != "UID") {
+        let mut values: Vec<&Property> = vec![];
+        for prop in cards.iter().flatten().filter(|prop| prop.name == *name) {
+            if !values.iter().any(|val| prop_key(val) == prop_key(prop)) {
+                values.push(prop);
+            }
+        }
+        let chosen = match values.len() {
+            0 => continue,
+            _ if *name == "VERSION" => 0,
+            1 => 0,
+            _ => {
+                let texts: Vec<_> = values.iter().map(|prop| prop.text()).collect();
+                choose(name, &texts).min(values.len() - 1)
+            }
+        };
+        let chosen = values[chosen].clone();
+        match props.iter().position(|prop| prop.name == *name) {
+            Some(i) => props[i] = chosen,
+            None => props.push(chosen),
+        }
+    }
+
+    // Other properties: all the distinct values. Groups of added properties
+    // are renamed, so that they do not collide with existing ones.
+    let mut next_group = props.len();
+    for card in cards.iter().skip(1) {
+        let mut groups: HashMap<String, String> = HashMap::new();
+        for prop in card.iter() {
+            if SINGULAR_PROPS.contains(&prop.name.as_str()) {
+                continue;
+            }
+            let exists = props
+                .iter()
+                .any(|p| p.name == prop.name && prop_key(p) == prop_key(prop));
+            if exists {
+                continue;
+            }
+
+            let mut prop = prop.clone();
+            if let Some(ref group) = prop.group {
+                let group = groups.entry(group.to_owned()).or_insert_with(|| {
+                    next_group += 1;
+                    format!("merged{}", next_group)
+                });
+                prop.group = Some(group.to_owned());
+            }
+            props.push(prop);
+        }
+    }
+
+    // Keeps VERSION first, as some clients expect it.
+    props.sort_by_key(|prop| prop.name != "VERSION");
+    vcard::build(&props)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn cards(cards: &[(&str, &str)]) -> HashMap<String, Card> {
+        cards
+            .iter()
+            .map(|(name, props)| {
+                let card = Card {
+                    name: name.to_string(),
+                    path: PathBuf::from(format!("{}.vcf", name)),
+                    date: Utc::now(),
+                    content: format!("BEGIN:VCARD\r\nVERSION:3.0\r\n{}END:VCARD\r\n", props),
+                };
+                (name.to_string(), card)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn similar_names() {
+        assert!(is_similar_name("doe john", "doe john"));
+        assert!(is_similar_name("doe jonathan", "doe jonatan"));
+        assert!(!is_similar_name("doe jon", "doe joe"));
+        assert!(!is_similar_name("doe jonathan", "doe jonathan smith"));
+        assert!(!is_similar_name("", ""));
+    }
+
+    #[test]
+    fn exact_groups() {
+        let cards = cards(&[
+            ("a", "FN:John Doe\r\nEMAIL:john@doe.com\r\n"),
+            ("b", "FN:J. Doe\r\nEMAIL:JOHN@doe.com\r\n"),
+            ("c", "FN:Someone\r\n"),
+        ]);
+        let groups = find_duplicates(&cards);
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].names, vec!["a", "b"]);
+        assert!(groups[0].is_exact);
+    }
+
+    #[test]
+    fn weak_groups() {
+        let cards = cards(&[
+            ("a", "FN:John Doe\r\nEMAIL:john@doe.com\r\n"),
+            (
+                "b",
+                "FN:Jane Doe\r\nEMAIL:john@doe.com\r\nTEL:+33 6 12 34 56 78\r\n",
+            ),
+            ("c", "FN:Kid Doe\r\nTEL:06 12 34 56 78\r\n"),
+            ("d", "FN:Jonathan Smith\r\n"),
+            ("e", "FN:Smith Jonatan\r\n"),
+        ]);
+        let groups = find_duplicates(&cards);
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].names, vec!["a", "b", "c"]);
+        assert!(!groups[0].is_exact);
+        assert_eq!(groups[1].names, vec!["d", "e"]);
+        assert!(!groups[1].is_exact);
+    }
+
+    #[test]
+    fn merge_cards() {
+        let a =
+            "BEGIN:VCARD\r\nVERSION:3.0\r\nUID:a\r\nFN:John\r\nEMAIL:john@doe.com\r\nEND:VCARD\r\n";
+        let b = "BEGIN:VCARD\r\nVERSION:4.0\r\nUID:b\r\nFN:Johnny\r\nEMAIL:JOHN@doe.com\r\nTEL;VALUE=uri;PREF=1:tel:123456\r\nEND:VCARD\r\n";
+        let mut asked = vec![];
+        let merged = merge(&[a, b], |name, values| {
+            asked.push((name.to_owned(), values.to_vec()));
+            1
+        });
+
+        assert_eq!(
+            asked,
+            vec![(
+                String::from("FN"),
+                vec![String::from("John"), String::from("Johnny")]
+            )]
+        );
+        assert_eq!(
+            merged,
+            "BEGIN:VCARD\r\nVERSION:3.0\r\nUID:a\r\nFN:Johnny\r\nEMAIL:john@doe.com\r\nN:;Johnny;;;\r\nTEL;TYPE=pref:123456\r\nEND:VCARD\r\n"
+        );
+    }
+}
@@ -0,0 +1,271 @@
+//! CSV import and export, with a configurable mapping between the columns
+//! and the card fields.
+//!
+//! A field is either `fn`, a component of `N` (`family`, `given`, `middle`,
+//! `prefix`, `suffix`), a component of `ADR` (`po-box`, `extended`,
+//! `street`, `city`, `region`, `postal-code`, `country`), `org`, or any
+//! property name (`email`, `tel`, `title`, `note`…). It can be followed by a
+//! type, like `tel:cell` or `city:home`. Many columns can share a field, in
+//! which case they hold its successive values.
+
+use error_chain::error_chain;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use crate::{
+    config::CsvColumn,
+    contact::Contact,
+    convert,
+    vcard::{self, Property, Version},
+};
+
+error_chain! {
+    foreign_links {
+        Csv(::csv::Error);
+    }
+}
+
+const N_COMPONENTS: [&str; 5] = ["family", "given", "middle", "prefix", "suffix"];
+
+const ADR_COMPONENTS: [&str; 7] = [
+    "po-box",
+    "extended",
+    "street",
+    "city",
+    "region",
+    "postal-code",
+    "country",
+];
+
+/// Splits a field into its name and its optional type.
+fn parse_field(field: &str) -> (String, Option<String>) {
+    let mut parts = field.splitn(2, ':');
+    let name = parts.next().unwrap_or_default().trim().to_lowercase();
+    let kind = parts
+        .next()
+        .map(|kind| kind.trim().to_lowercase())
+        .filter(|kind| !kind.is_empty());
+    (name, kind)
+}
+
+fn has_type(prop: &Property, kind: &Option<String>) -> bool {
+    match kind {
+        Some(kind) => prop.types().contains(kind),
+        None => true,
+    }
+}
+
+/// Lists the values of a field in a card.
+fn field_values(props: &[Property], field: &str) -> Vec<String> {
+    let (name, kind) = parse_field(field);
+    let component = |prop_name: &str, components: &[&str]| -> Vec<String> {
+        let i = components.iter().position(|c| *c == name).unwrap_or(0);
+        props
+            .iter()
+            .filter(|prop| prop.name == prop_name && has_type(prop, &kind))
+            .filter_map(|prop| prop.components().get(i).cloned())
+            .collect()
+    };
+
+    match name.as_str() {
+        name if N_COMPONENTS.contains(&name) => component("N", &N_COMPONENTS),
+        name if ADR_COMPONENTS.contains(&name) => component("ADR", &ADR_COMPONENTS),
+        "org" => component("ORG", &["org"]),
+        name => props
+            .iter()
+            .filter(|prop| prop.name.eq_ignore_ascii_case(name) && has_type(prop, &kind))
+            .map(|prop| prop.text())
+            .collect(),
+    }
+}
+
+/// Exports the cards as CSV, one row per card.
+pub fn export(cards: &[&str], columns: &[CsvColumn], delimiter: u8) -> Result<String> {
+    let mut writer = ::csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(vec![]);
+    writer.write_record(columns.iter().map(|column| &column.header))?;
+
+    for card in cards {
+        let card = match vcard::version(card) {
+            Some(Version::V21) => convert::convert(card, Version::V30),
+            _ => card.to_string(),
+        };
+        let props = vcard::parse(&card);
+        let mut occurrences: BTreeMap<&str, usize> = BTreeMap::new();
+        let row: Vec<String> = columns
+            .iter()
+            .map(|column| {
+                let i = occurrences.entry(&column.field).or_default();
+                let value = field_values(&props, &column.field)
+                    .get(*i)
+                    .cloned()
+                    .unwrap_or_default();
+                *i += 1;
+                value
+            })
+            .collect();
+        writer.write_record(&row)?;
+    }
+
+    let csv = writer.into_inner().chain_err(|| "Could not write CSV")?;
+    String::from_utf8(csv).chain_err(|| "Invalid utf8 CSV")
+}
+
+fn with_type(mut prop: Property, kind: &Option<String>) -> Property {
+    if let Some(kind) = kind {
+        prop.params.push((String::from("TYPE"), kind.to_owned()));
+    }
+    prop
+}
+
+/// Imports the rows of a CSV content as vCard 3.0 cards. Columns missing
+/// from the mapping are ignored.
+pub fn import(content: &str, columns: &[CsvColumn], delimiter: u8) -> Result<Vec<String>> {
+    let mut reader = ::csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_reader(content.as_bytes());
+    let headers = reader.headers()?.clone();
+    let fields: Vec<Option<String>> = headers
+        .iter()
+        .map(|header| {
+            columns
+                .iter()
+                .find(|column| column.header.trim() == header.trim())
+                .map(|column| column.field.to_owned())
+        })
+        .collect();
+
+    let mut cards = vec![];
+    for record in reader.records() {
+        let record = record?;
+        let mut props = vec![Property::new("VERSION", "3.0")];
+        let mut n = vec![String::new(); N_COMPONENTS.len()];
+        let mut adrs: BTreeMap<Option<String>, Vec<String>> = BTreeMap::new();
+
+        for (value, field) in record.iter().zip(fields.iter()) {
+            let (value, field) = match field {
+                Some(field) if !value.trim().is_empty() => (value.trim(), field),
+                _ => continue,
+            };
+            let (name, kind) = parse_field(field);
+
+            if let Some(i) = N_COMPONENTS.iter().position(|c| *c == name) {
+                n[i] = vcard::escape(value);
+            } else if let Some(i) = ADR_COMPONENTS.iter().position(|c| *c == name) {
+                adrs.entry(kind)
+                    .or_insert_with(|| vec![String::new(); ADR_COMPONENTS.len()])[i] =
+                    vcard::escape(value);
+            } else {
+                // URIs are not escaped text.
+                let value = match name.as_str() {
+                    "url" => value.to_owned(),
+                    _ => vcard::escape(value),
+                };
+                let prop = Property::new(&name, &value);
+                props.push(with_type(prop, &kind));
+            }
+        }
+
+        if n.iter().any(|component| !component.is_empty()) {
+            props.push(Property::new("N", &n.join(";")));
+        }
+        for (kind, adr) in adrs {
+            props.push(with_type(Property::new("ADR", &adr.join(";")), &kind));
+        }
+        if !props.iter().any(|prop| prop.name == "FN") {
+            let name = Contact::from_vcard(&vcard::build(&props)).name;
+            if name.is_empty() {
+                continue;
+            }
+            props.push(Property::new("FN", &vcard::escape(&name)));
+        }
+        if !props.iter().any(|prop| prop.name == "UID") {
+            props.push(Property::new("UID", &Uuid::new_v4().to_string()));
+        }
+
+        cards.push(vcard::build(&props));
+    }
+
+    Ok(cards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns(fields: &[(&str, &str)]) -> Vec<CsvColumn> {
+        fields
+            .iter()
+            .map(|(header, field)| CsvColumn {
+                header: header.to_string(),
+                field: field.to_string(),
+            })
+            .collect()
+    }
+
+    const CARD: &str = "BEGIN:VCARD\r\n\
+        VERSION:3.0\r\n\
+        UID:jdoe\r\n\
+        FN:John Doe\r\n\
+        N:Doe;John;;;\r\n\
+        EMAIL:john@doe.com\r\n\
+        EMAIL:jdoe@work.com\r\n\
+        TEL;TYPE=cell:+33 6 12 34 56 78\r\n\
+        ADR;TYPE=home:;;1 Main St;Springfield;;;USA\r\n\
+        NOTE:Likes commas\\, semicolons\\; and more\r\n\
+        END:VCARD\r\n";
+
+    #[test]
+    fn export_card() {
+        let columns = columns(&[
+            ("Name", "fn"),
+            ("Email 1", "email"),
+            ("Email 2", "email"),
+            ("Mobile", "tel:cell"),
+            ("City", "city:home"),
+            ("Note", "note"),
+        ]);
+
+        assert_eq!(
+            export(&[CARD], &columns, b';').unwrap(),
+            "Name;Email 1;Email 2;Mobile;City;Note\n\
+            John Doe;john@doe.com;jdoe@work.com;+33 6 12 34 56 78;Springfield;\"Likes commas, semicolons; and more\"\n"
+        );
+    }
+
+    #[test]
+    fn import_rows() {
+        let columns = columns(&[
+            ("First", "given"),
+            ("Last", "family"),
+            ("Email", "email:work"),
+            ("Street", "street:home"),
+            ("City", "city:home"),
+            ("Website", "url"),
+        ]);
+        let content = "First,Last,Email,Street,City,Website,Ignored\n\
+            John,Doe,john@doe.com,1 Main St,Springfield,https://doe.com/a;b,x\n\
+            ,,,,,,y\n";
+        let cards = import(content, &columns, b',').unwrap();
+
+        assert_eq!(cards.len(), 1);
+        let uid = vcard::uid(&cards[0]).unwrap();
+        assert_eq!(
+            cards[0],
+            format!(
+                "BEGIN:VCARD\r\n\
+                VERSION:3.0\r\n\
+                EMAIL;TYPE=work:john@doe.com\r\n\
+                URL:https://doe.com/a;b\r\n\
+                N:Doe;John;;;\r\n\
+                ADR;TYPE=home:;;1 Main St;Springfield;;;\r\n\
+                FN:John Doe\r\n\
+                UID:{}\r\n\
+                END:VCARD\r\n",
+                uid
+            )
+        );
+    }
+}
@@ -0,0 +1,420 @@
+//! jCard, the JSON format for vCard described in [RFC 7095].
+//!
+//! [RFC 7095]: https://tools.ietf.org/html/rfc7095
+
+use error_chain::{bail, error_chain};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    convert,
+    vcard::{self, Property, Version},
+};
+
+error_chain! {
+    foreign_links {
+        Json(serde_json::Error);
+    }
+}
+
+/// Properties whose value is made of components separated by `;`.
+const STRUCTURED_PROPS: [&str; 5] = ["N", "ADR", "ORG", "GENDER", "CLIENTPIDMAP"];
+
+/// Properties whose value is a list separated by `,`.
+const MULTI_VALUED_PROPS: [&str; 2] = ["CATEGORIES", "NICKNAME"];
+
+/// Value types holding dates and times, written in the basic ISO 8601 format
+/// in vCard (`19850412T102200`) and in the extended one in jCard
+/// (`1985-04-12T10:22:00`).
+const DATE_TIME_TYPES: [&str; 5] = ["date", "time", "date-time", "date-and-or-time", "timestamp"];
+
+/// Value type of a property when it has no `VALUE` parameter (RFC 6350).
+fn default_type(name: &str) -> &'static str {
+    match name {
+        "BDAY" | "ANNIVERSARY" | "DEATHDATE" => "date-and-or-time",
+        "REV" => "timestamp",
+        "SOURCE" | "PHOTO" | "LOGO" | "SOUND" | "URL" | "KEY" | "FBURL" | "CALADRURI"
+        | "CALURI" | "IMPP" | "MEMBER" | "RELATED" | "GEO" | "ORG-DIRECTORY" => "uri",
+        "LANG" => "language-tag",
+        name if name.starts_with("X-") => "unknown",
+        _ => "text",
+    }
+}
+
+fn is_text(value_type: &str) -> bool {
+    value_type == "text" || value_type == "unknown"
+}
+
+/// Applies the given conversions to the date and the time parts of a value.
+/// Values of `date-and-or-time` starting with `T` only have a time part.
+fn map_date_time<D, T>(value_type: &str, value: &str, date: D, time: T) -> String
+where
+    D: Fn(&str) -> String,
+    T: Fn(&str) -> String,
+{
+    if value_type == "time" {
+        return time(value);
+    }
+    match value.split_once('T') {
+        Some((d, t)) => format!("{}T{}", date(d), time(t)),
+        None => date(value),
+    }
+}
+
+/// Splits the leading dashes of a truncated date or time (`--0412`, `-2200`)
+/// from its digits.
+fn split_dashes(value: &str) -> (&str, &str) {
+    value.split_at(value.len() - value.trim_start_matches('-').len())
+}
+
+/// Converts a basic date (`19850412`, `--0412`) to the extended format
+/// (`1985-04-12`, `--04-12`). Other forms are the same in both formats.
+fn extended_date(date: &str) -> String {
+    let (dashes, digits) = split_dashes(date);
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return date.to_owned();
+    }
+    match (dashes.len(), digits.len()) {
+        (0, 8) => format!("{}-{}-{}", &digits[..4], &digits[4..6], &digits[6..]),
+        (2, 4) => format!("--{}-{}", &digits[..2], &digits[2..]),
+        _ => date.to_owned(),
+    }
+}
+
+/// Reverts [`extended_date`].
+fn basic_date(date: &str) -> String {
+    let (dashes, digits) = split_dashes(date);
+    let is_extended = match (dashes.len(), digits.len()) {
+        (0, 10) => digits.as_bytes()[4] == b'-' && digits.as_bytes()[7] == b'-',
+        (2, 5) => digits.as_bytes()[2] == b'-',
+        _ => false,
+    };
+    if is_extended {
+        format!("{}{}", dashes, digits.replace('-', ""))
+    } else {
+        date.to_owned()
+    }
+}
+
+/// Converts a basic time (`102200`, `-2200`, `102200-0800`) to the extended
+/// format (`10:22:00`, `-22:00`, `10:22:00-08:00`).
+fn extended_time(time: &str) -> String {
+    let (dashes, rest) = split_dashes(time);
+    let (digits, zone) = match rest.find(&['Z', '+', '-'][..]) {
+        Some(i) => rest.split_at(i),
+        None => (rest, ""),
+    };
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return time.to_owned();
+    }
+
+    let digits: Vec<_> = digits
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| String::from_utf8_lossy(pair))
+        .collect();
+    let zone = match zone.len() {
+        5 => format!("{}:{}", &zone[..3], &zone[3..]),
+        _ => zone.to_owned(),
+    };
+    format!("{}{}{}", dashes, digits.join(":"), zone)
+}
+
+/// Reverts [`extended_time`].
+fn basic_time(time: &str) -> String {
+    time.replace(':', "")
+}
+
+/// Builds a jCard value: a string, or an array if there are many values.
+fn one_or_many(mut values: Vec<Value>) -> Value {
+    if values.len() == 1 {
+        values.remove(0)
+    } else {
+        Value::Array(values)
+    }
+}
+
+fn prop_to_jcard(prop: &Property) -> Value {
+    let value_type = prop
+        .param("VALUE")
+        .map(str::to_lowercase)
+        .unwrap_or_else(|| default_type(&prop.name).to_owned());
+    let text = |raw: &str| -> Value {
+        if is_text(&value_type) {
+            Value::String(vcard::unescape(raw))
+        } else if DATE_TIME_TYPES.contains(&value_type.as_str()) {
+            Value::String(map_date_time(
+                &value_type,
+                raw,
+                extended_date,
+                extended_time,
+            ))
+        } else {
+            Value::String(raw.to_owned())
+        }
+    };
+
+    let mut params = Map::new();
+    if let Some(ref group) = prop.group {
+        params.insert(String::from("group"), json!(group));
+    }
+    for (key, val) in prop.params.iter() {
+        if key == "VALUE" {
+            continue;
+        }
+        let key = key.to_lowercase();
+        let vals: Vec<Value> = val
+            .trim_matches('"')
+            .split(',')
+            .map(|val| json!(val))
+            .collect();
+        // Repeated parameters (like `TYPE=work;TYPE=voice`) are merged.
+        let vals = match params.remove(&key) {
+            Some(Value::Array(mut prev)) => {
+                prev.extend(vals);
+                prev
+            }
+            Some(prev) => [vec![prev], vals].concat(),
+            None => vals,
+        };
+        params.insert(key, one_or_many(vals));
+    }
+
+    let mut jprop = vec![
+        json!(prop.name.to_lowercase()),
+        Value::Object(params),
+        json!(value_type),
+    ];
+    if STRUCTURED_PROPS.contains(&prop.name.as_str()) {
+        let components = vcard::split_raw(&prop.value, ';')
+            .iter()
+            .map(|component| {
+                let values = vcard::split_raw(component, ',');
+                one_or_many(values.iter().map(|val| text(val)).collect())
+            })
+            .collect();
+        jprop.push(Value::Array(components));
+    } else if MULTI_VALUED_PROPS.contains(&prop.name.as_str()) {
+        jprop.extend(
+            vcard::split_raw(&prop.value, ',')
+                .iter()
+                .map(|val| text(val)),
+        );
+    } else {
+        jprop.push(text(&prop.value));
+    }
+
+    Value::Array(jprop)
+}
+
+/// Converts a card to jCard. The card is converted to vCard 4.0 first, as
+/// required by the RFC.
+pub fn to_jcard(content: &str) -> Value {
+    let content = match vcard::version(content) {
+        Some(Version::V40) => content.to_owned(),
+        _ => convert::convert(content, Version::V40),
+    };
+    let props: Vec<Value> = vcard::parse(&content)
+        .iter()
+        .filter(|prop| prop.name != "BEGIN" && prop.name != "END")
+        .map(prop_to_jcard)
+        .collect();
+
+    json!(["vcard", props])
+}
+
+fn value_to_string(value: &Value, is_text: bool) -> String {
+    match value {
+        Value::String(val) if is_text => vcard::escape(val),
+        Value::String(val) => val.to_owned(),
+        Value::Null => String::new(),
+        Value::Array(vals) => vals
+            .iter()
+            .map(|val| value_to_string(val, is_text))
+            .collect::<Vec<_>>()
+            .join(","),
+        val => val.to_string(),
+    }
+}
+
+fn param_to_string(value: &Value) -> String {
+    let val = match value {
+        Value::Array(vals) => vals
+            .iter()
+            .map(param_to_string)
+            .collect::<Vec<_>>()
+            .join(","),
+        Value::String(val) => val.to_owned(),
+        val => val.to_string(),
+    };
+    if !val.starts_with('"') && val.contains(&[':', ';'][..]) {
+        format!("\"{}\"", val)
+    } else {
+        val
+    }
+}
+
+fn prop_from_jcard(jprop: &Value) -> Result<Property> {
+    let jprop = match jprop.as_array() {
+        Some(jprop) if jprop.len() >= 4 => jprop,
+        _ => bail!("Invalid jCard property {}", jprop),
+    };
+    let name = jprop[0]
+        .as_str()
+        .chain_err(|| "Invalid jCard property name")?
+        .to_uppercase();
+    let value_type = jprop[2].as_str().unwrap_or("unknown").to_lowercase();
+
+    let mut prop = Property::new(&name, "");
+    for (key, val) in jprop[1].as_object().into_iter().flatten() {
+        match key.as_str() {
+            "group" => prop.group = val.as_str().map(String::from),
+            key => prop.params.push((key.to_uppercase(), param_to_string(val))),
+        }
+    }
+    if value_type != default_type(&name) && value_type != "unknown" {
+        prop.params
+            .push((String::from("VALUE"), value_type.to_owned()));
+    }
+
+    let is_text = is_text(&value_type);
+    prop.value = if STRUCTURED_PROPS.contains(&name.as_str()) && jprop[3].is_array() {
+        jprop[3]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|component| value_to_string(component, is_text))
+            .collect::<Vec<_>>()
+            .join(";")
+    } else {
+        jprop[3..]
+            .iter()
+            .map(|val| value_to_string(val, is_text))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    if DATE_TIME_TYPES.contains(&value_type.as_str()) {
+        prop.value = prop
+            .value
+            .split(',')
+            .map(|value| map_date_time(&value_type, value, basic_date, basic_time))
+            .collect::<Vec<_>>()
+            .join(",");
+    }
+
+    Ok(prop)
+}
+
+/// Converts a jCard to a vCard 4.0.
+pub fn from_jcard(jcard: &Value) -> Result<String> {
+    let props = match jcard.as_array().map(Vec::as_slice) {
+        Some([Value::String(kind), Value::Array(props)]) if kind == "vcard" => props,
+        _ => bail!("Invalid jCard, expected [\"vcard\", [...]]"),
+    };
+
+    let mut props = props
+        .iter()
+        .map(prop_from_jcard)
+        .collect::<Result<Vec<_>>>()?;
+    if !props.iter().any(|prop| prop.name == "VERSION") {
+        props.insert(0, Property::new("VERSION", "4.0"));
+    }
+
+    Ok(vcard::build(&props))
+}
+
+/// Checks if a content looks like a jCard rather than a vCard.
+pub fn is_jcard(content: &str) -> bool {
+    content.trim_start().starts_with('[')
+}
+
+/// Parses one jCard or an array of jCards.
+pub fn parse(content: &str) -> Result<Vec<String>> {
+    let value: Value = serde_json::from_str(content)?;
+
+    match value.as_array().and_then(|vals| vals.first()) {
+        Some(Value::Array(_)) => value
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(from_jcard)
+            .collect(),
+        _ => Ok(vec![from_jcard(&value)?]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CARD: &str = "BEGIN:VCARD\r\n\
+        VERSION:4.0\r\n\
+        FN:John Doe\r\n\
+        N:Doe;John;;Dr.,Prof.;\r\n\
+        NICKNAME:Johnny,J\r\n\
+        NOTE:Line one\\nwith\\, commas\r\n\
+        item1.TEL;PREF=1;TYPE=work,voice:+33 1 23 45 67 89\r\n\
+        BDAY:19850412\r\n\
+        ANNIVERSARY:--0412T102200-0800\r\n\
+        X-WAKE-UP;VALUE=time:0630\r\n\
+        REV:20160101T100000Z\r\n\
+        END:VCARD\r\n";
+
+    #[test]
+    fn card_to_jcard() {
+        assert_eq!(
+            to_jcard(CARD),
+            json!(["vcard", [
+                ["version", {}, "text", "4.0"],
+                ["fn", {}, "text", "John Doe"],
+                ["n", {}, "text", ["Doe", "John", "", ["Dr.", "Prof."], ""]],
+                ["nickname", {}, "text", "Johnny", "J"],
+                ["note", {}, "text", "Line one\nwith, commas"],
+                ["tel", {"group": "item1", "type": ["work", "voice"], "pref": "1"}, "text", "+33 1 23 45 67 89"],
+                ["bday", {}, "date-and-or-time", "1985-04-12"],
+                ["anniversary", {}, "date-and-or-time", "--04-12T10:22:00-08:00"],
+                ["x-wake-up", {}, "time", "06:30"],
+                ["rev", {}, "timestamp", "2016-01-01T10:00:00Z"],
+            ]])
+        );
+    }
+
+    #[test]
+    fn jcard_round_trip() {
+        assert_eq!(from_jcard(&to_jcard(CARD)).unwrap(), CARD);
+    }
+
+    #[test]
+    fn dates_and_times() {
+        let extended = |t: &str, v: &str| map_date_time(t, v, extended_date, extended_time);
+        let basic = |t: &str, v: &str| map_date_time(t, v, basic_date, basic_time);
+        let values = [
+            ("date", "19850412", "1985-04-12"),
+            ("date", "1985-04", "1985-04"),
+            ("date", "1985", "1985"),
+            ("date", "--0412", "--04-12"),
+            ("date", "---12", "---12"),
+            ("time", "102200", "10:22:00"),
+            ("time", "1022", "10:22"),
+            ("time", "-2200", "-22:00"),
+            ("time", "--00", "--00"),
+            ("time", "102200Z", "10:22:00Z"),
+            ("time", "102200+0530", "10:22:00+05:30"),
+            ("date-time", "19961022T140000", "1996-10-22T14:00:00"),
+            ("date-time", "---22T14", "---22T14"),
+            ("date-and-or-time", "T102200", "T10:22:00"),
+            ("timestamp", "19961022T140000-05", "1996-10-22T14:00:00-05"),
+        ];
+
+        for (value_type, basic_value, extended_value) in values.iter() {
+            assert_eq!(&extended(value_type, basic_value), extended_value);
+            assert_eq!(&basic(value_type, extended_value), basic_value);
+        }
+    }
+
+    #[test]
+    fn parse_many() {
+        let content = format!("[{}, {}]", to_jcard(CARD), to_jcard(CARD));
+        assert_eq!(parse(&content).unwrap(), vec![CARD, CARD]);
+        assert!(parse("[\"vcard\"]").is_err());
+    }
+}
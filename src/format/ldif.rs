@@ -0,0 +1,373 @@
+//! LDIF import and export ([RFC 2849]), using the `inetOrgPerson` schema.
+//!
+//! [RFC 2849]: https://tools.ietf.org/html/rfc2849
+
+use error_chain::{bail, error_chain};
+use uuid::Uuid;
+
+use crate::{
+    contact::{self, Contact},
+    convert,
+    vcard::{self, Property, Version},
+};
+
+error_chain! {}
+
+/// Phone attributes, by vCard type.
+const PHONE_ATTRS: [(&str, &str); 4] = [
+    ("cell", "mobile"),
+    ("fax", "facsimileTelephoneNumber"),
+    ("home", "homePhone"),
+    ("pager", "pager"),
+];
+
+/// Attributes holding a single property value.
+const TEXT_ATTRS: [(&str, &str); 5] = [
+    ("title", "TITLE"),
+    ("description", "NOTE"),
+    ("labeledURI", "URL"),
+    ("displayName", "FN"),
+    ("uid", "UID"),
+];
+
+/// Address attributes, by `ADR` component index.
+const ADR_ATTRS: [(usize, &str); 5] = [
+    (2, "street"),
+    (3, "l"),
+    (4, "st"),
+    (5, "postalCode"),
+    (6, "c"),
+];
+
+/// Checks if a value can be written as is, or must be base64 encoded.
+fn is_safe(value: &str) -> bool {
+    value.is_ascii()
+        && !value.starts_with(&[' ', ':', '<'][..])
+        && !value.ends_with(' ')
+        && !value.contains(&['\r', '\n', '\0'][..])
+}
+
+/// Writes an attribute line, folded at 76 characters.
+fn attr_line(name: &str, value: &str) -> String {
+    let line = if is_safe(value) {
+        format!("{}: {}", name, value)
+    } else {
+        format!("{}:: {}", name, base64::encode(value))
+    };
+
+    let chars: Vec<_> = line.chars().collect();
+    chars
+        .chunks(75)
+        .map(|chunk| chunk.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join("\n ")
+}
+
+/// Escapes a RDN value as described in RFC 4514.
+fn escape_dn(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => format!("\\{}", c),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+fn to_entry(content: &str) -> String {
+    let content = match vcard::version(content) {
+        Some(Version::V21) => convert::convert(content, Version::V30),
+        _ => content.to_owned(),
+    };
+    let props = vcard::parse(&content);
+    let contact = Contact::from_vcard(&content);
+    let find = |name: &str| props.iter().find(|prop| prop.name == name);
+
+    let mut dn = format!("cn={}", escape_dn(&contact.name));
+    if let Some(email) = contact.emails.first() {
+        dn.push_str(&format!(",mail={}", escape_dn(email)));
+    }
+
+    let mut attrs = vec![(String::from("dn"), dn)];
+    for class in ["top", "person", "organizationalPerson", "inetOrgPerson"].iter() {
+        attrs.push((String::from("objectClass"), class.to_string()));
+    }
+    attrs.push((String::from("cn"), contact.name.to_owned()));
+
+    let n = find("N").map(Property::components).unwrap_or_default();
+    let sn = n
+        .first()
+        .filter(|sn| !sn.is_empty())
+        .unwrap_or(&contact.name);
+    attrs.push((String::from("sn"), sn.to_owned()));
+    if let Some(given) = n.get(1).filter(|given| !given.is_empty()) {
+        attrs.push((String::from("givenName"), given.to_owned()));
+    }
+
+    for email in contact.emails.iter() {
+        attrs.push((String::from("mail"), email.to_owned()));
+    }
+    for prop in props.iter().filter(|prop| prop.name == "TEL") {
+        let types = prop.types();
+        let attr = PHONE_ATTRS
+            .iter()
+            .find(|(t, _)| types.iter().any(|kind| kind == t))
+            .map(|(_, attr)| *attr)
+            .unwrap_or("telephoneNumber");
+        attrs.push((attr.to_owned(), prop.text()));
+    }
+    if let Some(org) = find("ORG") {
+        let mut components = org.components().into_iter();
+        if let Some(o) = components.next().filter(|o| !o.is_empty()) {
+            attrs.push((String::from("o"), o));
+        }
+        if let Some(ou) = components.next().filter(|ou| !ou.is_empty()) {
+            attrs.push((String::from("ou"), ou));
+        }
+    }
+    for (attr, name) in TEXT_ATTRS.iter() {
+        if let Some(prop) = find(name) {
+            attrs.push((attr.to_string(), prop.text()));
+        }
+    }
+    if let Some(adr) = find("ADR") {
+        let components = adr.components();
+        for (i, attr) in ADR_ATTRS.iter() {
+            if let Some(val) = components.get(*i).filter(|val| !val.is_empty()) {
+                attrs.push((attr.to_string(), val.to_owned()));
+            }
+        }
+    }
+
+    attrs
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(name, value)| attr_line(&name, &value) + "\n")
+        .collect()
+}
+
+/// Exports the cards as LDIF, one entry per card.
+pub fn export(cards: &[&str]) -> String {
+    let entries: Vec<_> = cards.iter().map(|card| to_entry(card)).collect();
+    format!("version: 1\n\n{}", entries.join("\n"))
+}
+
+/// Parses the entries of a LDIF content into lists of attributes.
+fn parse_entries(content: &str) -> Result<Vec<Vec<(String, String)>>> {
+    let mut entries = vec![];
+    let mut entry: Vec<(String, String)> = vec![];
+    let mut lines: Vec<String> = vec![];
+
+    // Unfolds lines first, then splits entries on empty lines.
+    for line in content.lines() {
+        let line = line.trim_end_matches('\r');
+        match (line.strip_prefix(' '), lines.last_mut()) {
+            (Some(next), Some(last)) => last.push_str(next),
+            _ => lines.push(line.to_owned()),
+        }
+    }
+
+    for line in lines.iter().chain(std::iter::once(&String::new())) {
+        if line.is_empty() {
+            if !entry.is_empty() {
+                entries.push(std::mem::take(&mut entry));
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let i = match line.find(':') {
+            Some(i) => i,
+            None => bail!("Invalid LDIF line {:?}", line),
+        };
+        let name = line[..i].to_owned();
+        let value = &line[i + 1..];
+        let value = if let Some(value) = value.strip_prefix(':') {
+            let value = base64::decode(value.trim())
+                .chain_err(|| format!("Invalid base64 value for {}", name))?;
+            String::from_utf8(value).chain_err(|| format!("Invalid utf8 value for {}", name))?
+        } else {
+            value.trim_start().to_owned()
+        };
+
+        if name.eq_ignore_ascii_case("version") && entry.is_empty() {
+            continue;
+        }
+        entry.push((name, value));
+    }
+
+    Ok(entries)
+}
+
+/// Imports LDIF entries as vCard 3.0 cards. Entries without name are
+/// skipped, as well as change records.
+pub fn import(content: &str) -> Result<Vec<String>> {
+    let mut cards = vec![];
+
+    for entry in parse_entries(content)? {
+        let attr = |name: &str| -> Vec<&str> {
+            entry
+                .iter()
+                .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+                .collect()
+        };
+        if !attr("changetype").is_empty() {
+            continue;
+        }
+
+        let first = |name: &str| attr(name).first().map(|value| value.to_string());
+        let name = first("displayName")
+            .or_else(|| first("cn"))
+            .unwrap_or_default();
+        let mut props = vec![Property::new("VERSION", "3.0")];
+        let n = match (first("sn"), first("givenName")) {
+            (None, None) => contact::structured_name(&name),
+            (sn, given) => format!(
+                "{};{};;;",
+                vcard::escape(&sn.unwrap_or_default()),
+                vcard::escape(&given.unwrap_or_default())
+            ),
+        };
+        let name = if name.is_empty() {
+            Contact::from_vcard(&vcard::build(&[Property::new("N", &n)])).name
+        } else {
+            name
+        };
+        if name.is_empty() {
+            continue;
+        }
+        props.push(Property::new("FN", &vcard::escape(&name)));
+        props.push(Property::new("N", &n));
+
+        for email in attr("mail") {
+            props.push(Property::new("EMAIL", &vcard::escape(email)));
+        }
+        for phone in attr("telephoneNumber") {
+            props.push(Property::new("TEL", &vcard::escape(phone)));
+        }
+        for (kind, attr_name) in PHONE_ATTRS.iter() {
+            for phone in attr(attr_name) {
+                let mut prop = Property::new("TEL", &vcard::escape(phone));
+                prop.params.push((String::from("TYPE"), kind.to_string()));
+                props.push(prop);
+            }
+        }
+        let org: Vec<_> = attr("o")
+            .into_iter()
+            .take(1)
+            .chain(attr("ou").into_iter().take(1))
+            .map(vcard::escape)
+            .collect();
+        if !org.is_empty() {
+            props.push(Property::new("ORG", &org.join(";")));
+        }
+        for (attr_name, name) in TEXT_ATTRS.iter().filter(|(_, name)| *name != "FN") {
+            if let Some(value) = first(attr_name) {
+                let value = if *name == "URL" {
+                    value
+                } else {
+                    vcard::escape(&value)
+                };
+                props.push(Property::new(name, &value));
+            }
+        }
+        let mut adr = vec![String::new(); 7];
+        for (i, attr_name) in ADR_ATTRS.iter() {
+            if let Some(value) = attr(attr_name).first() {
+                adr[*i] = vcard::escape(value);
+            }
+        }
+        if adr.iter().any(|component| !component.is_empty()) {
+            props.push(Property::new("ADR", &adr.join(";")));
+        }
+        if !props.iter().any(|prop| prop.name == "UID") {
+            props.push(Property::new("UID", &Uuid::new_v4().to_string()));
+        }
+
+        cards.push(vcard::build(&props));
+    }
+
+    Ok(cards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CARD: &str = "BEGIN:VCARD\r\n\
+        VERSION:3.0\r\n\
+        UID:jdoe\r\n\
+        FN:John Doe\r\n\
+        N:Doe;John;;;\r\n\
+        EMAIL:john@doe.com\r\n\
+        TEL;TYPE=cell:+33 6 12 34 56 78\r\n\
+        ORG:Doe Inc.;R&D\r\n\
+        NOTE:Met in Zürich\r\n\
+        END:VCARD\r\n";
+
+    #[test]
+    fn export_card() {
+        assert_eq!(
+            export(&[CARD]),
+            "version: 1\n\n\
+            dn: cn=John Doe,mail=john@doe.com\n\
+            objectClass: top\n\
+            objectClass: person\n\
+            objectClass: organizationalPerson\n\
+            objectClass: inetOrgPerson\n\
+            cn: John Doe\n\
+            sn: Doe\n\
+            givenName: John\n\
+            mail: john@doe.com\n\
+            mobile: +33 6 12 34 56 78\n\
+            o: Doe Inc.\n\
+            ou: R&D\n\
+            description:: TWV0IGluIFrDvHJpY2g=\n\
+            displayName: John Doe\n\
+            uid: jdoe\n"
+        );
+    }
+
+    #[test]
+    fn import_exported_card() {
+        let cards = import(&export(&[CARD])).unwrap();
+
+        assert_eq!(
+            cards,
+            vec![String::from(
+                "BEGIN:VCARD\r\n\
+                VERSION:3.0\r\n\
+                FN:John Doe\r\n\
+                N:Doe;John;;;\r\n\
+                EMAIL:john@doe.com\r\n\
+                TEL;TYPE=cell:+33 6 12 34 56 78\r\n\
+                ORG:Doe Inc.;R&D\r\n\
+                NOTE:Met in Zürich\r\n\
+                UID:jdoe\r\n\
+                END:VCARD\r\n"
+            )]
+        );
+    }
+
+    #[test]
+    fn import_display_name() {
+        let content = "dn: uid=jdoe\nuid: jdoe\ncn: Doe John\ndisplayName: John\n  Doe\n";
+        let cards = import(content).unwrap();
+
+        assert_eq!(cards.len(), 1);
+        assert_eq!(vcard::prop(&cards[0], "FN").as_deref(), Some("John Doe"));
+        assert_eq!(vcard::prop(&cards[0], "NICKNAME"), None);
+    }
+
+    #[test]
+    fn import_skips_change_records() {
+        let content = "dn: cn=John Doe\nchangetype: delete\n\ndn: cn=Jane\ncn: Jane\n";
+        let cards = import(content).unwrap();
+
+        assert_eq!(cards.len(), 1);
+        assert_eq!(vcard::prop(&cards[0], "FN").as_deref(), Some("Jane"));
+    }
+}
@@ -0,0 +1,307 @@
+use glob::Pattern;
+use log::warn;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    model::Card,
+    repository::{self, LocalRepository, Result, ResultExt},
+};
+use crate::config::Config;
+
+/// Filters which files of the sync dir are considered as cards.
+struct Discovery {
+    recursive: bool,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl Discovery {
+    fn from_config(config: &Config) -> Result<Self> {
+        let mut exclude = config.exclude();
+        exclude.extend(read_ignore_file(config)?);
+
+        Ok(Self {
+            recursive: config.recursive(),
+            include: parse_patterns(&config.include())?,
+            exclude: parse_patterns(&exclude)?,
+        })
+    }
+
+    fn matches(&self, rel_path: &Path) -> bool {
+        self.include.iter().any(|p| p.matches_path(rel_path)) && !self.is_excluded(rel_path)
+    }
+
+    fn is_excluded(&self, rel_path: &Path) -> bool {
+        self.exclude.iter().any(|p| p.matches_path(rel_path))
+    }
+}
+
+fn parse_patterns(patterns: &[String]) -> Result<Vec<Pattern>> {
+    patterns
+        .iter()
+        .map(|p| Pattern::new(p).chain_err(|| format!("Invalid glob pattern {:?}", p)))
+        .collect()
+}
+
+/// Reads glob patterns from the `.cardamomignore` file of the sync dir, one
+/// per line. Empty lines and lines starting with `#` are skipped.
+fn read_ignore_file(config: &Config) -> Result<Vec<String>> {
+    let path = config.file_path(".cardamomignore");
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let content = fs::read_to_string(&path).chain_err(|| "Could not read ignore file")?;
+    Ok(content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Hidden files as well as temporary files left by editors (`file~`,
+/// `#file#`, `file.swp`…) are never considered as cards.
+fn is_ignored_file_name(name: &str) -> bool {
+    name.starts_with('.')
+        || name.ends_with('~')
+        || (name.starts_with('#') && name.ends_with('#'))
+        || name.ends_with(".swp")
+        || name.ends_with(".swo")
+        || name.ends_with(".tmp")
+}
+
+/// Collects the paths of the files matching the discovery options.
+/// Excluded directories are skipped, and symlinks to directories are not
+/// followed, so that they cannot loop.
+fn list_files(
+    discovery: &Discovery,
+    root: &Path,
+    dir: &Path,
+    paths: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let entries = fs::read_dir(dir).chain_err(|| format!("Could not read dir {:?}", dir))?;
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!("Could not read entry of {:?}: {}", dir, err);
+                continue;
+            }
+        };
+
+        if is_ignored_file_name(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+
+        let path = entry.path();
+        let rel_path = path.strip_prefix(root).unwrap_or(&path);
+
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(err) => {
+                warn!("Could not read type of {:?}: {}", path, err);
+                continue;
+            }
+        };
+        if file_type.is_symlink() && path.is_dir() {
+            continue;
+        }
+
+        if file_type.is_dir() {
+            if discovery.recursive && !discovery.is_excluded(rel_path) {
+                if let Err(err) = list_files(discovery, root, &path, paths) {
+                    warn!("{}", err);
+                }
+            }
+            continue;
+        }
+
+        if discovery.matches(rel_path) {
+            paths.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Directory of `.vcf` files, each one containing one or many cards.
+pub struct DirRepository {
+    sync_dir: PathBuf,
+    discovery: Discovery,
+}
+
+impl DirRepository {
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            sync_dir: config.sync_dir.to_owned(),
+            discovery: Discovery::from_config(config)?,
+        })
+    }
+
+    /// Builds a repository from a plain directory of `.vcf` files, without
+    /// any discovery option.
+    pub fn from_path(path: PathBuf) -> Self {
+        Self {
+            sync_dir: path,
+            discovery: Discovery {
+                recursive: false,
+                include: vec![Pattern::new("*.vcf").unwrap()],
+                exclude: vec![],
+            },
+        }
+    }
+}
+
+impl LocalRepository for DirRepository {
+    fn list(&self) -> Result<HashMap<String, Card>> {
+        let mut cards = HashMap::new();
+
+        for path in self.files()? {
+            match repository::read_file(&path) {
+                Ok(new_cards) => repository::insert_cards(&mut cards, new_cards),
+                Err(err) => warn!("Skipping {:?}: {}", path, err),
+            }
+        }
+
+        Ok(cards)
+    }
+
+    /// Existing cards are updated in place, even if they share their file with
+    /// other cards. New cards get their own file.
+    fn write(&mut self, cards: &HashMap<String, String>) -> Result<()> {
+        let prev_cards = self.list()?;
+        let mut files: HashMap<PathBuf, HashMap<&str, Option<&str>>> = HashMap::new();
+
+        for (name, content) in cards {
+            let path = match prev_cards.get(name) {
+                Some(card) => card.path.to_owned(),
+                None => self.sync_dir.join(repository::file_name(name)),
+            };
+
+            files
+                .entry(path)
+                .or_default()
+                .insert(name, Some(content.trim_end_matches('\r')));
+        }
+
+        for (path, cards) in files {
+            repository::update_file(&path, cards)?;
+        }
+
+        Ok(())
+    }
+
+    fn delete(&mut self, names: &[String]) -> Result<()> {
+        let prev_cards = self.list()?;
+        let mut files: HashMap<PathBuf, HashMap<&str, Option<&str>>> = HashMap::new();
+
+        for name in names {
+            if let Some(card) = prev_cards.get(name) {
+                files
+                    .entry(card.path.to_owned())
+                    .or_default()
+                    .insert(name, None);
+            }
+        }
+
+        for (path, cards) in files {
+            repository::update_file(&path, cards)?;
+        }
+
+        Ok(())
+    }
+
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = vec![];
+        list_files(&self.discovery, &self.sync_dir, &self.sync_dir, &mut paths)
+            .chain_err(|| "Could not read cards from sync dir")?;
+        paths.sort();
+
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn files(dir: &TempDir, keys: &str) -> Vec<String> {
+        let config: Config =
+            toml::from_str(&format!("sync-dir = {:?}\n{}", dir.path(), keys)).unwrap();
+        let mut paths: Vec<_> = DirRepository::new(&config)
+            .unwrap()
+            .files()
+            .unwrap()
+            .iter()
+            .map(|path| {
+                let path = path.strip_prefix(dir.path()).unwrap();
+                path.to_string_lossy().into_owned()
+            })
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    fn touch(dir: &TempDir, path: &str) {
+        let path = dir.path().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "").unwrap();
+    }
+
+    #[test]
+    fn discovery() {
+        let dir = TempDir::new().unwrap();
+        for path in [
+            "a.vcf",
+            "b.txt",
+            ".hidden.vcf",
+            "a.vcf~",
+            "sub/c.vcf",
+            "sub/deep/d.vcf",
+            "old/e.vcf",
+        ] {
+            touch(&dir, path);
+        }
+
+        assert_eq!(files(&dir, ""), vec!["a.vcf"]);
+        assert_eq!(
+            files(&dir, "recursive = true"),
+            vec!["a.vcf", "old/e.vcf", "sub/c.vcf", "sub/deep/d.vcf"]
+        );
+        assert_eq!(
+            files(
+                &dir,
+                "recursive = true\ninclude = [\"*.vcf\", \"*.txt\"]\nexclude = [\"sub/deep\"]"
+            ),
+            vec!["a.vcf", "b.txt", "old/e.vcf", "sub/c.vcf"]
+        );
+
+        fs::write(
+            dir.path().join(".cardamomignore"),
+            "# Archives\n\nold\nsub/c.vcf\n",
+        )
+        .unwrap();
+        assert_eq!(
+            files(&dir, "recursive = true"),
+            vec!["a.vcf", "sub/deep/d.vcf"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loop() {
+        let dir = TempDir::new().unwrap();
+        touch(&dir, "a/b.vcf");
+        std::os::unix::fs::symlink("..", dir.path().join("a/loop")).unwrap();
+
+        assert_eq!(files(&dir, "recursive = true"), vec!["a/b.vcf"]);
+    }
+}
@@ -0,0 +1,117 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use super::{
+    model::Card,
+    repository::{self, LocalRepository, Result},
+};
+use crate::config::Config;
+
+/// Single `.vcf` file containing all the cards.
+pub struct FileRepository {
+    path: PathBuf,
+}
+
+impl FileRepository {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            path: config.sync_file(),
+        }
+    }
+}
+
+impl LocalRepository for FileRepository {
+    fn list(&self) -> Result<HashMap<String, Card>> {
+        let mut cards = HashMap::new();
+        if self.path.exists() {
+            repository::insert_cards(&mut cards, repository::read_file(&self.path)?);
+        }
+
+        Ok(cards)
+    }
+
+    fn write(&mut self, cards: &HashMap<String, String>) -> Result<()> {
+        let cards = cards
+            .iter()
+            .map(|(name, content)| (name.as_str(), Some(content.trim_end_matches('\r'))))
+            .collect();
+
+        repository::update_file(&self.path, cards)
+    }
+
+    fn delete(&mut self, names: &[String]) -> Result<()> {
+        let cards = names.iter().map(|name| (name.as_str(), None)).collect();
+        repository::update_file(&self.path, cards)
+    }
+
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        Ok(if self.path.exists() {
+            vec![self.path.to_owned()]
+        } else {
+            vec![]
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const ALICE: &str = "BEGIN:VCARD\r\nVERSION:3.0\r\nUID:a\r\nFN:Alice\r\nEND:VCARD\r\n";
+    const BOB: &str = "BEGIN:VCARD\nVERSION:3.0\nUID:b\nFN:Bob\nEND:VCARD\n";
+    const CAROL: &str = "BEGIN:VCARD\r\nVERSION:4.0\r\nUID:c\r\nFN:Carol\r\nEND:VCARD\r\n";
+
+    fn repository(dir: &TempDir) -> FileRepository {
+        let config: Config =
+            toml::from_str(&format!("sync-dir = {:?}\nlayout = \"file\"", dir.path())).unwrap();
+        fs::write(config.sync_file(), format!("{}{}{}", ALICE, BOB, CAROL)).unwrap();
+        FileRepository::new(&config)
+    }
+
+    fn cards(cards: &[(&str, &str)]) -> HashMap<String, String> {
+        cards
+            .iter()
+            .map(|(name, content)| (name.to_string(), content.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn write_into_shared_file() {
+        let dir = TempDir::new().unwrap();
+        let mut repo = repository(&dir);
+        let bob = BOB.replace("FN:Bob", "FN:Bob B");
+        let dave = "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Dave\r\nEND:VCARD\r\n";
+
+        repo.write(&cards(&[("b", &bob), ("d", dave)])).unwrap();
+
+        let content = fs::read_to_string(&repo.path).unwrap();
+        assert_eq!(
+            content,
+            format!(
+                "{}{}{}BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Dave\r\nUID:d\r\nEND:VCARD\r\n",
+                ALICE, bob, CAROL
+            )
+        );
+        let mut names: Vec<_> = repo.list().unwrap().into_keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn delete_from_shared_file() {
+        let dir = TempDir::new().unwrap();
+        let mut repo = repository(&dir);
+
+        repo.delete(&[String::from("b")]).unwrap();
+        assert_eq!(
+            fs::read_to_string(&repo.path).unwrap(),
+            format!("{}{}", ALICE, CAROL)
+        );
+
+        repo.delete(&[String::from("a"), String::from("c")])
+            .unwrap();
+        assert!(!repo.path.exists());
+    }
+}
@@ -0,0 +1,63 @@
+use chrono::Utc;
+use std::{collections::HashMap, path::PathBuf};
+
+use super::{
+    model::Card,
+    repository::{LocalRepository, Result},
+};
+
+/// Cards kept in memory only, mostly useful for testing.
+#[derive(Debug, Default)]
+pub struct MemoryRepository {
+    cards: HashMap<String, Card>,
+}
+
+impl MemoryRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl LocalRepository for MemoryRepository {
+    fn list(&self) -> Result<HashMap<String, Card>> {
+        Ok(self
+            .cards
+            .values()
+            .map(|card| {
+                let card = Card {
+                    name: card.name.to_owned(),
+                    path: card.path.to_owned(),
+                    date: card.date,
+                    content: card.content.to_owned(),
+                };
+                (card.name.to_owned(), card)
+            })
+            .collect())
+    }
+
+    fn write(&mut self, cards: &HashMap<String, String>) -> Result<()> {
+        for (name, content) in cards {
+            let card = Card {
+                name: name.to_owned(),
+                path: PathBuf::from(name),
+                date: Utc::now(),
+                content: content.to_owned(),
+            };
+            self.cards.insert(name.to_owned(), card);
+        }
+
+        Ok(())
+    }
+
+    fn delete(&mut self, names: &[String]) -> Result<()> {
+        for name in names {
+            self.cards.remove(name);
+        }
+
+        Ok(())
+    }
+
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        Ok(vec![])
+    }
+}
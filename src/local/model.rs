@@ -1,7 +1,10 @@
 use chrono::{DateTime, Utc};
+use std::path::PathBuf;
 
 #[derive(Debug)]
 pub struct Card {
     pub name: String,
+    pub path: PathBuf,
     pub date: DateTime<Utc>,
+    pub content: String,
 }
@@ -1,42 +1,279 @@
+use chrono::{DateTime, Utc};
 use error_chain::error_chain;
-use std::{collections::HashMap, fs, path::PathBuf};
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
 
-use super::model::Card;
-use crate::config::Config;
+use super::{dir::DirRepository, file::FileRepository, model::Card, vdir::VdirRepository};
+use crate::{
+    cache::CacheItem,
+    config::{Config, Layout},
+    remote::model::Metadata,
+    vcard,
+};
 
 error_chain! {}
 
-pub fn read_cards(config: &Config) -> Result<HashMap<String, Card>> {
-    Ok(fs::read_dir(config.sync_dir.to_owned())
-        .chain_err(|| "Could not read cards from sync dir")?
-        .filter_map(|entry| match entry {
-            Err(_) => None,
-            Ok(entry) => {
-                let is_entry_vcf = entry
-                    .path()
-                    .extension()
-                    .map(|ext| ext == "vcf")
-                    .unwrap_or(false);
-
-                if is_entry_vcf {
-                    Some(entry)
-                } else {
-                    None
-                }
-            }
+/// Storage of the local cards.
+pub trait LocalRepository {
+    /// Lists all the cards, indexed by name.
+    fn list(&self) -> Result<HashMap<String, Card>>;
+
+    fn read(&self, name: &str) -> Result<Option<Card>> {
+        Ok(self.list()?.remove(name))
+    }
+
+    /// Writes the given cards contents, indexed by name. Existing cards are
+    /// replaced, others are created.
+    fn write(&mut self, cards: &HashMap<String, String>) -> Result<()>;
+
+    fn delete(&mut self, names: &[String]) -> Result<()>;
+
+    /// Lists the files that may contain cards, including the ones that could
+    /// not be parsed.
+    fn files(&self) -> Result<Vec<PathBuf>>;
+
+    /// Stores the metadata of the remote collection, for backends that
+    /// support it.
+    fn write_metadata(&mut self, _metadata: &Metadata) -> Result<()> {
+        Ok(())
+    }
+
+    /// Checks if a card changed since it has been cached. The modification
+    /// date is used as a fast pre-check, the content hash is only computed
+    /// when it differs.
+    fn is_changed(&self, card: &Card, cached: &CacheItem) -> bool {
+        if card.date == cached.local_date {
+            return false;
+        }
+
+        if cached.local_hash.is_empty() {
+            return true;
+        }
+
+        hash_card(card) != cached.local_hash
+    }
+}
+
+/// Builds the local repository matching the configured layout.
+pub fn from_config(config: &Config) -> Result<Box<dyn LocalRepository>> {
+    Ok(match config.layout() {
+        Layout::Dir => Box::new(DirRepository::new(config)?),
+        Layout::File => Box::new(FileRepository::new(config)),
+        Layout::Vdir => Box::new(VdirRepository::new(config)),
+    })
+}
+
+/// Names the cards of a file by their UID. Cards without UID are named after
+/// the file stem, suffixed by their index if the file contains many cards.
+pub fn card_names(path: &Path, blocks: &[String]) -> Vec<String> {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| match vcard::uid(block) {
+            Some(uid) => uid,
+            None if blocks.len() == 1 => stem.to_owned(),
+            None => format!("{}-{}", stem, i + 1),
+        })
+        .collect()
+}
+
+/// Reads a `.vcf` file and splits it into cards.
+pub(super) fn read_file(path: &Path) -> Result<Vec<Card>> {
+    let date: DateTime<Utc> = fs::metadata(path)
+        .and_then(|m| m.modified())
+        .chain_err(|| format!("Could not read modification date of {:?}", path))?
+        .into();
+    let content = fs::read_to_string(path).chain_err(|| format!("Could not read {:?}", path))?;
+    let blocks = vcard::split(&content);
+    if blocks.is_empty() {
+        warn!("No card found in {:?}", path);
+    }
+
+    let names = card_names(path, &blocks);
+    Ok(blocks
+        .into_iter()
+        .zip(names)
+        .map(|(content, name)| Card {
+            name,
+            path: path.to_owned(),
+            date,
+            content,
         })
-        .fold(HashMap::new(), |mut cards, entry| {
-            match PathBuf::from(entry.path()).file_stem() {
-                None => cards,
-                Some(name) => {
-                    let card = Card {
-                        name: name.to_string_lossy().to_string(),
-                        date: entry.metadata().unwrap().modified().unwrap().into(),
-                    };
-
-                    cards.insert(name.to_string_lossy().to_string(), card);
-                    cards
-                }
+        .collect())
+}
+
+pub(super) fn insert_cards(cards: &mut HashMap<String, Card>, new_cards: Vec<Card>) {
+    for card in new_cards {
+        match cards.get(&card.name) {
+            Some(prev) => warn!(
+                "Skipping card {} from {:?}: already found in {:?}",
+                card.name, card.path, prev.path
+            ),
+            None => {
+                cards.insert(card.name.to_owned(), card);
             }
-        }))
+        }
+    }
+}
+
+/// Builds a file name from a card name, replacing characters that are not
+/// safe in paths.
+pub fn file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .enumerate()
+        .map(|(i, c)| match c {
+            '.' if i == 0 => '_',
+            c if c.is_alphanumeric() || "-_.@+".contains(c) => c,
+            _ => '_',
+        })
+        .collect();
+
+    format!("{}.vcf", name)
+}
+
+/// Replaces the cards of a file with the given ones (matched by name),
+/// removes the ones set to `None` and appends the remaining ones. The file is
+/// removed when no card is left.
+pub(super) fn update_file(path: &Path, mut cards: HashMap<&str, Option<&str>>) -> Result<()> {
+    let blocks = if path.exists() {
+        let content =
+            fs::read_to_string(path).chain_err(|| format!("Could not read {:?}", path))?;
+        vcard::split(&content)
+    } else {
+        vec![]
+    };
+    let names = card_names(path, &blocks);
+
+    let mut blocks: Vec<String> = blocks
+        .into_iter()
+        .zip(names)
+        .filter_map(|(block, name)| match cards.remove(name.as_str()) {
+            Some(Some(content)) => Some(content.to_owned()),
+            Some(None) => None,
+            None => Some(block),
+        })
+        .collect();
+    let mut cards: Vec<_> = cards
+        .into_iter()
+        .filter_map(|(name, content)| Some((name, content?)))
+        .collect();
+    cards.sort();
+    let is_shared = blocks.len() + cards.len() > 1;
+    blocks.extend(cards.into_iter().map(|(name, content)| {
+        // Cards sharing a file can only be identified by their UID.
+        if is_shared && vcard::uid(content).is_none() {
+            vcard::with_uid(content, name)
+        } else {
+            content.to_owned()
+        }
+    }));
+
+    let content = match blocks.len() {
+        0 => return fs::remove_file(path).chain_err(|| format!("Could not remove {:?}", path)),
+        1 => blocks.remove(0),
+        // Blocks keep their own line endings, so that the untouched cards
+        // stay as they were.
+        _ => blocks
+            .iter()
+            .map(|block| match block.ends_with('\n') {
+                true => block.to_owned(),
+                false => format!("{}\r\n", block),
+            })
+            .collect(),
+    };
+
+    fs::write(path, content).chain_err(|| format!("Could not write {:?}", path))
+}
+
+/// Normalizes the card content so that changes in line endings or trailing
+/// whitespaces do not count as modifications.
+fn normalize(content: &str) -> String {
+    content
+        .lines()
+        .map(|line| line.trim_end())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn hash_content(content: &str) -> String {
+    format!("{:x}", Sha256::digest(normalize(content).as_bytes()))
+}
+
+pub fn hash_card(card: &Card) -> String {
+    hash_content(&card.content)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        time::{Duration, SystemTime},
+    };
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const CARD: &str = "BEGIN:VCARD\r\nVERSION:3.0\r\nUID:a\r\nFN:Alice\r\nEND:VCARD\r\n";
+
+    /// Writes the content of the card, with a modification date moved
+    /// forward, then reads it back.
+    fn write(repo: &dyn LocalRepository, path: &Path, content: &str, secs: u64) -> Card {
+        fs::write(path, content).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(secs))
+            .unwrap();
+        repo.read("a").unwrap().unwrap()
+    }
+
+    #[test]
+    fn changes() {
+        let dir = TempDir::new().unwrap();
+        let config: Config = toml::from_str(&format!("sync-dir = {:?}", dir.path())).unwrap();
+        let repo = from_config(&config).unwrap();
+        let path = dir.path().join("a.vcf");
+        let card = write(repo.as_ref(), &path, CARD, 0);
+        let cached = CacheItem {
+            name: String::from("a"),
+            etag: String::new(),
+            local_date: card.date,
+            remote_date: card.date,
+            local_hash: hash_card(&card),
+        };
+        assert!(!repo.is_changed(&card, &cached));
+
+        let card = write(repo.as_ref(), &path, CARD, 10);
+        assert_ne!(card.date, cached.local_date);
+        assert!(!repo.is_changed(&card, &cached));
+
+        let content = CARD
+            .replace("\r\n", "  \n")
+            .replace("UID:a  \n", "UID:a\n\n");
+        let card = write(repo.as_ref(), &path, &content, 20);
+        assert!(!repo.is_changed(&card, &cached));
+
+        let card = write(repo.as_ref(), &path, &CARD.replace("Alice", "Alice B"), 30);
+        assert!(repo.is_changed(&card, &cached));
+
+        // Without hash, as in old caches, any date change is a change.
+        let cached = CacheItem {
+            local_hash: String::new(),
+            ..cached
+        };
+        assert!(repo.is_changed(&card, &cached));
+    }
 }
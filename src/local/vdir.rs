@@ -0,0 +1,154 @@
+use log::warn;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    model::Card,
+    repository::{self, LocalRepository, Result, ResultExt},
+};
+use crate::{config::Config, remote::model::Metadata};
+
+/// Writes a file atomically, through a hidden temporary file renamed over
+/// the target, as required by the vdir spec.
+fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name));
+
+    fs::write(&tmp_path, content).chain_err(|| format!("Could not write {:?}", tmp_path))?;
+    fs::rename(&tmp_path, path).chain_err(|| format!("Could not move {:?}", tmp_path))
+}
+
+/// Strips the alpha of a `#RRGGBBAA` color, since vdir colors are
+/// `#RRGGBB`. Other colors are kept as they are.
+fn rgb_color(color: &str) -> &str {
+    let is_rgba = color.len() == 9
+        && color.starts_with('#')
+        && color[1..].chars().all(|c| c.is_ascii_hexdigit());
+
+    match color.get(..7) {
+        Some(rgb) if is_rgba => rgb,
+        _ => color,
+    }
+}
+
+/// Collection following the [vdir] spec, readable by tools like khard: one
+/// `.vcf` file per card and `displayname`/`color` metadata files.
+///
+/// [vdir]: https://vdirsyncer.pimutils.org/en/stable/vdir.html
+pub struct VdirRepository {
+    path: PathBuf,
+}
+
+impl VdirRepository {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            path: config.sync_dir.to_owned(),
+        }
+    }
+}
+
+impl LocalRepository for VdirRepository {
+    fn list(&self) -> Result<HashMap<String, Card>> {
+        let mut cards = HashMap::new();
+
+        for path in self.files()? {
+            match repository::read_file(&path) {
+                Ok(mut file_cards) => {
+                    if file_cards.len() > 1 {
+                        warn!("Only the first card of {:?} is used", path);
+                        file_cards.truncate(1);
+                    }
+                    repository::insert_cards(&mut cards, file_cards);
+                }
+                Err(err) => warn!("Skipping {:?}: {}", path, err),
+            }
+        }
+
+        Ok(cards)
+    }
+
+    fn write(&mut self, cards: &HashMap<String, String>) -> Result<()> {
+        let prev_cards = self.list()?;
+
+        for (name, content) in cards {
+            let path = match prev_cards.get(name) {
+                Some(card) => card.path.to_owned(),
+                None => self.path.join(repository::file_name(name)),
+            };
+            write_atomic(&path, content.trim_end_matches('\r'))?;
+        }
+
+        Ok(())
+    }
+
+    fn delete(&mut self, names: &[String]) -> Result<()> {
+        let prev_cards = self.list()?;
+
+        for card in names.iter().filter_map(|name| prev_cards.get(name)) {
+            fs::remove_file(&card.path)
+                .chain_err(|| format!("Could not remove {:?}", card.path))?;
+        }
+
+        Ok(())
+    }
+
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        let entries = fs::read_dir(&self.path)
+            .chain_err(|| format!("Could not read cards from {:?}", self.path))?;
+        let mut paths = vec![];
+
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(err) => {
+                    warn!("Could not read entry of {:?}: {}", self.path, err);
+                    continue;
+                }
+            };
+
+            let is_hidden = path
+                .file_name()
+                .map(|name| name.to_string_lossy().starts_with('.'))
+                .unwrap_or(true);
+            let is_vcf = path.extension().map(|ext| ext == "vcf").unwrap_or(false);
+            if !is_hidden && is_vcf && path.is_file() {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    fn write_metadata(&mut self, metadata: &Metadata) -> Result<()> {
+        if let Some(ref displayname) = metadata.displayname {
+            write_atomic(&self.path.join("displayname"), displayname)?;
+        }
+
+        if let Some(ref color) = metadata.color {
+            write_atomic(&self.path.join("color"), rgb_color(color))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rgb_color_strips_alpha() {
+        assert_eq!(rgb_color("#FF0000FF"), "#FF0000");
+        assert_eq!(rgb_color("#FF0000"), "#FF0000");
+        assert_eq!(rgb_color("#ééééé"), "#ééééé");
+        assert_eq!(rgb_color("#FF0000é"), "#FF0000é");
+        assert_eq!(rgb_color("red"), "red");
+    }
+}
@@ -0,0 +1,146 @@
+use chrono::{DateTime, Utc};
+use error_chain::error_chain;
+use fs2::FileExt;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Seek, SeekFrom, Write},
+    path::Path,
+    process,
+};
+use tokio::time::{self, Duration};
+
+use crate::config::Config;
+
+error_chain! {
+    links {
+        Config(crate::config::Error, crate::config::ErrorKind);
+    }
+
+    errors {
+        LockedErr(pid: Option<u32>, date: Option<DateTime<Utc>>) {
+            description("Sync dir is locked")
+            display(
+                "Sync dir is locked by {} (use --wait to wait for it)",
+                match (pid, date) {
+                    (Some(pid), Some(date)) => format!("process {} since {}", pid, date),
+                    _ => String::from("another process"),
+                }
+            )
+        }
+    }
+}
+
+const WAIT_INTERVAL_MS: u64 = 500;
+
+#[derive(Debug, Default)]
+struct LockInfo {
+    pid: Option<u32>,
+    date: Option<DateTime<Utc>>,
+}
+
+impl LockInfo {
+    /// Reads the owner of the lock, written in the lock file. It is only
+    /// informative: the lock itself is held by the OS.
+    fn from_file(path: &Path) -> Self {
+        let content = fs::read_to_string(path).unwrap_or_default();
+        let mut tokens = content.trim().split(';');
+        let pid = tokens.next().and_then(|pid| pid.parse().ok());
+        let date = tokens.next().and_then(|date| date.parse().ok());
+        Self { pid, date }
+    }
+}
+
+/// Advisory lock on the sync dir, released when dropped.
+///
+/// The lock is an OS file lock (`flock` on Unix) on the `.lock` file, so it
+/// is released by the OS when its process dies and can never be stale.
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+}
+
+impl Lock {
+    fn try_acquire(path: &Path) -> Result<Option<Self>> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .chain_err(|| "Could not open lock file")?;
+        if file.try_lock_exclusive().is_err() {
+            return Ok(None);
+        }
+
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| write!(file, "{};{}", process::id(), Utc::now()))
+            .chain_err(|| "Could not write lock file")?;
+        Ok(Some(Self { file }))
+    }
+
+    /// Acquires the sync dir lock, after checking that the sync dir is
+    /// writable. If another run holds it, either waits for it to be released
+    /// or fails with a [`ErrorKind::LockedErr`].
+    pub async fn acquire(config: &Config, wait: bool) -> Result<Self> {
+        config.check_sync_dir()?;
+        let path = config.file_path(".lock");
+
+        loop {
+            if let Some(lock) = Self::try_acquire(&path)? {
+                return Ok(lock);
+            }
+            if !wait {
+                let info = LockInfo::from_file(&path);
+                return Err(ErrorKind::LockedErr(info.pid, info.date).into());
+            }
+
+            time::sleep(Duration::from_millis(WAIT_INTERVAL_MS)).await;
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // The file is kept: removing it would let another process lock a new
+        // file while a third one still waits on the removed one.
+        self.file.set_len(0).ok();
+        self.file.unlock().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn acquire() {
+        let dir = TempDir::new().unwrap();
+        let config: Config = toml::from_str(&format!("sync-dir = {:?}", dir.path())).unwrap();
+
+        let lock = Lock::acquire(&config, false).await.unwrap();
+        let info = LockInfo::from_file(&config.file_path(".lock"));
+        assert_eq!(info.pid, Some(process::id()));
+        let content = fs::read_to_string(config.file_path(".lock")).unwrap();
+        assert_eq!(content, format!("{};{}", process::id(), info.date.unwrap()));
+
+        match Lock::acquire(&config, false).await {
+            Err(Error(ErrorKind::LockedErr(pid, date), _)) => {
+                assert_eq!(pid, Some(process::id()));
+                assert_eq!(date, info.date);
+            }
+            res => panic!("expected a locked error, got {:?}", res),
+        }
+
+        let waiting = tokio::spawn(async move {
+            let config: Config =
+                toml::from_str(&format!("sync-dir = {:?}", config.sync_dir)).unwrap();
+            Lock::acquire(&config, true).await.map(|_| ())
+        });
+        time::sleep(Duration::from_millis(100)).await;
+        drop(lock);
+        waiting.await.unwrap().unwrap();
+    }
+}
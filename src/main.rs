@@ -1,19 +1,51 @@
+use std::process;
 use tokio;
 
 mod cache;
 mod cli;
 mod config;
+mod configure;
+mod contact;
+mod convert;
+mod credentials;
+mod dedupe;
+mod format {
+    pub(crate) mod csv;
+    pub(crate) mod jcard;
+    pub(crate) mod ldif;
+}
 mod local {
+    pub(crate) mod dir;
+    pub(crate) mod file;
+    #[cfg(test)]
+    pub(crate) mod memory;
     pub(crate) mod model;
     pub(crate) mod repository;
+    pub(crate) mod vdir;
 }
+mod lock;
+mod photo;
 mod remote {
+    pub(crate) mod carddav;
+    pub(crate) mod dav;
+    pub(crate) mod digest;
+    pub(crate) mod dir;
     pub(crate) mod model;
+    pub(crate) mod oauth2;
+    pub(crate) mod people;
     pub(crate) mod repository;
+    pub(crate) mod tls;
+    pub(crate) mod webdav;
 }
+mod sync;
+mod validate;
+mod vcard;
+mod vdirsyncer;
 
 #[tokio::main]
 async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
     if let Err(ref errs) = cli::run().await {
         let mut errs = errs.iter();
         match errs.next() {
@@ -23,5 +55,6 @@ async fn main() {
                 errs.for_each(|err| eprintln!(" ↳ {}", err));
             }
         }
+        process::exit(1);
     }
 }
@@ -0,0 +1,362 @@
+//! Extraction of inline photos to sidecar files.
+//!
+//! Extracted photos are referenced from their card with a `file://` URI,
+//! and embedded back before the card is uploaded. Only files of the photo
+//! directory are embedded, other URIs are uploaded as is. File names contain
+//! a hash of the photo, so that changing a photo changes the card as well.
+
+use error_chain::{bail, error_chain};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+use url::Url;
+
+use crate::{
+    convert::{self, Binary},
+    local, remote,
+    vcard::{self, Property, Version},
+};
+
+error_chain! {}
+
+/// Photo extracted from a card, not written yet.
+#[derive(Debug)]
+pub struct Photo {
+    pub path: PathBuf,
+    pub data: Vec<u8>,
+}
+
+const MEDIA_TYPES: [(&str, &str); 6] = [
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("png", "image/png"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("bmp", "image/bmp"),
+];
+
+fn extension(media_type: Option<&str>) -> &'static str {
+    MEDIA_TYPES
+        .iter()
+        .find(|(_, t)| Some(*t) == media_type)
+        .map(|(ext, _)| *ext)
+        .unwrap_or("bin")
+}
+
+/// Guesses the media type of an image from its extension.
+pub fn media_type(path: &Path) -> Option<String> {
+    let ext = path.extension()?.to_string_lossy().to_lowercase();
+    MEDIA_TYPES
+        .iter()
+        .find(|(e, _)| *e == ext)
+        .map(|(_, t)| t.to_string())
+}
+
+/// Creates the photo directory if needed, then returns its canonical path so
+/// that URIs do not depend on the way the directory is configured.
+fn canonical_dir(dir: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(dir).chain_err(|| format!("Could not create {:?}", dir))?;
+    fs::canonicalize(dir).chain_err(|| format!("Could not resolve {:?}", dir))
+}
+
+/// Builds the percent-encoded `file://` URI of an absolute path.
+fn file_uri(path: &Path) -> String {
+    match Url::from_file_path(path) {
+        Ok(url) => url.to_string(),
+        Err(()) => format!("file://{}", path.display()),
+    }
+}
+
+fn file_path(uri: &str) -> Option<PathBuf> {
+    Url::parse(uri)
+        .ok()
+        .filter(|url| url.scheme() == "file")
+        .and_then(|url| url.to_file_path().ok())
+}
+
+fn card_props(content: &str) -> Vec<Property> {
+    vcard::parse(content)
+        .into_iter()
+        .filter(|prop| prop.name != "BEGIN" && prop.name != "END")
+        .collect()
+}
+
+/// Path of a photo in the given directory.
+fn photo_path(dir: &Path, name: &str, data: &[u8], media_type: Option<&str>) -> PathBuf {
+    let file_name = local::repository::file_name(name);
+    let hash = format!("{:x}", Sha256::digest(data));
+    dir.join(format!(
+        "{}.{}.{}",
+        file_name.trim_end_matches(".vcf"),
+        &hash[..8],
+        extension(media_type)
+    ))
+}
+
+/// Replaces the inline photos of a card by references to files of the given
+/// directory. The content is returned as is if there is nothing to extract.
+pub fn extract(content: &str, name: &str, dir: &Path) -> Result<(String, Vec<Photo>)> {
+    let version = vcard::version(content).unwrap_or(Version::V30);
+    let mut props = card_props(content);
+    let mut photos = vec![];
+
+    for prop in props.iter_mut().filter(|prop| prop.name == "PHOTO") {
+        if let Binary::Inline { media_type, data } = convert::binary(prop) {
+            let data = base64::decode(&data)
+                .chain_err(|| format!("Invalid base64 photo in card {}", name))?;
+            let path = photo_path(&canonical_dir(dir)?, name, &data, media_type.as_deref());
+            let uri = file_uri(&path);
+            *prop = convert::binary_prop(prop, Binary::Uri(uri), version);
+            photos.push(Photo { path, data });
+        }
+    }
+
+    if photos.is_empty() {
+        return Ok((content.to_owned(), photos));
+    }
+    Ok((vcard::build(&props), photos))
+}
+
+/// Extracts the photos of remote cards, indexed by card name.
+pub fn extract_cards(
+    cards: &mut HashMap<String, remote::model::Card>,
+    dir: &Path,
+) -> Result<HashMap<String, Vec<Photo>>> {
+    let mut photos = HashMap::new();
+    for (name, card) in cards.iter_mut() {
+        let (content, card_photos) = extract(&card.content, name, dir)?;
+        card.content = content;
+        photos.insert(name.to_owned(), card_photos);
+    }
+
+    Ok(photos)
+}
+
+/// Writes extracted photos, unless they already exist.
+pub fn write(photos: &[Photo]) -> Result<()> {
+    for photo in photos.iter().filter(|photo| !photo.path.exists()) {
+        if let Some(dir) = photo.path.parent() {
+            fs::create_dir_all(dir).chain_err(|| format!("Could not create {:?}", dir))?;
+        }
+        fs::write(&photo.path, &photo.data)
+            .chain_err(|| format!("Could not write photo {:?}", photo.path))?;
+    }
+
+    Ok(())
+}
+
+/// Embeds back the photos referenced by `file://` URIs of files of the given
+/// directory. The content is returned as is if there is nothing to embed.
+pub fn embed(content: &str, dir: &Path) -> Result<String> {
+    let version = vcard::version(content).unwrap_or(Version::V30);
+    let mut props = card_props(content);
+    let mut is_changed = false;
+    let dir = canonical_dir(dir)?;
+
+    for prop in props.iter_mut().filter(|prop| prop.name == "PHOTO") {
+        let path = match convert::binary(prop) {
+            Binary::Uri(uri) => match file_path(&uri) {
+                Some(path) => path,
+                None => continue,
+            },
+            _ => continue,
+        };
+        // Links are resolved first, so that a card cannot embed files from
+        // outside of the photo dir.
+        let path = match fs::canonicalize(&path) {
+            Ok(path) if path.starts_with(&dir) => path,
+            Err(err) if path.starts_with(&dir) => {
+                return Err(err).chain_err(|| format!("Could not read photo {:?}", path))
+            }
+            _ => continue,
+        };
+        let data = fs::read(&path).chain_err(|| format!("Could not read photo {:?}", path))?;
+        let binary = Binary::Inline {
+            media_type: media_type(&path),
+            data: base64::encode(data),
+        };
+        *prop = convert::binary_prop(prop, binary, version);
+        is_changed = true;
+    }
+
+    if !is_changed {
+        return Ok(content.to_owned());
+    }
+    Ok(vcard::build(&props))
+}
+
+/// Reads the photo of a card, either inline or from a local file.
+pub fn get(content: &str) -> Result<Option<Vec<u8>>> {
+    let prop = match vcard::parse(content)
+        .into_iter()
+        .find(|prop| prop.name == "PHOTO")
+    {
+        Some(prop) => prop,
+        None => return Ok(None),
+    };
+
+    match convert::binary(&prop) {
+        Binary::Inline { data, .. } => Ok(Some(
+            base64::decode(&data).chain_err(|| "Invalid base64 photo")?,
+        )),
+        Binary::Uri(uri) => match file_path(&uri) {
+            Some(path) => {
+                Ok(Some(fs::read(&path).chain_err(|| {
+                    format!("Could not read photo {:?}", path)
+                })?))
+            }
+            None => bail!("Photo is a link to {}", uri),
+        },
+    }
+}
+
+/// Sets the photo of a card, replacing the previous ones. The photo is
+/// inline, unless a directory is given to extract it to.
+pub fn set(
+    content: &str,
+    name: &str,
+    data: Vec<u8>,
+    media_type: Option<String>,
+    dir: Option<&Path>,
+) -> Result<(String, Vec<Photo>)> {
+    let version = vcard::version(content).unwrap_or(Version::V30);
+    let mut props = card_props(content);
+    let mut photos = vec![];
+
+    let binary = match dir {
+        Some(dir) => {
+            let path = photo_path(&canonical_dir(dir)?, name, &data, media_type.as_deref());
+            let uri = file_uri(&path);
+            photos.push(Photo { path, data });
+            Binary::Uri(uri)
+        }
+        None => Binary::Inline {
+            media_type,
+            data: base64::encode(data),
+        },
+    };
+    let prop = convert::binary_prop(&Property::new("PHOTO", ""), binary, version);
+
+    let i = props
+        .iter()
+        .position(|prop| prop.name == "PHOTO")
+        .unwrap_or(props.len());
+    props.retain(|prop| prop.name != "PHOTO");
+    props.insert(i, prop);
+
+    Ok((vcard::build(&props), photos))
+}
+
+/// Checks if a file name looks like the one of an extracted photo, so that
+/// other files of the directory are left untouched.
+fn is_photo_file(path: &Path) -> bool {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut parts = file_name.rsplitn(3, '.');
+    let ext = parts.next().unwrap_or_default();
+    let hash = parts.next().unwrap_or_default();
+
+    parts.next().is_some()
+        && (ext == "bin" || MEDIA_TYPES.iter().any(|(e, _)| *e == ext))
+        && hash.len() == 8
+        && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Removes the extracted photos of the directory that are not referenced by
+/// any card anymore.
+pub fn clean(dir: &Path, contents: &[&str]) -> Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    let dir = canonical_dir(dir)?;
+
+    let used: HashSet<PathBuf> = contents
+        .iter()
+        .flat_map(|content| vcard::parse(content))
+        .filter(|prop| prop.name == "PHOTO")
+        .filter_map(|prop| match convert::binary(&prop) {
+            Binary::Uri(uri) => file_path(&uri),
+            _ => None,
+        })
+        .map(|path| fs::canonicalize(&path).unwrap_or(path))
+        .collect();
+
+    let entries = fs::read_dir(&dir).chain_err(|| format!("Could not read {:?}", dir))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_file() && is_photo_file(&path) && !used.contains(&path) {
+            fs::remove_file(&path).chain_err(|| format!("Could not remove {:?}", path))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const CARD: &str = "BEGIN:VCARD\r\n\
+        VERSION:3.0\r\n\
+        FN:John Doe\r\n\
+        PHOTO;ENCODING=b;TYPE=JPEG:aGVsbG8=\r\n\
+        END:VCARD\r\n";
+
+    #[test]
+    fn extract_and_embed() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path().join("my photos");
+
+        let (content, photos) = extract(CARD, "jdoe", &dir).unwrap();
+        write(&photos).unwrap();
+        let dir = fs::canonicalize(&dir).unwrap();
+        let uri = file_uri(&dir.join("jdoe.2cf24dba.jpg"));
+
+        assert!(uri.starts_with("file:///") && uri.contains("/my%20photos/"));
+        assert_eq!(
+            content,
+            format!(
+                "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:John Doe\r\nPHOTO;VALUE=uri:{}\r\nEND:VCARD\r\n",
+                uri
+            )
+        );
+        assert_eq!(fs::read(dir.join("jdoe.2cf24dba.jpg")).unwrap(), b"hello");
+        assert_eq!(embed(&content, &dir).unwrap(), CARD);
+    }
+
+    #[test]
+    fn embed_only_photo_dir_files() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path().join("photos");
+        fs::write(tmp.path().join("secret.jpg"), "secret").unwrap();
+        let outside = file_uri(&fs::canonicalize(tmp.path()).unwrap().join("secret.jpg"));
+        let escaping = format!("{}/photos/../secret.jpg", file_uri(tmp.path()));
+
+        for uri in [outside, escaping, String::from("https://doe.com/jdoe.jpg")].iter() {
+            let content = format!(
+                "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:John Doe\r\nPHOTO;VALUE=uri:{}\r\nEND:VCARD\r\n",
+                uri
+            );
+            assert_eq!(embed(&content, &dir).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn missing_photo() {
+        let tmp = TempDir::new().unwrap();
+        let dir = fs::canonicalize(tmp.path()).unwrap();
+        let content = format!(
+            "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:John Doe\r\nPHOTO;VALUE=uri:{}\r\nEND:VCARD\r\n",
+            file_uri(&dir.join("jdoe.2cf24dba.jpg"))
+        );
+
+        assert!(embed(&content, &dir).is_err());
+    }
+}
@@ -0,0 +1,400 @@
+use async_trait::async_trait;
+use log::warn;
+use quick_xml::de as xml;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::{
+    dav::{self, Etag, Href, LastModified, Multistatus},
+    model::{Card, Metadata},
+    repository::{self, Client, RemoteRepository, Result, ResultExt},
+};
+use crate::config::Config;
+
+// Ctag structs
+
+#[derive(Debug, Deserialize)]
+pub struct Ctag {
+    #[serde(rename = "$value")]
+    pub value: String,
+}
+
+// Current user principal structs
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CurrentUserPrincipalProp {
+    pub current_user_principal: CurrentUserPrincipal,
+}
+
+#[derive(Debug, Deserialize)]
+struct CurrentUserPrincipal {
+    pub href: Href,
+}
+
+// Addressbook home set structs
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct AddressbookHomeSetProp {
+    pub addressbook_home_set: AddressbookHomeSet,
+}
+
+#[derive(Debug, Deserialize)]
+struct AddressbookHomeSet {
+    pub href: Href,
+}
+
+// Addressbook structs
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct AddressbookProp {
+    pub resourcetype: AddressbookResourceType,
+}
+
+#[derive(Debug, Deserialize)]
+struct AddressbookResourceType {
+    pub addressbook: Option<Addressbook>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Addressbook {}
+
+// Address data structs
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AddressDataProp {
+    pub address_data: AddressData,
+    pub getetag: Etag,
+    pub getlastmodified: LastModified,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddressData {
+    #[serde(rename = "$value")]
+    pub value: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CtagProp {
+    pub getctag: Ctag,
+}
+
+// Fetch URL fns
+
+async fn fetch_current_user_principal_url(
+    config: &Config,
+    client: &Client,
+    path: String,
+) -> Result<String> {
+    let req = dav::request(config, client, dav::propfind()?, &path)?.body(
+        r#"
+            <D:propfind xmlns:D="DAV:">
+                <D:prop>
+                    <D:current-user-principal />
+                </D:prop>
+            </D:propfind>
+            "#,
+    );
+    let res = dav::send(config, client, req)
+        .await
+        .chain_err(|| "Could not send current user principal request")?;
+    let res = res
+        .text()
+        .await
+        .chain_err(|| "Could not extract text body from current user principal response")?;
+    let res: Multistatus<CurrentUserPrincipalProp> =
+        xml::from_str(&res).chain_err(|| "Could not parse current user principal response")?;
+
+    Ok(res
+        .responses
+        .first()
+        .map(|res| {
+            res.propstat
+                .prop
+                .current_user_principal
+                .href
+                .value
+                .to_owned()
+        })
+        .unwrap_or(path))
+}
+
+async fn fetch_addressbook_home_set_url(
+    config: &Config,
+    client: &Client,
+    path: String,
+) -> Result<String> {
+    let req = dav::request(config, client, dav::propfind()?, &path)?.body(
+        r#"
+            <D:propfind xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:carddav">
+                <D:prop>
+                    <C:addressbook-home-set />
+                </D:prop>
+            </D:propfind>
+            "#,
+    );
+    let res = dav::send(config, client, req)
+        .await
+        .chain_err(|| "Could not send addressbook home set request")?;
+    let res = res
+        .text()
+        .await
+        .chain_err(|| "Could not extract text body from addressbook home set response")?;
+    let res: Multistatus<AddressbookHomeSetProp> =
+        xml::from_str(&res).chain_err(|| "Could not parse addressbook home set response")?;
+
+    Ok(res
+        .responses
+        .first()
+        .map(|res| res.propstat.prop.addressbook_home_set.href.value.to_owned())
+        .unwrap_or(path))
+}
+
+/// Lists the addressbooks of a collection.
+async fn fetch_addressbook_urls(
+    config: &Config,
+    client: &Client,
+    path: &str,
+) -> Result<Vec<String>> {
+    let req = dav::request(config, client, dav::propfind()?, path)?;
+    let res = dav::send(config, client, req)
+        .await
+        .chain_err(|| "Could not send addressbook request")?;
+    let res = res
+        .text()
+        .await
+        .chain_err(|| "Could not extract text body from addressbook response")?;
+    let res: Multistatus<AddressbookProp> =
+        xml::from_str(&res).chain_err(|| "Could not parse addressbook response")?;
+
+    Ok(res
+        .responses
+        .iter()
+        .filter(|res| {
+            let valid_status = res
+                .propstat
+                .status
+                .as_ref()
+                .map(|s| s.value.ends_with("200 OK"))
+                .unwrap_or(false);
+            let has_addressbook = res
+                .propstat
+                .prop
+                .resourcetype
+                .addressbook
+                .as_ref()
+                .is_some();
+
+            valid_status && has_addressbook
+        })
+        .map(|res| res.href.value.to_owned())
+        .collect())
+}
+
+async fn fetch_addressbook_url(config: &Config, client: &Client, path: String) -> Result<String> {
+    let urls = fetch_addressbook_urls(config, client, &path).await?;
+    Ok(urls.into_iter().next().unwrap_or(path))
+}
+
+/// Lists the cards of an addressbook. Cards that cannot be read are skipped,
+/// their hrefs are returned along with the cards.
+async fn fetch_cards(
+    config: &Config,
+    client: &Client,
+    path: &str,
+) -> Result<(HashMap<String, Card>, Vec<String>)> {
+    // The jCard form is only requested on demand, servers not supporting it
+    // are free to answer with vCard anyway.
+    let address_data = if config.jcard() {
+        r#"<C:address-data content-type="application/vcard+json" version="4.0" />"#
+    } else {
+        "<C:address-data />"
+    };
+    let req = dav::request(config, client, dav::report()?, path)?
+        .header("Depth", "1")
+        .body(format!(
+            r#"
+            <C:addressbook-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:carddav">
+                <D:prop>
+                    <D:getetag />
+                    <D:getlastmodified />
+                    {}
+                </D:prop>
+            </C:addressbook-query>
+            "#,
+            address_data
+        ));
+    let res = dav::send(config, client, req)
+        .await
+        .chain_err(|| "Could not send address data request")?
+        .text()
+        .await
+        .chain_err(|| "Could not extract text body from address data response")?;
+    let res: Multistatus<AddressDataProp> =
+        xml::from_str(&res).chain_err(|| "Could not parse address data response")?;
+
+    let mut cards = HashMap::new();
+    let mut skipped = vec![];
+    for res in res.responses.iter() {
+        let content = match dav::card_content(res.propstat.prop.address_data.value.to_owned()) {
+            Ok(content) => content,
+            Err(err) => {
+                let err: Vec<_> = err.iter().map(|err| err.to_string()).collect();
+                warn!("Skipping card {}: {}", res.href.value, err.join(": "));
+                skipped.push(res.href.value.to_owned());
+                continue;
+            }
+        };
+        let name = match repository::card_name(&res.href.value, &content) {
+            Some(name) => name,
+            None => continue,
+        };
+
+        cards.insert(
+            name.to_owned(),
+            Card {
+                etag: res.propstat.prop.getetag.value.to_owned(),
+                name,
+                href: res.href.value.to_owned(),
+                date: res.propstat.prop.getlastmodified.value,
+                content,
+            },
+        );
+    }
+
+    Ok((cards, skipped))
+}
+
+async fn fetch_ctag(config: &Config, client: &Client, path: &str) -> Result<String> {
+    let req = dav::request(config, client, dav::propfind()?, path)?
+        .header("Depth", "0")
+        .body(
+            r#"
+            <D:propfind xmlns:D="DAV:" xmlns:C="http://calendarserver.org/ns/">
+                <D:prop>
+                    <C:getctag />
+                </D:prop>
+            </D:propfind>
+            "#,
+        );
+    let res = dav::send(config, client, req)
+        .await
+        .chain_err(|| "Could not send ctag request")?
+        .text()
+        .await
+        .chain_err(|| "Could not extract text body from ctag response")?;
+    let res: Multistatus<CtagProp> =
+        xml::from_str(&res).chain_err(|| "Could not parse ctag response")?;
+
+    Ok(res
+        .responses
+        .iter()
+        .find(|res| {
+            res.propstat
+                .status
+                .as_ref()
+                .map(|s| s.value.ends_with("200 OK"))
+                .unwrap_or(false)
+        })
+        .map(|res| res.propstat.prop.getctag.value.to_owned())
+        .unwrap_or_default())
+}
+
+async fn addressbook_home_set_path(config: &Config, client: &Client, path: &str) -> Result<String> {
+    let path = fetch_current_user_principal_url(config, client, path.to_owned()).await?;
+    let path = fetch_addressbook_home_set_url(config, client, path).await?;
+
+    Ok(path)
+}
+
+/// Finds the addressbook to sync: the configured one, or the first one of
+/// the current user.
+async fn addressbook_path(config: &Config, client: &Client) -> Result<String> {
+    if let Some(ref path) = config.remote_path {
+        return Ok(path.to_owned());
+    }
+
+    let path = addressbook_home_set_path(config, client, "/").await?;
+    let path = fetch_addressbook_url(config, client, path).await?;
+
+    Ok(path)
+}
+
+/// Lists the addressbooks of the current user, starting the discovery
+/// from the given path.
+pub async fn addressbooks(config: &Config, client: &Client, path: &str) -> Result<Vec<String>> {
+    let path = addressbook_home_set_path(config, client, path).await?;
+    fetch_addressbook_urls(config, client, &path).await
+}
+
+/// CardDAV addressbook, discovered from the current user principal unless
+/// `remote-path` is set.
+pub struct CardDavRepository<'a> {
+    config: &'a Config,
+    client: &'a Client,
+    path: String,
+    is_partial: AtomicBool,
+}
+
+impl<'a> CardDavRepository<'a> {
+    pub fn new(config: &'a Config, client: &'a Client) -> Self {
+        Self {
+            config,
+            client,
+            path: String::from("/"),
+            is_partial: AtomicBool::new(false),
+        }
+    }
+}
+
+#[async_trait]
+impl RemoteRepository for CardDavRepository<'_> {
+    async fn discover(&mut self) -> Result<String> {
+        self.path = addressbook_path(self.config, self.client).await?;
+        Ok(self.path.to_owned())
+    }
+
+    async fn change_token(&self) -> Result<String> {
+        fetch_ctag(self.config, self.client, &self.path).await
+    }
+
+    async fn list(&self) -> Result<HashMap<String, Card>> {
+        let (cards, skipped) = fetch_cards(self.config, self.client, &self.path).await?;
+        self.is_partial
+            .store(!skipped.is_empty(), Ordering::Relaxed);
+        Ok(cards)
+    }
+
+    fn is_partial(&self) -> bool {
+        self.is_partial.load(Ordering::Relaxed)
+    }
+
+    async fn fetch(&self, href: &str) -> Result<Card> {
+        dav::get(self.config, self.client, href).await
+    }
+
+    async fn put(&mut self, name: &str, content: &str, prev: Option<&Card>) -> Result<Card> {
+        let href = match prev {
+            Some(card) => card.href.to_owned(),
+            None => dav::new_href(&self.path, name),
+        };
+        let etag = prev.map(|card| card.etag.as_str());
+        dav::put(self.config, self.client, &href, content, etag).await?;
+
+        self.fetch(&href).await
+    }
+
+    async fn delete(&mut self, card: &Card) -> Result<()> {
+        dav::delete(self.config, self.client, &card.href, &card.etag).await
+    }
+
+    async fn metadata(&self) -> Result<Metadata> {
+        dav::fetch_metadata(self.config, self.client, &self.path).await
+    }
+}
@@ -0,0 +1,569 @@
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use quick_xml::de as xml;
+use reqwest::{
+    header::{self, HeaderMap, HeaderValue},
+    Method, Request, RequestBuilder, StatusCode,
+};
+use serde::Deserialize;
+use std::time::Duration;
+
+use super::{
+    digest,
+    model::{Card, Metadata},
+    oauth2,
+    repository::{self, Client, Result, ResultExt},
+};
+use crate::{
+    config::{AuthKind, Config},
+    credentials,
+    format::jcard,
+    local,
+};
+
+// Common structs
+
+#[derive(Debug, Deserialize)]
+pub struct Multistatus<T> {
+    #[serde(rename = "response")]
+    pub responses: Vec<Response<T>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Response<T> {
+    pub href: Href,
+    pub propstat: Propstat<T>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Propstat<T> {
+    pub prop: T,
+    pub status: Option<Status>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Href {
+    #[serde(rename = "$value")]
+    pub value: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Status {
+    #[serde(rename = "$value")]
+    pub value: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Etag {
+    #[serde(rename = "$value", default)]
+    pub value: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LastModified {
+    #[serde(with = "date_parser", rename = "$value")]
+    pub value: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Text {
+    #[serde(rename = "$value", default)]
+    pub value: String,
+}
+
+mod date_parser {
+    use chrono::{DateTime, Utc};
+    use serde::{self, Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        DateTime::parse_from_rfc2822(&s)
+            .map(|d| d.into())
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+// Metadata structs
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct MetadataProp {
+    pub displayname: Option<Text>,
+    /// Color of the address book, as exposed by Radicale and InfCloud.
+    pub addressbook_color: Option<Text>,
+    /// Color of calendars, that some servers also expose on address books.
+    pub calendar_color: Option<Text>,
+}
+
+// Methods
+
+pub fn propfind() -> Result<Method> {
+    Method::from_bytes(b"PROPFIND").chain_err(|| "Could not create custom method PROPFIND")
+}
+
+pub fn report() -> Result<Method> {
+    Method::from_bytes(b"REPORT").chain_err(|| "Could not create custom method REPORT")
+}
+
+// Request fns
+
+/// Builds a request to the given path of the server. Credentials are added
+/// when it is sent, see [`send`].
+pub fn request(
+    config: &Config,
+    client: &Client,
+    method: Method,
+    path: &str,
+) -> Result<RequestBuilder> {
+    Ok(client.request(method, config.url(path)))
+}
+
+/// Longest delay before sending a request again. Requests are not sent again
+/// if the server asks to wait longer.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        method.as_str(),
+        "GET" | "HEAD" | "OPTIONS" | "PROPFIND" | "REPORT"
+    )
+}
+
+/// Reads the `Retry-After` header, either a number of seconds or a date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let val = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    match val.parse() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => {
+            let date = DateTime::parse_from_rfc2822(val).ok()?;
+            let delay = date.with_timezone(&Utc) - Utc::now();
+            Some(delay.to_std().unwrap_or_default())
+        }
+    }
+}
+
+/// Sends a request. Idempotent requests are sent again after a network
+/// failure or a `429`/`503` response, waiting twice longer after each
+/// attempt, or as long as the server asks with `Retry-After`.
+async fn execute(
+    config: &Config,
+    client: &Client,
+    mut req: Request,
+    auth: Option<String>,
+) -> Result<reqwest::Response> {
+    if let Some(auth) = auth {
+        let auth = HeaderValue::from_str(&auth).chain_err(|| "Invalid authorization header")?;
+        req.headers_mut().insert(header::AUTHORIZATION, auth);
+    }
+
+    let mut attempt = 0;
+    loop {
+        let retry = req
+            .try_clone()
+            .filter(|_| is_idempotent(req.method()) && attempt < config.retries());
+        let url = req.url().to_string();
+        let backoff = Duration::from_secs(2u64.saturating_pow(attempt)).min(MAX_RETRY_DELAY);
+
+        let res = client.execute(req).await;
+        let delay = match res {
+            Ok(ref res)
+                if res.status() == StatusCode::TOO_MANY_REQUESTS
+                    || res.status() == StatusCode::SERVICE_UNAVAILABLE =>
+            {
+                retry_after(res.headers()).unwrap_or(backoff)
+            }
+            Err(ref err) if err.is_timeout() || err.is_connect() => backoff,
+            _ => return res.chain_err(|| "Could not send request"),
+        };
+        req = match retry {
+            Some(retry) if delay <= MAX_RETRY_DELAY => retry,
+            _ => return res.chain_err(|| "Could not send request"),
+        };
+
+        warn!(
+            "Request to {} failed, sending it again in {:.1}s",
+            url,
+            delay.as_secs_f32()
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+async fn passwd(config: &Config) -> Result<String> {
+    credentials::passwd(config)
+        .await
+        .chain_err(|| "Could not retrieve password")
+}
+
+/// Sends a request authenticated with the configured credentials. The
+/// request is sent again once if the server rejects it but the credentials
+/// can be renewed: a new Digest challenge, or an expired OAuth2 token.
+pub async fn send(
+    config: &Config,
+    client: &Client,
+    req: RequestBuilder,
+) -> Result<reqwest::Response> {
+    let req = req.build().chain_err(|| "Could not build request")?;
+    let retry = req.try_clone();
+
+    let mut is_new_token = false;
+    let auth = match config.auth() {
+        AuthKind::Basic if !digest::has_challenge(client) => {
+            let credentials = format!("{}:{}", config.login, passwd(config).await?);
+            Some(format!("Basic {}", base64::encode(credentials)))
+        }
+        AuthKind::Basic | AuthKind::Digest => {
+            digest::authorization(client, &config.login, &passwd(config).await?, &req)
+        }
+        AuthKind::Oauth2 => {
+            let token = match oauth2::access_token(client) {
+                Some(token) => token,
+                None => {
+                    is_new_token = true;
+                    oauth2::refresh(config, client).await?
+                }
+            };
+            Some(format!("Bearer {}", token))
+        }
+    };
+    let res = execute(config, client, req, auth).await?;
+
+    let retry = match retry {
+        Some(retry) if res.status() == StatusCode::UNAUTHORIZED => retry,
+        _ => return Ok(res),
+    };
+    match config.auth() {
+        AuthKind::Basic | AuthKind::Digest => match digest::parse_challenge(res.headers())? {
+            Some(challenge) => {
+                debug!("Digest challenge received, sending the request again");
+                digest::set_challenge(client, challenge);
+                let auth =
+                    digest::authorization(client, &config.login, &passwd(config).await?, &retry);
+                execute(config, client, retry, auth).await
+            }
+            None => Ok(res),
+        },
+        AuthKind::Oauth2 if !is_new_token => {
+            let token = oauth2::refresh(config, client).await?;
+            execute(config, client, retry, Some(format!("Bearer {}", token))).await
+        }
+        AuthKind::Oauth2 => Ok(res),
+    }
+}
+
+pub async fn get(config: &Config, client: &Client, href: &str) -> Result<Card> {
+    let mut req = request(config, client, Method::GET, href)?;
+    if config.jcard() {
+        req = req.header(header::ACCEPT, "application/vcard+json, text/vcard;q=0.9");
+    }
+    let res = send(config, client, req)
+        .await
+        .chain_err(|| format!("Could not send get request for {}", href))?
+        .error_for_status()
+        .chain_err(|| format!("Could not get card {}", href))?;
+    let etag = res
+        .headers()
+        .get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    let date = res
+        .headers()
+        .get(header::LAST_MODIFIED)
+        .and_then(|date| date.to_str().ok())
+        .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+        .map(|date| date.into())
+        .unwrap_or_else(Utc::now);
+    let content = res
+        .text()
+        .await
+        .chain_err(|| format!("Could not extract text body from card {}", href))?;
+    let content = card_content(content).chain_err(|| format!("Invalid card {}", href))?;
+
+    Ok(Card {
+        etag,
+        name: repository::card_name(href, &content).unwrap_or_default(),
+        href: href.to_owned(),
+        date,
+        content,
+    })
+}
+
+/// Converts a card received from the server to vCard, in case the server
+/// answered with a jCard.
+pub fn card_content(content: String) -> Result<String> {
+    if !jcard::is_jcard(&content) {
+        return Ok(content);
+    }
+
+    jcard::parse(&content)
+        .chain_err(|| "Could not parse jCard")?
+        .into_iter()
+        .next()
+        .chain_err(|| "Empty jCard")
+}
+
+/// Puts a card at the given href. If an etag is given, the card is only
+/// updated if it did not change on the server since, otherwise it is only
+/// created if it does not exist yet.
+pub async fn put(
+    config: &Config,
+    client: &Client,
+    href: &str,
+    content: &str,
+    etag: Option<&str>,
+) -> Result<()> {
+    let req = request(config, client, Method::PUT, href)?
+        .header(header::CONTENT_TYPE, "text/vcard; charset=utf-8")
+        .body(content.to_owned());
+    let req = match etag {
+        Some(etag) => req.header(header::IF_MATCH, etag),
+        None => req.header(header::IF_NONE_MATCH, "*"),
+    };
+
+    send(config, client, req)
+        .await
+        .chain_err(|| format!("Could not send put request for {}", href))?
+        .error_for_status()
+        .chain_err(|| format!("Could not put card {}", href))?;
+
+    Ok(())
+}
+
+pub async fn delete(config: &Config, client: &Client, href: &str, etag: &str) -> Result<()> {
+    let req = request(config, client, Method::DELETE, href)?.header(header::IF_MATCH, etag);
+    send(config, client, req)
+        .await
+        .chain_err(|| format!("Could not send delete request for {}", href))?
+        .error_for_status()
+        .chain_err(|| format!("Could not delete card {}", href))?;
+
+    Ok(())
+}
+
+/// Fetches the display name and the color of a collection.
+pub async fn fetch_metadata(config: &Config, client: &Client, path: &str) -> Result<Metadata> {
+    let req = request(config, client, propfind()?, path)?
+        .header("Depth", "0")
+        .body(
+            r#"
+            <D:propfind xmlns:D="DAV:" xmlns:A="http://inf-it.com/ns/ab/" xmlns:I="http://apple.com/ns/ical/">
+                <D:prop>
+                    <D:displayname />
+                    <A:addressbook-color />
+                    <I:calendar-color />
+                </D:prop>
+            </D:propfind>
+            "#,
+        );
+    let res = send(config, client, req)
+        .await
+        .chain_err(|| "Could not send metadata request")?
+        .text()
+        .await
+        .chain_err(|| "Could not extract text body from metadata response")?;
+
+    parse_metadata(&res)
+}
+
+/// Parses the metadata of a collection. The address book color is preferred
+/// over the calendar one.
+fn parse_metadata(res: &str) -> Result<Metadata> {
+    let res: Multistatus<MetadataProp> =
+        xml::from_str(res).chain_err(|| "Could not parse metadata response")?;
+
+    let non_empty = |text: Option<Text>| text.map(|t| t.value).filter(|v| !v.is_empty());
+    Ok(res
+        .responses
+        .into_iter()
+        .next()
+        .map(|res| {
+            let prop = res.propstat.prop;
+            Metadata {
+                displayname: non_empty(prop.displayname),
+                color: non_empty(prop.addressbook_color).or(non_empty(prop.calendar_color)),
+            }
+        })
+        .unwrap_or_default())
+}
+
+/// Builds the href of a new card in the given collection.
+pub fn new_href(path: &str, name: &str) -> String {
+    format!(
+        "{}/{}",
+        path.trim_end_matches('/'),
+        local::repository::file_name(name)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration as ChronoDuration;
+    use std::sync::{Arc, Mutex};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::remote::repository;
+
+    /// Serves the given responses (status line and headers), one per
+    /// connection, and records the request lines.
+    async fn server(responses: Vec<&'static str>) -> (Config, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let lines = Arc::new(Mutex::new(vec![]));
+        let received = lines.clone();
+
+        tokio::spawn(async move {
+            for res in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut req = vec![];
+                let mut buf = [0; 1024];
+                while !String::from_utf8_lossy(&req).contains("\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    req.extend_from_slice(&buf[..n]);
+                }
+                let req = String::from_utf8_lossy(&req);
+                received
+                    .lock()
+                    .unwrap()
+                    .push(req.lines().next().unwrap_or_default().to_owned());
+                let res = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    res
+                );
+                stream.write_all(res.as_bytes()).await.unwrap();
+            }
+        });
+
+        let config = toml::from_str(&format!(
+            r#"
+            sync-dir = "/tmp"
+            host = "127.0.0.1"
+            port = {}
+            ssl = false
+            login = "u"
+            "#,
+            port
+        ))
+        .unwrap();
+        (config, lines)
+    }
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::RETRY_AFTER,
+            HeaderValue::from_str(retry_after).unwrap(),
+        );
+        headers
+    }
+
+    fn metadata(props: &str) -> Metadata {
+        parse_metadata(&format!(
+            r#"<?xml version="1.0"?>
+            <D:multistatus xmlns:D="DAV:" xmlns:A="http://inf-it.com/ns/ab/" xmlns:I="http://apple.com/ns/ical/">
+                <D:response>
+                    <D:href>/books/default/</D:href>
+                    <D:propstat><D:prop>{}</D:prop></D:propstat>
+                </D:response>
+            </D:multistatus>"#,
+            props
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn metadata_colors() {
+        let res = metadata(
+            "<D:displayname>Friends</D:displayname>\
+             <A:addressbook-color>#00FF00FF</A:addressbook-color>\
+             <I:calendar-color>#FF0000FF</I:calendar-color>",
+        );
+        assert_eq!(res.displayname.as_deref(), Some("Friends"));
+        assert_eq!(res.color.as_deref(), Some("#00FF00FF"));
+
+        let res = metadata("<A:addressbook-color /><I:calendar-color>#FF0000FF</I:calendar-color>");
+        assert_eq!(res.color.as_deref(), Some("#FF0000FF"));
+
+        let res = metadata("<D:displayname />");
+        assert_eq!(res.displayname, None);
+        assert_eq!(res.color, None);
+    }
+
+    #[test]
+    fn retry_after_header() {
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+        assert_eq!(retry_after(&headers("5")), Some(Duration::from_secs(5)));
+        assert_eq!(retry_after(&headers("soon")), None);
+        assert_eq!(
+            retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Some(Duration::from_secs(0))
+        );
+
+        let date = (Utc::now() + ChronoDuration::seconds(30)).to_rfc2822();
+        let delay = retry_after(&headers(&date)).unwrap();
+        assert!(delay > Duration::from_secs(25) && delay <= Duration::from_secs(30));
+
+        let delay = retry_after(&headers("120")).unwrap();
+        assert!(delay > MAX_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn retry_idempotent_requests() {
+        let (config, lines) =
+            server(vec!["503 Service Unavailable\r\nRetry-After: 0", "200 OK"]).await;
+        let client = repository::client(&config).unwrap();
+        let req = client.get(config.url("/card.vcf")).build().unwrap();
+        let res = execute(&config, &client, req, None).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            *lines.lock().unwrap(),
+            vec!["GET /card.vcf HTTP/1.1", "GET /card.vcf HTTP/1.1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn do_not_retry_other_requests() {
+        let (config, lines) = server(vec![
+            "503 Service Unavailable\r\nRetry-After: 0",
+            "503 Service Unavailable\r\nRetry-After: 0",
+            "503 Service Unavailable\r\nRetry-After: 120",
+            "200 OK",
+        ])
+        .await;
+        let client = repository::client(&config).unwrap();
+
+        let req = client
+            .put(config.url("/card.vcf"))
+            .body("")
+            .build()
+            .unwrap();
+        let res = execute(&config, &client, req, None).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let req = client.post(config.url("/cards")).body("").build().unwrap();
+        let res = execute(&config, &client, req, None).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // The server asks to wait longer than the longest delay.
+        let req = client.get(config.url("/card.vcf")).build().unwrap();
+        let res = execute(&config, &client, req, None).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        assert_eq!(
+            *lines.lock().unwrap(),
+            vec![
+                "PUT /card.vcf HTTP/1.1",
+                "POST /cards HTTP/1.1",
+                "GET /card.vcf HTTP/1.1"
+            ]
+        );
+    }
+}
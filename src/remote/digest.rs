@@ -0,0 +1,314 @@
+//! HTTP Digest authentication ([RFC 7616]), driven by the `WWW-Authenticate`
+//! challenge of the server.
+//!
+//! [RFC 7616]: https://tools.ietf.org/html/rfc7616
+
+use md5::Md5;
+use reqwest::{header::HeaderMap, Method, Request};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use super::repository::{Client, Result};
+
+#[derive(Debug, Clone)]
+pub struct Challenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: String,
+    /// Whether the server supports the `auth` quality of protection.
+    has_qop: bool,
+    /// Number of requests sent with the current nonce.
+    nonce_count: u32,
+}
+
+/// Splits the parameters of a challenge, like `realm="a, b", nonce=c`.
+fn parse_params(params: &str) -> Vec<(String, String)> {
+    let mut parsed = vec![];
+    let mut chars = params.chars().peekable();
+
+    loop {
+        let key: String = chars
+            .by_ref()
+            .skip_while(|c| *c == ',' || c.is_whitespace())
+            .take_while(|c| *c != '=')
+            .collect();
+        if key.is_empty() {
+            break;
+        }
+
+        let mut val = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => val.extend(chars.next()),
+                    '"' => break,
+                    c => val.push(c),
+                }
+            }
+        } else {
+            val = chars.by_ref().take_while(|c| *c != ',').collect();
+        }
+        parsed.push((key.trim().to_lowercase(), val.trim().to_owned()));
+    }
+
+    parsed
+}
+
+/// Finds a Digest challenge in the `WWW-Authenticate` headers of a response.
+/// Fails if the challenge requires the `auth-int` quality of protection,
+/// which is not supported.
+pub fn parse_challenge(headers: &HeaderMap) -> Result<Option<Challenge>> {
+    let challenge = headers
+        .get_all(reqwest::header::WWW_AUTHENTICATE)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .find(|val| val.len() > 7 && val[..7].eq_ignore_ascii_case("digest "));
+    let params = match challenge {
+        Some(challenge) => parse_params(&challenge[7..]),
+        None => return Ok(None),
+    };
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, val)| val.to_owned())
+    };
+
+    let qop = param("qop");
+    let has_qop = qop
+        .as_ref()
+        .map(|qop| qop.split(',').any(|qop| qop.trim() == "auth"))
+        .unwrap_or(false);
+    if qop.is_some() && !has_qop {
+        return Err(format!(
+            "Server requires Digest authentication with qop={:?}, only \"auth\" is supported",
+            qop.unwrap_or_default()
+        )
+        .into());
+    }
+    let nonce = match param("nonce") {
+        Some(nonce) => nonce,
+        None => return Ok(None),
+    };
+
+    Ok(Some(Challenge {
+        realm: param("realm").unwrap_or_default(),
+        nonce,
+        opaque: param("opaque"),
+        algorithm: param("algorithm").unwrap_or_else(|| String::from("MD5")),
+        has_qop,
+        nonce_count: 0,
+    }))
+}
+
+/// Keeps the challenge for the next requests of the client.
+pub fn set_challenge(client: &Client, challenge: Challenge) {
+    if let Ok(mut prev) = client.digest_challenge.lock() {
+        *prev = Some(challenge);
+    }
+}
+
+/// Checks if the client received a challenge.
+pub fn has_challenge(client: &Client) -> bool {
+    client
+        .digest_challenge
+        .lock()
+        .map(|challenge| challenge.is_some())
+        .unwrap_or(false)
+}
+
+/// Quotes a value as a quoted-string (RFC 7230, section 3.2.6).
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn hash(algorithm: &str, data: &str) -> String {
+    if algorithm.to_uppercase().starts_with("SHA-256") {
+        format!("{:x}", Sha256::digest(data.as_bytes()))
+    } else {
+        format!("{:x}", Md5::digest(data.as_bytes()))
+    }
+}
+
+/// Builds the `Authorization` header of a request from the last challenge
+/// of the client. Returns `None` if no challenge was received yet.
+pub fn authorization(client: &Client, login: &str, passwd: &str, req: &Request) -> Option<String> {
+    let mut guard = client.digest_challenge.lock().ok()?;
+    let challenge = guard.as_mut()?;
+
+    let uri = match req.url().query() {
+        Some(query) => format!("{}?{}", req.url().path(), query),
+        None => req.url().path().to_owned(),
+    };
+    let cnonce = Uuid::new_v4().to_simple().to_string();
+
+    Some(header(
+        challenge,
+        login,
+        passwd,
+        req.method(),
+        &uri,
+        &cnonce,
+    ))
+}
+
+fn header(
+    challenge: &mut Challenge,
+    login: &str,
+    passwd: &str,
+    method: &Method,
+    uri: &str,
+    cnonce: &str,
+) -> String {
+    challenge.nonce_count += 1;
+    let algorithm = challenge.algorithm.as_str();
+    let nc = format!("{:08x}", challenge.nonce_count);
+
+    let mut ha1 = hash(
+        algorithm,
+        &format!("{}:{}:{}", login, challenge.realm, passwd),
+    );
+    if algorithm.to_lowercase().ends_with("-sess") {
+        ha1 = hash(
+            algorithm,
+            &format!("{}:{}:{}", ha1, challenge.nonce, cnonce),
+        );
+    }
+    let ha2 = hash(algorithm, &format!("{}:{}", method, uri));
+    let response = if challenge.has_qop {
+        hash(
+            algorithm,
+            &format!("{}:{}:{}:{}:auth:{}", ha1, challenge.nonce, nc, cnonce, ha2),
+        )
+    } else {
+        hash(algorithm, &format!("{}:{}:{}", ha1, challenge.nonce, ha2))
+    };
+
+    let mut header = format!(
+        "Digest username={}, realm={}, nonce={}, uri={}, algorithm={}, response=\"{}\"",
+        quote(login),
+        quote(&challenge.realm),
+        quote(&challenge.nonce),
+        quote(uri),
+        algorithm,
+        response
+    );
+    if challenge.has_qop {
+        header.push_str(&format!(", qop=auth, nc={}, cnonce={}", nc, quote(cnonce)));
+    }
+    if let Some(ref opaque) = challenge.opaque {
+        header.push_str(&format!(", opaque={}", quote(opaque)));
+    }
+
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::{HeaderValue, WWW_AUTHENTICATE};
+
+    use super::*;
+
+    /// Challenges of the example of RFC 7616, section 3.9.1.
+    fn headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for algorithm in ["SHA-256", "MD5"].iter() {
+            let challenge = format!(
+                r#"Digest realm="http-auth@example.org", qop="auth, auth-int", algorithm={}, nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v", opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#,
+                algorithm
+            );
+            headers.append(WWW_AUTHENTICATE, HeaderValue::from_str(&challenge).unwrap());
+        }
+        headers
+    }
+
+    fn authorization(challenge: &mut Challenge, login: &str) -> Vec<(String, String)> {
+        let header = header(
+            challenge,
+            login,
+            "Circle of Life",
+            &Method::GET,
+            "/dir/index.html",
+            "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ",
+        );
+        parse_params(header.strip_prefix("Digest ").unwrap())
+    }
+
+    fn param<'a>(params: &'a [(String, String)], name: &str) -> &'a str {
+        params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, val)| val.as_str())
+            .unwrap()
+    }
+
+    #[test]
+    fn params() {
+        assert_eq!(
+            parse_params(r#"realm="a, \"b\"", nonce=c,qop="auth""#),
+            vec![
+                (String::from("realm"), String::from(r#"a, "b""#)),
+                (String::from("nonce"), String::from("c")),
+                (String::from("qop"), String::from("auth")),
+            ]
+        );
+    }
+
+    #[test]
+    fn rfc_7616_sha_256() {
+        let mut challenge = parse_challenge(&headers()).unwrap().unwrap();
+        let params = authorization(&mut challenge, "Mufasa");
+
+        assert_eq!(param(&params, "username"), "Mufasa");
+        assert_eq!(param(&params, "realm"), "http-auth@example.org");
+        assert_eq!(param(&params, "uri"), "/dir/index.html");
+        assert_eq!(param(&params, "algorithm"), "SHA-256");
+        assert_eq!(param(&params, "qop"), "auth");
+        assert_eq!(param(&params, "nc"), "00000001");
+        assert_eq!(
+            param(&params, "opaque"),
+            "FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS"
+        );
+        assert_eq!(
+            param(&params, "response"),
+            "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1"
+        );
+
+        let params = authorization(&mut challenge, "Mufasa");
+        assert_eq!(param(&params, "nc"), "00000002");
+    }
+
+    #[test]
+    fn rfc_7616_md5() {
+        let mut challenge = parse_challenge(&headers()).unwrap().unwrap();
+        challenge.algorithm = String::from("MD5");
+        let params = authorization(&mut challenge, "Mufasa");
+
+        assert_eq!(
+            param(&params, "response"),
+            "8ca523f5e9506fed4657c9700eebdbec"
+        );
+    }
+
+    #[test]
+    fn quoted_username() {
+        let mut challenge = parse_challenge(&headers()).unwrap().unwrap();
+        let params = authorization(&mut challenge, r#"Mu"fa\sa"#);
+
+        assert_eq!(param(&params, "username"), r#"Mu"fa\sa"#);
+    }
+
+    #[test]
+    fn auth_int_only() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            WWW_AUTHENTICATE,
+            HeaderValue::from_static(r#"Digest realm="r", nonce="n", qop="auth-int""#),
+        );
+
+        assert!(parse_challenge(&headers).is_err());
+        assert!(parse_challenge(&HeaderMap::new()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,119 @@
+use async_trait::async_trait;
+use error_chain::bail;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, path::PathBuf};
+
+use super::{
+    model::Card,
+    repository::{RemoteRepository, Result, ResultExt},
+};
+use crate::{
+    config::Config,
+    local::{
+        self,
+        dir::DirRepository as LocalDirRepository,
+        repository::{hash_card, LocalRepository},
+    },
+};
+
+/// Another directory of `.vcf` files, useful for directory-to-directory
+/// synchronization. Etags are the content hashes of the cards.
+pub struct DirRepository {
+    path: PathBuf,
+    repo: LocalDirRepository,
+}
+
+impl DirRepository {
+    pub fn new(config: &Config) -> Result<Self> {
+        let path = config
+            .remote_dir
+            .to_owned()
+            .chain_err(|| "Missing `remote-dir` config")?;
+
+        Ok(Self {
+            repo: LocalDirRepository::from_path(path.to_owned()),
+            path,
+        })
+    }
+
+    fn to_remote_card(card: local::model::Card) -> Card {
+        Card {
+            etag: hash_card(&card),
+            href: card.path.to_string_lossy().to_string(),
+            name: card.name,
+            date: card.date,
+            content: card.content,
+        }
+    }
+}
+
+#[async_trait]
+impl RemoteRepository for DirRepository {
+    async fn discover(&mut self) -> Result<String> {
+        if !self.path.is_dir() {
+            bail!("Remote dir {:?} does not exist", self.path);
+        }
+
+        Ok(self.path.to_string_lossy().to_string())
+    }
+
+    async fn change_token(&self) -> Result<String> {
+        let mut etags: Vec<_> = self
+            .list()
+            .await?
+            .into_iter()
+            .map(|(name, card)| format!("{} {}\n", name, card.etag))
+            .collect();
+        etags.sort();
+
+        Ok(format!("{:x}", Sha256::digest(etags.concat().as_bytes())))
+    }
+
+    async fn list(&self) -> Result<HashMap<String, Card>> {
+        let cards = self
+            .repo
+            .list()
+            .chain_err(|| format!("Could not list cards from {:?}", self.path))?;
+
+        Ok(cards
+            .into_iter()
+            .map(|(name, card)| (name, Self::to_remote_card(card)))
+            .collect())
+    }
+
+    async fn fetch(&self, href: &str) -> Result<Card> {
+        self.list()
+            .await?
+            .into_values()
+            .find(|card| card.href == href)
+            .chain_err(|| format!("Could not find card {}", href))
+    }
+
+    async fn put(&mut self, name: &str, content: &str, prev: Option<&Card>) -> Result<Card> {
+        let curr = self.list().await?.remove(name);
+        match (prev, curr) {
+            (Some(prev), Some(curr)) if prev.etag != curr.etag => {
+                bail!("Card {} changed in the meantime", name)
+            }
+            (None, Some(_)) => bail!("Card {} already exists", name),
+            _ => (),
+        }
+
+        let mut cards = HashMap::new();
+        cards.insert(name.to_owned(), content.to_owned());
+        self.repo
+            .write(&cards)
+            .chain_err(|| format!("Could not write card {}", name))?;
+
+        self.list()
+            .await?
+            .remove(name)
+            .chain_err(|| format!("Could not find card {} after writing it", name))
+    }
+
+    async fn delete(&mut self, card: &Card) -> Result<()> {
+        self.repo
+            .delete(&[card.name.to_owned()])
+            .chain_err(|| format!("Could not delete card {}", card.name))
+    }
+}
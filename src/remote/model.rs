@@ -4,5 +4,14 @@ use chrono::{DateTime, Utc};
 pub struct Card {
     pub etag: String,
     pub name: String,
+    pub href: String,
     pub date: DateTime<Utc>,
+    pub content: String,
+}
+
+/// Metadata of the remote collection.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    pub displayname: Option<String>,
+    pub color: Option<String>,
 }
@@ -0,0 +1,190 @@
+use log::{info, warn};
+use serde::Deserialize;
+
+use super::repository::{Client, Result, ResultExt};
+use crate::config::Config;
+
+/// Tokens obtained during the run. They are only kept in memory, a new
+/// access token is requested on the first request of each run.
+#[derive(Debug, Default)]
+pub struct Tokens {
+    access_token: Option<String>,
+    /// Refresh token sent by the server in place of the configured one.
+    refresh_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+}
+
+/// Returns the current access token of the client, if any.
+pub fn access_token(client: &Client) -> Option<String> {
+    client.oauth2_tokens.lock().ok()?.access_token.clone()
+}
+
+fn rotated_refresh_token(client: &Client) -> Option<String> {
+    client.oauth2_tokens.lock().ok()?.refresh_token.clone()
+}
+
+/// Requests a new access token from the token endpoint, using the refresh
+/// token grant (RFC 6749, section 6).
+///
+/// Servers may send a new refresh token along with the access token. It is
+/// used for the rest of the run, but cannot be written back to the command
+/// printing the configured one, so the user is warned to update it.
+pub async fn refresh(config: &Config, client: &Client) -> Result<String> {
+    let url = config
+        .oauth2_token_url
+        .as_ref()
+        .chain_err(|| "Missing `oauth2-token-url` in config")?;
+    let refresh_token = match rotated_refresh_token(client) {
+        Some(token) => token,
+        None => config
+            .oauth2_refresh_token()
+            .chain_err(|| "Could not retrieve OAuth2 refresh token")?,
+    };
+
+    let mut form = vec![
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token.as_str()),
+    ];
+    if let Some(ref id) = config.oauth2_client_id {
+        form.push(("client_id", id));
+    }
+    if let Some(ref secret) = config.oauth2_client_secret {
+        form.push(("client_secret", secret));
+    }
+
+    info!("Requesting a new OAuth2 access token");
+    let res = client
+        .post(url)
+        .form(&form)
+        .send()
+        .await
+        .chain_err(|| "Could not send OAuth2 token request")?;
+    let status = res.status();
+    let body = res
+        .text()
+        .await
+        .chain_err(|| "Could not extract text body from OAuth2 token response")?;
+    if !status.is_success() {
+        return Err(format!("OAuth2 token request failed with {}: {}", status, body).into());
+    }
+    let res: TokenResponse =
+        serde_json::from_str(&body).chain_err(|| "Could not parse OAuth2 token response")?;
+
+    let rotated = res
+        .refresh_token
+        .filter(|token| !token.is_empty() && *token != refresh_token);
+    if rotated.is_some() {
+        warn!(
+            "The OAuth2 server issued a new refresh token, only kept for this run: \
+             the one printed by `oauth2-refresh-token-cmd` may stop working"
+        );
+    }
+    if let Ok(mut tokens) = client.oauth2_tokens.lock() {
+        tokens.access_token = Some(res.access_token.to_owned());
+        if rotated.is_some() {
+            tokens.refresh_token = rotated;
+        }
+    }
+    Ok(res.access_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::remote::repository;
+
+    /// Serves the given token responses, one per connection, and records the
+    /// bodies of the requests.
+    async fn token_endpoint(responses: Vec<&'static str>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        let bodies = Arc::new(Mutex::new(vec![]));
+        let received = bodies.clone();
+
+        tokio::spawn(async move {
+            for res in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut req = vec![];
+                let mut buf = [0; 1024];
+                loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    req.extend_from_slice(&buf[..n]);
+                    let req = String::from_utf8_lossy(&req);
+                    if let Some(i) = req.find("\r\n\r\n") {
+                        let len = req
+                            .lines()
+                            .find_map(|line| {
+                                line.to_lowercase()
+                                    .strip_prefix("content-length: ")
+                                    .map(String::from)
+                            })
+                            .and_then(|len| len.trim().parse::<usize>().ok())
+                            .unwrap_or(0);
+                        if req.len() >= i + 4 + len {
+                            received.lock().unwrap().push(req[i + 4..].to_owned());
+                            break;
+                        }
+                    }
+                }
+                let res = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    res.len(),
+                    res
+                );
+                stream.write_all(res.as_bytes()).await.unwrap();
+            }
+        });
+
+        (url, bodies)
+    }
+
+    #[tokio::test]
+    async fn refresh_grant() {
+        let (url, bodies) = token_endpoint(vec![
+            r#"{"access_token":"at-1","token_type":"Bearer"}"#,
+            r#"{"access_token":"at-2","refresh_token":"rt-2"}"#,
+            r#"{"access_token":"at-3"}"#,
+        ])
+        .await;
+        let config: Config = toml::from_str(&format!(
+            r#"
+            sync-dir = "/tmp"
+            auth = "oauth2"
+            oauth2-token-url = "{}"
+            oauth2-client-id = "cardamom"
+            oauth2-refresh-token-cmd = "echo rt-1"
+            "#,
+            url
+        ))
+        .unwrap();
+        let client = repository::client(&config).unwrap();
+
+        assert_eq!(access_token(&client), None);
+        assert_eq!(refresh(&config, &client).await.unwrap(), "at-1");
+        assert_eq!(access_token(&client).as_deref(), Some("at-1"));
+        assert_eq!(refresh(&config, &client).await.unwrap(), "at-2");
+        assert_eq!(refresh(&config, &client).await.unwrap(), "at-3");
+
+        assert_eq!(
+            *bodies.lock().unwrap(),
+            vec![
+                "grant_type=refresh_token&refresh_token=rt-1&client_id=cardamom",
+                "grant_type=refresh_token&refresh_token=rt-1&client_id=cardamom",
+                "grant_type=refresh_token&refresh_token=rt-2&client_id=cardamom",
+            ]
+        );
+        let other_client = repository::client(&config).unwrap();
+        assert_eq!(access_token(&other_client), None);
+    }
+}
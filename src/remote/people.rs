@@ -0,0 +1,584 @@
+//! REST backend in the style of the Google People API: contacts are JSON
+//! persons, listed from `people/me/connections` and converted from and to
+//! vCard. Only the fields the API has in common with vCard are synchronized
+//! (names, emails, phones, organizations, URLs and notes), the other
+//! properties of the local cards are kept when persons are downloaded. The
+//! UID of the cards is kept in the client data of the persons.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use reqwest::{header, Method};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use super::{
+    dav,
+    model::Card,
+    repository::{self, Client, RemoteRepository, Result, ResultExt},
+};
+use crate::{
+    config::Config,
+    convert,
+    vcard::{self, Property},
+};
+
+/// Fields of the persons that are read.
+const PERSON_FIELDS: &str =
+    "names,emailAddresses,phoneNumbers,organizations,urls,biographies,clientData,metadata";
+
+/// Fields of the persons that are replaced on update.
+const UPDATE_PERSON_FIELDS: &str =
+    "names,emailAddresses,phoneNumbers,organizations,urls,biographies,clientData";
+
+/// Properties of the cards stored in the persons. The other ones are only
+/// kept locally.
+const PERSON_PROPS: &[&str] = &["FN", "N", "EMAIL", "TEL", "ORG", "TITLE", "URL", "NOTE"];
+
+/// Key of the client data holding the UID of the card.
+const UID_KEY: &str = "uid";
+
+// Person structs
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Person {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resource_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    names: Vec<Name>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    email_addresses: Vec<Field>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    phone_numbers: Vec<Field>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    organizations: Vec<Organization>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    urls: Vec<Field>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    biographies: Vec<Field>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    client_data: Vec<ClientData>,
+    #[serde(skip_serializing)]
+    metadata: Option<PersonMetadata>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Name {
+    #[serde(skip_serializing)]
+    display_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unstructured_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    family_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    given_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    middle_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    honorific_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    honorific_suffix: Option<String>,
+}
+
+/// Email address, phone number, URL or biography.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Field {
+    #[serde(default)]
+    value: String,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    kind: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Organization {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    department: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ClientData {
+    key: String,
+    value: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PersonMetadata {
+    #[serde(default)]
+    sources: Vec<Source>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Source {
+    update_time: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Connections {
+    #[serde(default)]
+    connections: Vec<Person>,
+    next_page_token: Option<String>,
+}
+
+// Conversion fns
+
+fn non_empty(text: String) -> Option<String> {
+    Some(text).filter(|text| !text.trim().is_empty())
+}
+
+/// Maps a vCard type to a person field type, and back.
+fn field_type(types: &[String]) -> Option<String> {
+    types
+        .iter()
+        .find(|t| !matches!(t.as_str(), "internet" | "pref" | "voice"))
+        .map(|t| match t.as_str() {
+            "cell" => String::from("mobile"),
+            t => t.to_owned(),
+        })
+}
+
+fn vcard_type(kind: &str) -> String {
+    match kind.to_lowercase().as_str() {
+        "mobile" => String::from("cell"),
+        kind => kind.to_owned(),
+    }
+}
+
+/// Builds a person from a card.
+fn to_person(content: &str) -> Person {
+    let props = vcard::parse(content);
+    let find = |name: &str| props.iter().find(|prop| prop.name == name);
+    let fields = |name: &str| -> Vec<Field> {
+        props
+            .iter()
+            .filter(|prop| prop.name == name)
+            .filter_map(|prop| {
+                Some(Field {
+                    value: non_empty(prop.text())?,
+                    kind: field_type(&prop.types()),
+                })
+            })
+            .collect()
+    };
+
+    let n = find("N").map(Property::components).unwrap_or_default();
+    let n = |i: usize| n.get(i).cloned().and_then(non_empty);
+    let name = Name {
+        unstructured_name: find("FN").map(Property::text).and_then(non_empty),
+        family_name: n(0),
+        given_name: n(1),
+        middle_name: n(2),
+        honorific_prefix: n(3),
+        honorific_suffix: n(4),
+        ..Name::default()
+    };
+
+    let org = find("ORG").map(Property::components).unwrap_or_default();
+    let org = Organization {
+        name: org.first().cloned().and_then(non_empty),
+        department: org.get(1).cloned().and_then(non_empty),
+        title: find("TITLE").map(Property::text).and_then(non_empty),
+    };
+
+    Person {
+        names: vec![name],
+        email_addresses: fields("EMAIL"),
+        phone_numbers: fields("TEL"),
+        organizations: vec![org]
+            .into_iter()
+            .filter(|org| org.name.is_some() || org.department.is_some() || org.title.is_some())
+            .collect(),
+        urls: fields("URL"),
+        biographies: fields("NOTE")
+            .into_iter()
+            .map(|note| Field { kind: None, ..note })
+            .collect(),
+        client_data: vcard::uid(content)
+            .map(|uid| ClientData {
+                key: String::from(UID_KEY),
+                value: vcard::unescape(&uid),
+            })
+            .into_iter()
+            .collect(),
+        ..Person::default()
+    }
+}
+
+/// Builds a vCard 3.0 from a person. Persons created elsewhere have no UID,
+/// their resource name is used instead.
+fn to_vcard(person: &Person) -> String {
+    let mut props = vec![Property::new("VERSION", "3.0")];
+    let text_prop = |name: &str, text: &str| Property::new(name, &vcard::escape(text));
+    let typed_prop = |name: &str, field: &Field| {
+        let mut prop = text_prop(name, &field.value);
+        if let Some(ref kind) = field.kind {
+            prop.params.push((String::from("TYPE"), vcard_type(kind)));
+        }
+        prop
+    };
+
+    let uid = person
+        .client_data
+        .iter()
+        .find(|data| data.key == UID_KEY)
+        .map(|data| data.value.to_owned())
+        .or_else(|| {
+            let name = person.resource_name.as_ref()?;
+            Some(name.rsplit('/').next().unwrap_or(name).to_owned())
+        });
+    if let Some(uid) = uid {
+        props.push(text_prop("UID", &uid));
+    }
+
+    let name = person.names.first();
+    let full_name = name
+        .and_then(|name| {
+            name.display_name
+                .to_owned()
+                .or_else(|| name.unstructured_name.to_owned())
+        })
+        .unwrap_or_default();
+    props.push(text_prop("FN", &full_name));
+    if let Some(name) = name {
+        let parts = [
+            &name.family_name,
+            &name.given_name,
+            &name.middle_name,
+            &name.honorific_prefix,
+            &name.honorific_suffix,
+        ];
+        let n: Vec<_> = parts
+            .iter()
+            .map(|part| vcard::escape(part.as_deref().unwrap_or_default()))
+            .collect();
+        props.push(Property::new("N", &n.join(";")));
+    }
+
+    for email in person.email_addresses.iter() {
+        props.push(typed_prop("EMAIL", email));
+    }
+    for phone in person.phone_numbers.iter() {
+        props.push(typed_prop("TEL", phone));
+    }
+    if let Some(org) = person.organizations.first() {
+        if org.name.is_some() || org.department.is_some() {
+            let parts = [&org.name, &org.department];
+            let value: Vec<_> = parts
+                .iter()
+                .map(|part| vcard::escape(part.as_deref().unwrap_or_default()))
+                .collect();
+            props.push(Property::new("ORG", value.join(";").trim_end_matches(';')));
+        }
+        if let Some(ref title) = org.title {
+            props.push(text_prop("TITLE", title));
+        }
+    }
+    for url in person.urls.iter() {
+        props.push(Property::new("URL", &url.value));
+    }
+    for bio in person.biographies.iter() {
+        props.push(text_prop("NOTE", &bio.value));
+    }
+
+    vcard::build(&props)
+}
+
+/// Replaces the properties of a local card stored in the persons with the
+/// ones of a downloaded card, converted to the version of the local card.
+pub fn merge(local: &str, remote: &str) -> String {
+    let remote = match vcard::version(local) {
+        Some(version) if vcard::version(remote) != Some(version) => {
+            convert::convert(remote, version)
+        }
+        _ => remote.to_owned(),
+    };
+    let is_person_prop = |prop: &Property| PERSON_PROPS.contains(&prop.name.as_str());
+
+    let mut props: Vec<_> = vcard::parse(local)
+        .into_iter()
+        .filter(|prop| !is_person_prop(prop) && prop.name != "BEGIN" && prop.name != "END")
+        .collect();
+    props.extend(vcard::parse(&remote).into_iter().filter(is_person_prop));
+    vcard::build(&props)
+}
+
+/// Content of a card as stored in a person.
+#[cfg(test)]
+pub fn stored(content: &str) -> String {
+    to_vcard(&to_person(content))
+}
+
+fn to_card(person: Person) -> Result<Card> {
+    let href = person
+        .resource_name
+        .to_owned()
+        .chain_err(|| "Missing resource name of person")?;
+    let content = to_vcard(&person);
+    let date = person
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.sources.iter().find_map(|s| s.update_time.as_ref()))
+        .and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+        .map(|date| date.with_timezone(&Utc))
+        .unwrap_or_else(Utc::now);
+
+    Ok(Card {
+        etag: person.etag.unwrap_or_default(),
+        name: repository::card_name(&href, &content).unwrap_or_default(),
+        href,
+        date,
+        content,
+    })
+}
+
+/// Contacts of a People API–style REST service, authenticated with OAuth2.
+/// The service root is `remote-path` (`/v1` by default).
+pub struct PeopleRepository<'a> {
+    config: &'a Config,
+    client: &'a Client,
+    path: String,
+}
+
+impl<'a> PeopleRepository<'a> {
+    pub fn new(config: &'a Config, client: &'a Client) -> Self {
+        let path = config
+            .remote_path
+            .to_owned()
+            .unwrap_or_else(|| String::from("/v1"));
+
+        Self {
+            config,
+            client,
+            path: path.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    fn url_path(&self, path: &str) -> String {
+        format!("{}/{}", self.path, path)
+    }
+
+    /// Sends a request and parses the JSON person it answers with.
+    async fn send_person(
+        &self,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+        person: Option<&Person>,
+    ) -> Result<Person> {
+        let mut req = dav::request(self.config, self.client, method, &self.url_path(path))?
+            .query(&[("personFields", PERSON_FIELDS)])
+            .query(query);
+        if let Some(person) = person {
+            let body = serde_json::to_string(person).chain_err(|| "Could not serialize person")?;
+            req = req
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(body);
+        }
+        let res = dav::send(self.config, self.client, req)
+            .await
+            .chain_err(|| format!("Could not send request for {}", path))?;
+        let status = res.status();
+        let body = res
+            .text()
+            .await
+            .chain_err(|| format!("Could not extract text body from {} response", path))?;
+        if !status.is_success() {
+            return Err(format!("Request for {} failed with {}: {}", path, status, body).into());
+        }
+
+        serde_json::from_str(&body).chain_err(|| format!("Could not parse {} response", path))
+    }
+
+    /// Lists all the persons, page by page.
+    async fn fetch_persons(&self) -> Result<Vec<Person>> {
+        let mut persons = vec![];
+        let mut page_token = None;
+
+        loop {
+            let path = self.url_path("people/me/connections");
+            let mut req = dav::request(self.config, self.client, Method::GET, &path)?
+                .query(&[("personFields", PERSON_FIELDS), ("pageSize", "1000")]);
+            if let Some(ref token) = page_token {
+                req = req.query(&[("pageToken", token)]);
+            }
+            let res = dav::send(self.config, self.client, req)
+                .await
+                .chain_err(|| "Could not send connections request")?
+                .error_for_status()
+                .chain_err(|| "Could not list connections")?
+                .text()
+                .await
+                .chain_err(|| "Could not extract text body from connections response")?;
+            let res: Connections =
+                serde_json::from_str(&res).chain_err(|| "Could not parse connections response")?;
+
+            persons.extend(res.connections);
+            page_token = match res.next_page_token {
+                Some(token) if !token.is_empty() => Some(token),
+                _ => return Ok(persons),
+            };
+        }
+    }
+}
+
+#[async_trait]
+impl RemoteRepository for PeopleRepository<'_> {
+    async fn discover(&mut self) -> Result<String> {
+        Ok(self.path.to_owned())
+    }
+
+    /// The token is built from the etags of all the persons.
+    async fn change_token(&self) -> Result<String> {
+        let mut etags: Vec<_> = self
+            .fetch_persons()
+            .await?
+            .into_iter()
+            .map(|person| {
+                format!(
+                    "{} {}\n",
+                    person.resource_name.unwrap_or_default(),
+                    person.etag.unwrap_or_default()
+                )
+            })
+            .collect();
+        etags.sort();
+
+        Ok(format!("{:x}", Sha256::digest(etags.concat().as_bytes())))
+    }
+
+    async fn list(&self) -> Result<HashMap<String, Card>> {
+        let mut cards = HashMap::new();
+        for person in self.fetch_persons().await? {
+            let card = to_card(person)?;
+            cards.insert(card.name.to_owned(), card);
+        }
+
+        Ok(cards)
+    }
+
+    async fn fetch(&self, href: &str) -> Result<Card> {
+        to_card(self.send_person(Method::GET, href, &[], None).await?)
+    }
+
+    /// Updates are rejected by the service if the person changed since its
+    /// etag was read.
+    async fn put(&mut self, _name: &str, content: &str, prev: Option<&Card>) -> Result<Card> {
+        let mut person = to_person(content);
+        let person = match prev {
+            Some(prev) => {
+                person.etag = Some(prev.etag.to_owned());
+                let path = format!("{}:updateContact", prev.href);
+                let query = [("updatePersonFields", UPDATE_PERSON_FIELDS)];
+                self.send_person(Method::PATCH, &path, &query, Some(&person))
+                    .await?
+            }
+            None => {
+                let path = "people:createContact";
+                self.send_person(Method::POST, path, &[], Some(&person))
+                    .await?
+            }
+        };
+
+        to_card(person)
+    }
+
+    async fn delete(&mut self, card: &Card) -> Result<()> {
+        let path = self.url_path(&format!("{}:deleteContact", card.href));
+        let req = dav::request(self.config, self.client, Method::DELETE, &path)?;
+        dav::send(self.config, self.client, req)
+            .await
+            .chain_err(|| format!("Could not send delete request for {}", card.href))?
+            .error_for_status()
+            .chain_err(|| format!("Could not delete person {}", card.href))?;
+
+        Ok(())
+    }
+
+    fn merge(&self, local: &str, remote: &str) -> String {
+        merge(local, remote)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CARD: &str = "BEGIN:VCARD\r\n\
+        VERSION:3.0\r\n\
+        UID:abc\r\n\
+        FN:Jane Doe\r\n\
+        N:Doe;Jane;;Dr.;\r\n\
+        EMAIL;TYPE=work:jane@example.com\r\n\
+        TEL;TYPE=cell:+33 6 00 00 00 00\r\n\
+        ORG:Example\\, Inc.;R&D\r\n\
+        TITLE:Engineer\r\n\
+        URL:https://example.com\r\n\
+        NOTE:First line\\nSecond line\r\n\
+        END:VCARD\r\n";
+
+    #[test]
+    fn person_from_card() {
+        let person = to_person(CARD);
+        let name = &person.names[0];
+
+        assert_eq!(name.unstructured_name.as_deref(), Some("Jane Doe"));
+        assert_eq!(name.family_name.as_deref(), Some("Doe"));
+        assert_eq!(name.given_name.as_deref(), Some("Jane"));
+        assert_eq!(name.honorific_prefix.as_deref(), Some("Dr."));
+        assert_eq!(person.email_addresses[0].value, "jane@example.com");
+        assert_eq!(person.email_addresses[0].kind.as_deref(), Some("work"));
+        assert_eq!(person.phone_numbers[0].kind.as_deref(), Some("mobile"));
+        assert_eq!(
+            person.organizations[0].name.as_deref(),
+            Some("Example, Inc.")
+        );
+        assert_eq!(person.organizations[0].department.as_deref(), Some("R&D"));
+        assert_eq!(person.organizations[0].title.as_deref(), Some("Engineer"));
+        assert_eq!(person.biographies[0].value, "First line\nSecond line");
+        assert_eq!(person.client_data[0].value, "abc");
+    }
+
+    #[test]
+    fn card_round_trip() {
+        let mut person = to_person(CARD);
+        person.resource_name = Some(String::from("people/c1"));
+        person.etag = Some(String::from("%Eg"));
+        let json = serde_json::to_string(&person).unwrap();
+        let person: Person = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(to_vcard(&person), CARD);
+        let card = to_card(person).unwrap();
+        assert_eq!(card.name, "abc");
+        assert_eq!(card.href, "people/c1");
+        assert_eq!(card.etag, "%Eg");
+    }
+
+    #[test]
+    fn card_from_foreign_person() {
+        let person: Person = serde_json::from_str(
+            r#"{
+                "resourceName": "people/c42",
+                "etag": "%Eh",
+                "names": [{"displayName": "John Smith", "givenName": "John", "familyName": "Smith"}],
+                "phoneNumbers": [{"value": "123", "type": "home"}]
+            }"#,
+        )
+        .unwrap();
+        let card = to_card(person).unwrap();
+
+        assert_eq!(card.name, "c42");
+        assert_eq!(
+            card.content,
+            "BEGIN:VCARD\r\nVERSION:3.0\r\nUID:c42\r\nFN:John Smith\r\nN:Smith;John;;;\r\nTEL;TYPE=home:123\r\nEND:VCARD\r\n"
+        );
+    }
+}
@@ -1,403 +1,134 @@
-use chrono::{DateTime, Utc};
+use async_trait::async_trait;
 use error_chain::error_chain;
-use quick_xml::de as xml;
-use reqwest::{Client, Method};
-use serde::Deserialize;
-use std::{collections::HashMap, fs, path::PathBuf};
-
-use super::model::Card;
-use crate::config::Config;
+use reqwest::Proxy;
+use std::{collections::HashMap, ops::Deref, path::PathBuf, sync::Mutex};
+
+use super::{
+    carddav::CardDavRepository,
+    digest::Challenge,
+    dir::DirRepository,
+    model::{Card, Metadata},
+    oauth2::Tokens,
+    people::PeopleRepository,
+    tls,
+    webdav::WebDavRepository,
+};
+use crate::{
+    config::{Config, RemoteKind},
+    vcard,
+};
 
 error_chain! {}
 
-// Common structs
-
-#[derive(Debug, Deserialize)]
-pub struct Multistatus<T> {
-    #[serde(rename = "response")]
-    pub responses: Vec<Response<T>>,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct Response<T> {
-    pub href: Href,
-    pub propstat: Propstat<T>,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct Propstat<T> {
-    pub prop: T,
-    pub status: Option<Status>,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct Href {
-    #[serde(rename = "$value")]
-    pub value: String,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct Status {
-    #[serde(rename = "$value")]
-    pub value: String,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct Ctag {
-    #[serde(rename = "$value")]
-    pub value: String,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct Etag {
-    #[serde(rename = "$value")]
-    pub value: String,
-}
+/// Source of the remote cards.
+#[async_trait]
+pub trait RemoteRepository: Send + Sync {
+    /// Locates the collection holding the cards, and returns its path.
+    async fn discover(&mut self) -> Result<String>;
 
-#[derive(Debug, Deserialize)]
-pub struct LastModified {
-    #[serde(with = "date_parser", rename = "$value")]
-    pub value: DateTime<Utc>,
-}
+    /// Returns a token that changes whenever a card of the collection
+    /// changes (a ctag for CardDAV).
+    async fn change_token(&self) -> Result<String>;
 
-mod date_parser {
-    use chrono::{DateTime, Utc};
-    use serde::{self, Deserialize, Deserializer};
+    /// Lists all the cards, indexed by name.
+    async fn list(&self) -> Result<HashMap<String, Card>>;
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let s = String::deserialize(deserializer)?;
-        DateTime::parse_from_rfc2822(&s)
-            .map(|d| d.into())
-            .map_err(serde::de::Error::custom)
+    /// Checks if the last listing skipped cards that could not be read. Cards
+    /// missing from a partial listing are not considered deleted.
+    fn is_partial(&self) -> bool {
+        false
     }
-}
-
-// Current user principal structs
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "kebab-case")]
-struct CurrentUserPrincipalProp {
-    pub current_user_principal: CurrentUserPrincipal,
-}
 
-#[derive(Debug, Deserialize)]
-struct CurrentUserPrincipal {
-    pub href: Href,
-}
-
-// Addressbook home set structs
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "kebab-case")]
-struct AddressbookHomeSetProp {
-    pub addressbook_home_set: AddressbookHomeSet,
-}
-
-#[derive(Debug, Deserialize)]
-struct AddressbookHomeSet {
-    pub href: Href,
-}
-
-// Addressbook structs
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "kebab-case")]
-struct AddressbookProp {
-    pub resourcetype: AddressbookResourceType,
-}
-
-#[derive(Debug, Deserialize)]
-struct AddressbookResourceType {
-    pub addressbook: Option<Addressbook>,
-}
+    async fn fetch(&self, href: &str) -> Result<Card>;
 
-#[derive(Debug, Deserialize)]
-struct Addressbook {}
+    /// Puts the content of a card. When a previous version is given, the card
+    /// is only updated if it did not change in between, otherwise it is only
+    /// created if it does not exist yet. Returns the card as stored.
+    async fn put(&mut self, name: &str, content: &str, prev: Option<&Card>) -> Result<Card>;
 
-// Address data structs
+    async fn delete(&mut self, card: &Card) -> Result<()>;
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "kebab-case")]
-pub struct AddressDataProp {
-    pub address_data: AddressData,
-    pub getetag: Etag,
-    pub getlastmodified: LastModified,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct AddressData {
-    #[serde(rename = "$value")]
-    pub value: String,
-}
-
-// Ctag structs
-
-#[derive(Debug, Deserialize)]
-pub struct CtagProp {
-    pub getctag: Ctag,
-}
-
-// Methods
-
-fn propfind() -> Result<Method> {
-    Method::from_bytes(b"PROPFIND").chain_err(|| "Could not create custom method PROPFIND")
-}
-
-fn report() -> Result<Method> {
-    Method::from_bytes(b"REPORT").chain_err(|| "Could not create custom method REPORT")
-}
-
-// Fetch URL fns
-
-async fn fetch_current_user_principal_url(
-    config: &Config,
-    client: &Client,
-    path: String,
-) -> Result<String> {
-    let res = client
-        .request(propfind()?, &config.url(&path))
-        .basic_auth(
-            &config.login,
-            Some(
-                config
-                    .passwd()
-                    .chain_err(|| "Could not retrieve password")?,
-            ),
-        )
-        .body(
-            r#"
-            <D:propfind xmlns:D="DAV:">
-                <D:prop>
-                    <D:current-user-principal />
-                </D:prop>
-            </D:propfind>
-            "#,
-        )
-        .send()
-        .await
-        .chain_err(|| "Could not send current user principal request")?;
-    let res = res
-        .text()
-        .await
-        .chain_err(|| "Could not extract text body from current user principal response")?;
-    let res: Multistatus<CurrentUserPrincipalProp> =
-        xml::from_str(&res).chain_err(|| "Could not parse current user principal response")?;
-
-    Ok(res
-        .responses
-        .first()
-        .map(|res| {
-            res.propstat
-                .prop
-                .current_user_principal
-                .href
-                .value
-                .to_owned()
-        })
-        .unwrap_or(path))
-}
-
-async fn fetch_addressbook_home_set_url(
-    config: &Config,
-    client: &Client,
-    path: String,
-) -> Result<String> {
-    let res = client
-        .request(propfind()?, &config.url(&path))
-        .basic_auth(
-            &config.login,
-            Some(
-                config
-                    .passwd()
-                    .chain_err(|| "Could not retrieve password")?,
-            ),
-        )
-        .body(
-            r#"
-            <D:propfind xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:carddav">
-                <D:prop>
-                    <C:addressbook-home-set />
-                </D:prop>
-            </D:propfind>
-            "#,
-        )
-        .send()
-        .await
-        .chain_err(|| "Could not send addressbook home set request")?;
-    let res = res
-        .text()
-        .await
-        .chain_err(|| "Could not extract text body from addressbook home set response")?;
-    let res: Multistatus<AddressbookHomeSetProp> =
-        xml::from_str(&res).chain_err(|| "Could not parse addressbook home set response")?;
+    /// Builds the content of a downloaded card replacing a local one. Remotes
+    /// storing only some properties merge them into the local card.
+    fn merge(&self, _local: &str, remote: &str) -> String {
+        remote.to_owned()
+    }
 
-    Ok(res
-        .responses
-        .first()
-        .map(|res| res.propstat.prop.addressbook_home_set.href.value.to_owned())
-        .unwrap_or(path))
+    /// Fetches the metadata of the collection (display name, color).
+    async fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata::default())
+    }
 }
 
-async fn fetch_addressbook_url(config: &Config, client: &Client, path: String) -> Result<String> {
-    let res = client
-        .request(propfind()?, &config.url(&path))
-        .basic_auth(
-            &config.login,
-            Some(
-                config
-                    .passwd()
-                    .chain_err(|| "Could not retrieve password")?,
-            ),
-        )
-        .send()
-        .await
-        .chain_err(|| "Could not send addressbook request")?;
-    let res = res
-        .text()
-        .await
-        .chain_err(|| "Could not extract text body from addressbook response")?;
-    let res: Multistatus<AddressbookProp> =
-        xml::from_str(&res).chain_err(|| "Could not parse addressbook response")?;
-
-    Ok(res
-        .responses
-        .iter()
-        .find(|res| {
-            let valid_status = res
-                .propstat
-                .status
-                .as_ref()
-                .map(|s| s.value.ends_with("200 OK"))
-                .unwrap_or(false);
-            let has_addressbook = res
-                .propstat
-                .prop
-                .resourcetype
-                .addressbook
-                .as_ref()
-                .is_some();
-
-            valid_status && has_addressbook
-        })
-        .map(|res| res.href.value.to_owned())
-        .unwrap_or(path))
+/// HTTP client used by the remote repositories, along with the credentials
+/// obtained while sending its requests.
+#[derive(Debug)]
+pub struct Client {
+    http: reqwest::Client,
+    pub(super) digest_challenge: Mutex<Option<Challenge>>,
+    pub(super) oauth2_tokens: Mutex<Tokens>,
 }
 
-pub async fn fetch_and_write_cards(
-    config: &Config,
-    client: &Client,
-    path: &str,
-) -> Result<HashMap<String, Card>> {
-    let res = client
-        .request(report()?, &config.url(&path))
-        .basic_auth(
-            &config.login,
-            Some(
-                config
-                    .passwd()
-                    .chain_err(|| "Could not retrieve password")?,
-            ),
-        )
-        .header("Depth", "1")
-        .body(
-            r#"
-            <C:addressbook-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:carddav">
-                <D:prop>
-                    <D:getetag />
-                    <D:getlastmodified />
-                    <C:address-data />
-                </D:prop>
-            </C:addressbook-query>
-            "#,
-        )
-        .send()
-        .await
-        .chain_err(|| "Could not send address data request")?
-        .text()
-        .await
-        .chain_err(|| "Could not extract text body from address data response")?;
-    let res: Multistatus<AddressDataProp> =
-        xml::from_str(&res).chain_err(|| "Could not parse address data response")?;
-
-    let cards = res
-        .responses
-        .iter()
-        .filter_map(|res| {
-            let card = Card {
-                etag: res.propstat.prop.getetag.value.to_owned(),
-                name: PathBuf::from(&res.href.value)
-                    .file_stem()?
-                    .to_string_lossy()
-                    .to_string(),
-                date: res.propstat.prop.getlastmodified.value,
-            };
-
-            let path = config.file_path(&format!("{}.vcf", &card.name));
-            let content = res.propstat.prop.address_data.value.trim_end_matches("\r");
-            fs::write(&path, &content).ok()?;
-
-            Some(card)
-        })
-        .fold(HashMap::new(), |mut cards, card| {
-            cards.insert(card.name.to_owned(), card);
-            cards
-        });
+impl Deref for Client {
+    type Target = reqwest::Client;
 
-    Ok(cards)
+    fn deref(&self) -> &Self::Target {
+        &self.http
+    }
 }
 
-pub async fn fetch_ctag(config: &Config, client: &Client, path: &str) -> Result<String> {
-    let res = client
-        .request(propfind()?, &config.url(&path))
-        .basic_auth(
-            &config.login,
-            Some(
-                config
-                    .passwd()
-                    .chain_err(|| "Could not retrieve password")?,
-            ),
-        )
-        .header("Depth", "0")
-        .body(
-            r#"
-            <D:propfind xmlns:D="DAV:" xmlns:C="http://calendarserver.org/ns/">
-                <D:prop>
-                    <C:getctag />
-                </D:prop>
-            </D:propfind>
-            "#,
-        )
-        .send()
-        .await
-        .chain_err(|| "Could not send ctag request")?
-        .text()
-        .await
-        .chain_err(|| "Could not extract text body from ctag response")?;
-    let res: Multistatus<CtagProp> =
-        xml::from_str(&res).chain_err(|| "Could not parse ctag response")?;
-
-    Ok(res
-        .responses
-        .iter()
-        .find(|res| {
-            res.propstat
-                .status
-                .as_ref()
-                .map(|s| s.value.ends_with("200 OK"))
-                .unwrap_or(false)
-        })
-        .map(|res| res.propstat.prop.getctag.value.to_owned())
-        .unwrap_or_default())
-}
+/// Builds the HTTP client used by the remote repositories.
+pub fn client(config: &Config) -> Result<Client> {
+    let mut builder = tls::configure(config, reqwest::Client::builder())?;
 
-pub async fn addressbook_path(config: &Config, client: &Client) -> Result<String> {
-    let path = String::from("/");
-    let path = fetch_current_user_principal_url(&config, &client, path).await?;
-    let path = fetch_addressbook_home_set_url(&config, &client, path).await?;
-    let path = fetch_addressbook_url(&config, &client, path).await?;
+    if let Some(ref url) = config.proxy {
+        let proxy = Proxy::all(url).chain_err(|| format!("Invalid proxy {:?}", url))?;
+        builder = builder.proxy(proxy);
+    }
+    if let Some(timeout) = config.connect_timeout() {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = config.timeout() {
+        builder = builder.timeout(timeout);
+    }
 
-    Ok(path)
+    let http = builder
+        .build()
+        .chain_err(|| "Could not build HTTP client")?;
+
+    Ok(Client {
+        http,
+        digest_challenge: Mutex::new(None),
+        oauth2_tokens: Mutex::new(Tokens::default()),
+    })
+}
+
+/// Builds the remote repository matching the configured kind, and runs its
+/// discovery.
+pub async fn from_config<'a>(
+    config: &'a Config,
+    client: &'a Client,
+) -> Result<Box<dyn RemoteRepository + 'a>> {
+    let mut repo: Box<dyn RemoteRepository + 'a> = match config.remote() {
+        RemoteKind::Carddav => Box::new(CardDavRepository::new(config, client)),
+        RemoteKind::Webdav => Box::new(WebDavRepository::new(config, client)),
+        RemoteKind::Dir => Box::new(DirRepository::new(config)?),
+        RemoteKind::People => Box::new(PeopleRepository::new(config, client)),
+    };
+    repo.discover().await?;
+
+    Ok(repo)
+}
+
+/// Names a remote card by its UID, or by its file stem if it has no UID.
+pub fn card_name(href: &str, content: &str) -> Option<String> {
+    match vcard::uid(content) {
+        Some(uid) => Some(uid),
+        None => Some(
+            PathBuf::from(href)
+                .file_stem()?
+                .to_string_lossy()
+                .to_string(),
+        ),
+    }
 }
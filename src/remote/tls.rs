@@ -0,0 +1,193 @@
+//! TLS options of the HTTP client: custom CA bundle, client certificate,
+//! certificate pinning and insecure mode. As soon as one of them is set, the
+//! client uses rustls instead of the system TLS library. It trusts the
+//! system root certificates as well as the Mozilla ones bundled by
+//! `webpki-roots`.
+
+use log::warn;
+use reqwest::{Certificate, ClientBuilder, Identity};
+use rustls::{
+    internal::pemfile, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier,
+    TLSError,
+};
+use sha2::{Digest, Sha256};
+use std::{fs, io::BufReader, path::Path, sync::Arc};
+
+use super::repository::{Result, ResultExt};
+use crate::config::Config;
+
+/// Accepts the server certificate only if its SHA-256 fingerprint is the
+/// pinned one. The certificate chain is not checked, so that self-signed
+/// certificates can be pinned.
+struct PinnedVerifier {
+    fingerprint: Vec<u8>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> std::result::Result<ServerCertVerified, TLSError> {
+        let cert = presented_certs
+            .first()
+            .ok_or_else(|| TLSError::General(String::from("No server certificate")))?;
+        let fingerprint = Sha256::digest(&cert.0);
+
+        if fingerprint[..] == self.fingerprint[..] {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(TLSError::General(format!(
+                "Server certificate fingerprint {} does not match the pinned one",
+                fingerprint
+                    .iter()
+                    .map(|byte| format!("{:02X}", byte))
+                    .collect::<Vec<_>>()
+                    .join(":")
+            )))
+        }
+    }
+}
+
+/// Parses a SHA-256 fingerprint, written in hexadecimal with or without
+/// colons (`AB:CD:…` or `abcd…`).
+fn parse_fingerprint(fingerprint: &str) -> Result<Vec<u8>> {
+    let hex: String = fingerprint
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .collect();
+    if hex.len() != 64 {
+        return Err(format!("Invalid SHA-256 fingerprint {:?}", fingerprint).into());
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .chain_err(|| format!("Invalid SHA-256 fingerprint {:?}", fingerprint))
+        })
+        .collect()
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).chain_err(|| format!("Could not read {:?}", path))
+}
+
+/// Reads the client certificate and its key, as PEM. The key can be in the
+/// certificate file.
+fn client_identity_pem(config: &Config) -> Result<Option<Vec<u8>>> {
+    let cert = match config.tls_client_cert {
+        Some(ref path) => read(path)?,
+        None => return Ok(None),
+    };
+    let mut pem = match config.tls_client_key {
+        Some(ref path) => read(path)?,
+        None => vec![],
+    };
+    pem.push(b'\n');
+    pem.extend(cert);
+
+    Ok(Some(pem))
+}
+
+/// Builds a rustls configuration pinning the server certificate.
+fn pinned_config(config: &Config, fingerprint: &str) -> Result<ClientConfig> {
+    let mut tls = ClientConfig::new();
+    tls.set_protocols(&[b"http/1.1".to_vec()]);
+    tls.dangerous()
+        .set_certificate_verifier(Arc::new(PinnedVerifier {
+            fingerprint: parse_fingerprint(fingerprint)?,
+        }));
+
+    if let Some(pem) = client_identity_pem(config)? {
+        let certs = pemfile::certs(&mut BufReader::new(pem.as_slice()))
+            .map_err(|_| "Could not parse client certificate")?;
+        let key = pemfile::pkcs8_private_keys(&mut BufReader::new(pem.as_slice()))
+            .ok()
+            .filter(|keys| !keys.is_empty())
+            .or_else(|| pemfile::rsa_private_keys(&mut BufReader::new(pem.as_slice())).ok())
+            .and_then(|keys| keys.into_iter().next())
+            .chain_err(|| "Could not find client certificate key")?;
+        tls.set_single_client_cert(certs, key)
+            .chain_err(|| "Invalid client certificate")?;
+    }
+
+    Ok(tls)
+}
+
+/// Applies the TLS options of the config to a client builder.
+pub fn configure(config: &Config, builder: ClientBuilder) -> Result<ClientBuilder> {
+    if !config.has_tls_options() {
+        return Ok(builder);
+    }
+    let mut builder = builder.use_rustls_tls();
+
+    if let Some(ref fingerprint) = config.tls_fingerprint {
+        return Ok(builder.use_preconfigured_tls(pinned_config(config, fingerprint)?));
+    }
+
+    if let Some(ref path) = config.tls_ca_file {
+        let cert = Certificate::from_pem(&read(path)?)
+            .chain_err(|| format!("Could not parse CA bundle {:?}", path))?;
+        builder = builder.add_root_certificate(cert);
+    }
+    if let Some(pem) = client_identity_pem(config)? {
+        let identity = Identity::from_pem(&pem).chain_err(|| "Invalid client certificate")?;
+        builder = builder.identity(identity);
+    }
+    if config.tls_insecure() {
+        warn!("TLS certificates are not verified (`tls-insecure` is set)");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Self-signed certificate of `cardamom.test`, as DER.
+    const CERT: &str = "MIIBhzCCAS2gAwIBAgIUPaM4S4QAMC7aEtyQM895XkU7s+MwCgYIKoZIzj0EAwIwGDEWMBQGA1UEAwwNY2FyZGFtb20udGVzdDAgFw0yNjEwMTgyMTEyMDBaGA8yMTI2MDkyNDIxMTIwMFowGDEWMBQGA1UEAwwNY2FyZGFtb20udGVzdDBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABJfOT6LHfgWXZlVDq+ugJpOL1yJ+rufIL0pr/gXbgKsxSzyEdgG3UqITJnyS86OY0sbsktNt6UniDhWFUwfqUq+jUzBRMB0GA1UdDgQWBBS/MUKojmDcDnzwoePQ/A8SBcnRijAfBgNVHSMEGDAWgBS/MUKojmDcDnzwoePQ/A8SBcnRijAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0gAMEUCIF4ZbyvO6NKxrgU4YTWRfxK+cXGTIdivOeOq4wUaIEMdAiEA6KinVInCGiu6FyZ8FTVypcilKxxzm4z33h7u4WayMhA=";
+
+    /// SHA-256 fingerprint of [`CERT`].
+    const FINGERPRINT: &str =
+        "16:95:28:B6:B2:45:B2:E6:10:97:FC:8B:49:58:9B:58:E3:80:B4:55:B5:5E:9D:02:BE:49:FE:BE:47:97:11:A4";
+
+    #[test]
+    fn fingerprints() {
+        let bytes = parse_fingerprint(FINGERPRINT).unwrap();
+        assert_eq!(bytes.len(), 32);
+        assert_eq!(bytes[..2], [0x16, 0x95]);
+
+        let hex = FINGERPRINT.replace(':', "");
+        assert_eq!(parse_fingerprint(&hex).unwrap(), bytes);
+        assert_eq!(parse_fingerprint(&hex.to_lowercase()).unwrap(), bytes);
+
+        assert!(parse_fingerprint(&hex[2..]).is_err());
+        assert!(parse_fingerprint(&format!("{}AB", hex)).is_err());
+        assert!(parse_fingerprint(&hex.replace('1', "G")).is_err());
+    }
+
+    fn verify(fingerprint: &str) -> std::result::Result<ServerCertVerified, TLSError> {
+        let verifier = PinnedVerifier {
+            fingerprint: parse_fingerprint(fingerprint).unwrap(),
+        };
+        let cert = rustls::Certificate(base64::decode(CERT).unwrap());
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str("cardamom.test").unwrap();
+        verifier.verify_server_cert(&RootCertStore::empty(), &[cert], dns_name, &[])
+    }
+
+    #[test]
+    fn pinned_certificate() {
+        assert!(verify(FINGERPRINT).is_ok());
+
+        let other = FINGERPRINT.replace("16:95", "16:96");
+        match verify(&other) {
+            Err(TLSError::General(err)) => assert!(err.contains(FINGERPRINT)),
+            res => panic!("expected a fingerprint mismatch, got {:?}", res.is_ok()),
+        }
+    }
+}
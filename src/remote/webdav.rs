@@ -0,0 +1,154 @@
+use async_trait::async_trait;
+use log::warn;
+use quick_xml::de as xml;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::{
+    dav::{self, Etag, Multistatus},
+    model::{Card, Metadata},
+    repository::{Client, RemoteRepository, Result, ResultExt},
+};
+use crate::config::Config;
+
+// Entry structs
+
+#[derive(Debug, Deserialize)]
+struct EntryProp {
+    pub getetag: Option<Etag>,
+}
+
+/// Plain WebDAV folder of `.vcf` files.
+pub struct WebDavRepository<'a> {
+    config: &'a Config,
+    client: &'a Client,
+    path: String,
+    is_partial: AtomicBool,
+}
+
+impl<'a> WebDavRepository<'a> {
+    pub fn new(config: &'a Config, client: &'a Client) -> Self {
+        Self {
+            config,
+            client,
+            path: config.remote_path(),
+            is_partial: AtomicBool::new(false),
+        }
+    }
+
+    /// Lists the hrefs and etags of the `.vcf` files of the folder.
+    async fn fetch_entries(&self) -> Result<Vec<(String, String)>> {
+        let req = dav::request(self.config, self.client, dav::propfind()?, &self.path)?
+            .header("Depth", "1")
+            .body(
+                r#"
+                <D:propfind xmlns:D="DAV:">
+                    <D:prop>
+                        <D:getetag />
+                    </D:prop>
+                </D:propfind>
+                "#,
+            );
+        let res = dav::send(self.config, self.client, req)
+            .await
+            .chain_err(|| "Could not send folder listing request")?
+            .error_for_status()
+            .chain_err(|| format!("Could not list folder {}", self.path))?
+            .text()
+            .await
+            .chain_err(|| "Could not extract text body from folder listing response")?;
+        let res: Multistatus<EntryProp> =
+            xml::from_str(&res).chain_err(|| "Could not parse folder listing response")?;
+
+        let mut entries: Vec<_> = res
+            .responses
+            .into_iter()
+            .filter(|res| res.href.value.ends_with(".vcf"))
+            .map(|res| {
+                let etag = res
+                    .propstat
+                    .prop
+                    .getetag
+                    .map(|etag| etag.value)
+                    .unwrap_or_default();
+                (res.href.value, etag)
+            })
+            .collect();
+        entries.sort();
+
+        Ok(entries)
+    }
+}
+
+#[async_trait]
+impl RemoteRepository for WebDavRepository<'_> {
+    async fn discover(&mut self) -> Result<String> {
+        self.fetch_entries().await?;
+        Ok(self.path.to_owned())
+    }
+
+    /// Plain WebDAV has no ctag, so the token is built from the etags of all
+    /// the files of the folder.
+    async fn change_token(&self) -> Result<String> {
+        let mut hasher = Sha256::new();
+        for (href, etag) in self.fetch_entries().await? {
+            hasher.update(format!("{} {}\n", href, etag).as_bytes());
+        }
+
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Files that cannot be fetched or parsed are skipped, and the listing
+    /// is marked as partial.
+    async fn list(&self) -> Result<HashMap<String, Card>> {
+        let mut cards = HashMap::new();
+        let mut is_partial = false;
+        for (href, _) in self.fetch_entries().await? {
+            let card = match self.fetch(&href).await {
+                Ok(card) if !card.name.is_empty() => card,
+                Ok(_) => continue,
+                Err(err) => {
+                    let err: Vec<_> = err.iter().map(|err| err.to_string()).collect();
+                    warn!("Skipping card {}: {}", href, err.join(": "));
+                    is_partial = true;
+                    continue;
+                }
+            };
+            cards.insert(card.name.to_owned(), card);
+        }
+        self.is_partial.store(is_partial, Ordering::Relaxed);
+
+        Ok(cards)
+    }
+
+    fn is_partial(&self) -> bool {
+        self.is_partial.load(Ordering::Relaxed)
+    }
+
+    async fn fetch(&self, href: &str) -> Result<Card> {
+        dav::get(self.config, self.client, href).await
+    }
+
+    async fn put(&mut self, name: &str, content: &str, prev: Option<&Card>) -> Result<Card> {
+        let href = match prev {
+            Some(card) => card.href.to_owned(),
+            None => dav::new_href(&self.path, name),
+        };
+        let etag = prev.map(|card| card.etag.as_str());
+        dav::put(self.config, self.client, &href, content, etag).await?;
+
+        self.fetch(&href).await
+    }
+
+    async fn delete(&mut self, card: &Card) -> Result<()> {
+        dav::delete(self.config, self.client, &card.href, &card.etag).await
+    }
+
+    async fn metadata(&self) -> Result<Metadata> {
+        dav::fetch_metadata(self.config, self.client, &self.path).await
+    }
+}
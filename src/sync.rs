@@ -0,0 +1,585 @@
+use error_chain::error_chain;
+use log::{info, warn};
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{
+    cache::{self, Cache},
+    config::Config,
+    convert,
+    local::{
+        self,
+        repository::{hash_card, hash_content, LocalRepository},
+    },
+    photo,
+    remote::{self, repository::RemoteRepository},
+    validate::{self, Severity},
+    vcard::{self, Version},
+};
+
+error_chain! {
+    links {
+        Cache(cache::Error, cache::ErrorKind);
+        LocalRepository(local::repository::Error, local::repository::ErrorKind);
+        RemoteRepository(remote::repository::Error, remote::repository::ErrorKind);
+        Photo(photo::Error, photo::ErrorKind);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Download,
+    Upload,
+    DeleteLocal,
+    DeleteRemote,
+}
+
+/// Decides what to do with a card, depending on its presence and changes on
+/// both sides since the last sync. Conflicts are resolved in favour of the
+/// remote side.
+fn action(
+    name: &str,
+    lcard: Option<&local::model::Card>,
+    rcard: Option<&remote::model::Card>,
+    cached: Option<&cache::CacheItem>,
+    local_repo: &dyn LocalRepository,
+) -> Option<Action> {
+    let is_local_changed = |lcard, cached| local_repo.is_changed(lcard, cached);
+    let is_remote_changed =
+        |rcard: &remote::model::Card, cached: &cache::CacheItem| rcard.etag != cached.etag;
+    let is_same =
+        |lcard, rcard: &remote::model::Card| hash_card(lcard) == hash_content(&rcard.content);
+
+    match (lcard, rcard, cached) {
+        (Some(lcard), Some(rcard), Some(cached)) => {
+            match (
+                is_local_changed(lcard, cached),
+                is_remote_changed(rcard, cached),
+            ) {
+                (false, false) => None,
+                (true, false) => Some(Action::Upload),
+                (false, true) => Some(Action::Download),
+                (true, true) if is_same(lcard, rcard) => None,
+                (true, true) => {
+                    warn!(
+                        "Card {} changed on both sides, keeping the remote one",
+                        name
+                    );
+                    Some(Action::Download)
+                }
+            }
+        }
+        (Some(lcard), Some(rcard), None) if is_same(lcard, rcard) => None,
+        (Some(_), Some(_), None) => {
+            warn!("Card {} added on both sides, keeping the remote one", name);
+            Some(Action::Download)
+        }
+        (Some(lcard), None, Some(cached)) if is_local_changed(lcard, cached) => {
+            Some(Action::Upload)
+        }
+        (Some(_), None, Some(_)) => Some(Action::DeleteLocal),
+        (None, Some(rcard), Some(cached)) if is_remote_changed(rcard, cached) => {
+            Some(Action::Download)
+        }
+        (None, Some(_), Some(_)) => Some(Action::DeleteRemote),
+        (Some(_), None, None) => Some(Action::Upload),
+        (None, Some(_), None) => Some(Action::Download),
+        (None, None, _) => None,
+    }
+}
+
+/// Converts the local cards that are not in the given version.
+fn normalize_local(local_repo: &mut dyn LocalRepository, version: Version) -> Result<()> {
+    let cards: HashMap<String, String> = local_repo
+        .list()?
+        .into_iter()
+        .filter(|(_, card)| vcard::version(&card.content) != Some(version))
+        .map(|(name, card)| (name, convert::convert(&card.content, version)))
+        .collect();
+    local_repo.write(&cards)?;
+    cards
+        .keys()
+        .for_each(|name| println!("Card {} converted to vCard {}", name, version));
+
+    Ok(())
+}
+
+/// Synchronizes the local and the remote repositories, then writes the new
+/// cache. Remote failures are reported per card: their cache entry is kept
+/// as it was so that they are retried on the next sync.
+pub async fn sync(
+    config: &Config,
+    cache: &Cache,
+    local_repo: &mut dyn LocalRepository,
+    remote_repo: &mut dyn RemoteRepository,
+) -> Result<()> {
+    if let Some(version) = config.version {
+        normalize_local(local_repo, version)?;
+    }
+
+    let ctag = remote_repo.change_token().await?;
+    let lcards = local_repo.list()?;
+    let cache = &cache.migrate_names(&lcards);
+
+    let has_local_changes = cache.cards.len() != lcards.len()
+        || lcards
+            .iter()
+            .any(|(name, lcard)| match cache.cards.get(name) {
+                None => true,
+                Some(cached) => local_repo.is_changed(lcard, cached),
+            });
+
+    if ctag == cache.ctag && !has_local_changes {
+        info!("Nothing to synchronize");
+        return Ok(());
+    }
+
+    let metadata = remote_repo.metadata().await?;
+    local_repo.write_metadata(&metadata)?;
+
+    let mut rcards = remote_repo.list().await?;
+
+    // Remote cards are compared and downloaded with their photos extracted,
+    // the way they are stored locally.
+    let photos = match config.photo_dir() {
+        Some(ref dir) => photo::extract_cards(&mut rcards, dir)?,
+        None => HashMap::new(),
+    };
+    let names: BTreeSet<&String> = lcards
+        .keys()
+        .chain(rcards.keys())
+        .chain(cache.cards.keys())
+        .collect();
+
+    let mut actions = vec![];
+    for name in names {
+        let lcard = lcards.get(name);
+        let rcard = rcards.get(name);
+        let cached = cache.cards.get(name);
+        if let Some(action) = action(name, lcard, rcard, cached, local_repo) {
+            actions.push((name.to_owned(), action));
+        }
+    }
+
+    let mut downloads: HashMap<String, String> = actions
+        .iter()
+        .filter(|(_, action)| *action == Action::Download)
+        .filter_map(|(name, _)| {
+            let content = &rcards.get(name)?.content;
+            let content = match lcards.get(name) {
+                Some(lcard) => remote_repo.merge(&lcard.content, content),
+                None => content.to_owned(),
+            };
+            Some((name.to_owned(), content))
+        })
+        .collect();
+
+    // Downloaded cards in another version are converted, then uploaded back
+    // so that the remote side gets normalized as well.
+    let mut uploads: HashMap<String, String> = lcards
+        .iter()
+        .map(|(name, card)| (name.to_owned(), card.content.to_owned()))
+        .collect();
+    if let Some(version) = config.version {
+        for (name, content) in downloads.iter_mut() {
+            if vcard::version(content) != Some(version) {
+                *content = convert::convert(content, version);
+                uploads.insert(name.to_owned(), content.to_owned());
+                actions.push((name.to_owned(), Action::Upload));
+            }
+        }
+    }
+
+    for name in downloads.keys() {
+        photo::write(photos.get(name).map(Vec::as_slice).unwrap_or_default())?;
+    }
+    local_repo.write(&downloads)?;
+    downloads
+        .keys()
+        .for_each(|name| println!("Card {} downloaded", name));
+
+    // Cards missing from a partial listing may be the ones that could not be
+    // read, so they are kept until the next sync.
+    let mut failed = vec![];
+    let mut local_deletions = vec![];
+    for (name, action) in actions.iter() {
+        match action {
+            Action::DeleteLocal if remote_repo.is_partial() => {
+                warn!(
+                    "Card {} not deleted locally: some remote cards could not be read",
+                    name
+                );
+                failed.push(name);
+            }
+            Action::DeleteLocal => local_deletions.push(name.to_owned()),
+            _ => (),
+        }
+    }
+    local_repo.delete(&local_deletions)?;
+    local_deletions
+        .iter()
+        .for_each(|name| println!("Card {} deleted locally", name));
+
+    for (name, action) in actions.iter() {
+        let res = match action {
+            Action::Upload => {
+                let content = match config.photo_dir() {
+                    Some(dir) => photo::embed(&uploads[name], &dir),
+                    None => Ok(uploads[name].to_owned()),
+                };
+                let content = match content {
+                    Ok(content) => content,
+                    Err(err) => {
+                        let err: Vec<_> = err.iter().map(|err| err.to_string()).collect();
+                        warn!("Card {}: {}", name, err.join(": "));
+                        failed.push(name);
+                        continue;
+                    }
+                };
+                let errors: Vec<_> = validate::check(&content)
+                    .into_iter()
+                    .filter(|problem| problem.severity() == Severity::Error)
+                    .map(|problem| problem.kind.to_string())
+                    .collect();
+                if !errors.is_empty() {
+                    let err = format!("Card {} is invalid: {}", name, errors.join(", "));
+                    Err(err.into())
+                } else {
+                    match remote_repo.put(name, &content, rcards.get(name)).await {
+                        Ok(rcard) => {
+                            rcards.insert(name.to_owned(), rcard);
+                            println!("Card {} uploaded", name);
+                            Ok(())
+                        }
+                        Err(err) => Err(err),
+                    }
+                }
+            }
+            Action::DeleteRemote => match remote_repo.delete(&rcards[name]).await {
+                Ok(()) => {
+                    rcards.remove(name);
+                    println!("Card {} deleted remotely", name);
+                    Ok(())
+                }
+                Err(err) => Err(err),
+            },
+            _ => Ok(()),
+        };
+
+        if let Err(err) = res {
+            let err: Vec<_> = err.iter().map(|err| err.to_string()).collect();
+            warn!("{}", err.join(": "));
+            failed.push(name);
+        }
+    }
+
+    let ctag = remote_repo.change_token().await?;
+    let lcards = local_repo.list()?;
+    if let Some(ref dir) = config.photo_dir() {
+        let contents: Vec<_> = lcards.values().map(|card| card.content.as_str()).collect();
+        photo::clean(dir, &contents)?;
+    }
+    let mut next_cache = Cache::build(ctag, &lcards, &rcards);
+    for name in failed {
+        match cache.cards.get(name) {
+            Some(cached) => next_cache.cards.insert(name.to_owned(), cached.clone()),
+            None => next_cache.cards.remove(name),
+        };
+    }
+    next_cache.write(config)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use chrono::{DateTime, TimeZone, Utc};
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        cache::CacheItem,
+        local::memory::MemoryRepository,
+        remote::{model::Card as RemoteCard, people, repository},
+    };
+
+    fn card(uid: &str, name: &str) -> String {
+        format!(
+            "BEGIN:VCARD\r\nVERSION:3.0\r\nUID:{}\r\nFN:{}\r\nEND:VCARD\r\n",
+            uid, name
+        )
+    }
+
+    fn epoch() -> DateTime<Utc> {
+        Utc.timestamp_opt(0, 0).unwrap()
+    }
+
+    fn rcard(name: &str, content: &str, etag: &str) -> RemoteCard {
+        RemoteCard {
+            etag: etag.to_owned(),
+            name: name.to_owned(),
+            href: format!("/{}.vcf", name),
+            date: epoch(),
+            content: content.to_owned(),
+        }
+    }
+
+    fn copy(card: &RemoteCard) -> RemoteCard {
+        rcard(&card.name, &card.content, &card.etag)
+    }
+
+    /// Decides the action for a card given its local content, its remote
+    /// content and etag, and the local content and etag it had at the last
+    /// sync.
+    fn decide(
+        local: Option<&str>,
+        remote: Option<(&str, &str)>,
+        cached: Option<(&str, &str)>,
+    ) -> Option<Action> {
+        let mut local_repo = MemoryRepository::new();
+        if let Some(content) = local {
+            let cards = vec![(String::from("a"), content.to_owned())];
+            local_repo.write(&cards.into_iter().collect()).unwrap();
+        }
+        let lcards = local_repo.list().unwrap();
+        let rcard = remote.map(|(content, etag)| rcard("a", content, etag));
+        // The cached date never matches, so that changes are detected from
+        // the content hash.
+        let cached = cached.map(|(content, etag)| CacheItem {
+            name: String::from("a"),
+            etag: etag.to_owned(),
+            local_date: epoch(),
+            remote_date: epoch(),
+            local_hash: hash_content(content),
+        });
+
+        action(
+            "a",
+            lcards.get("a"),
+            rcard.as_ref(),
+            cached.as_ref(),
+            &local_repo,
+        )
+    }
+
+    #[test]
+    fn action_on_both_sides() {
+        let (old, new) = (card("a", "Alice"), card("a", "Alice B"));
+        let (old, new) = (old.as_str(), new.as_str());
+
+        assert_eq!(decide(Some(old), Some((old, "1")), Some((old, "1"))), None);
+        assert_eq!(
+            decide(Some(new), Some((old, "1")), Some((old, "1"))),
+            Some(Action::Upload)
+        );
+        assert_eq!(
+            decide(Some(old), Some((new, "2")), Some((old, "1"))),
+            Some(Action::Download)
+        );
+        assert_eq!(decide(Some(new), Some((new, "2")), Some((old, "1"))), None);
+        assert_eq!(
+            decide(
+                Some(new),
+                Some((&card("a", "Alice C"), "2")),
+                Some((old, "1"))
+            ),
+            Some(Action::Download)
+        );
+    }
+
+    #[test]
+    fn action_on_new_cards() {
+        let (old, new) = (card("a", "Alice"), card("a", "Alice B"));
+        let (old, new) = (old.as_str(), new.as_str());
+
+        assert_eq!(decide(Some(old), None, None), Some(Action::Upload));
+        assert_eq!(decide(None, Some((old, "1")), None), Some(Action::Download));
+        assert_eq!(decide(Some(old), Some((old, "1")), None), None);
+        assert_eq!(
+            decide(Some(new), Some((old, "1")), None),
+            Some(Action::Download)
+        );
+    }
+
+    #[test]
+    fn action_on_deleted_cards() {
+        let (old, new) = (card("a", "Alice"), card("a", "Alice B"));
+        let (old, new) = (old.as_str(), new.as_str());
+
+        assert_eq!(
+            decide(Some(old), None, Some((old, "1"))),
+            Some(Action::DeleteLocal)
+        );
+        assert_eq!(
+            decide(Some(new), None, Some((old, "1"))),
+            Some(Action::Upload)
+        );
+        assert_eq!(
+            decide(None, Some((old, "1")), Some((old, "1"))),
+            Some(Action::DeleteRemote)
+        );
+        assert_eq!(
+            decide(None, Some((new, "2")), Some((old, "1"))),
+            Some(Action::Download)
+        );
+        assert_eq!(decide(None, None, Some((old, "1"))), None);
+    }
+
+    /// Remote cards kept in memory, with a change token and etags bumped on
+    /// every change. People remotes only store the properties of persons.
+    #[derive(Default)]
+    struct MemoryRemote {
+        cards: HashMap<String, RemoteCard>,
+        version: u32,
+        is_people: bool,
+    }
+
+    impl MemoryRemote {
+        fn insert(&mut self, name: &str, content: &str) -> RemoteCard {
+            self.version += 1;
+            let card = rcard(name, content, &self.version.to_string());
+            self.cards.insert(name.to_owned(), copy(&card));
+            card
+        }
+    }
+
+    #[async_trait]
+    impl RemoteRepository for MemoryRemote {
+        async fn discover(&mut self) -> repository::Result<String> {
+            Ok(String::from("/"))
+        }
+
+        async fn change_token(&self) -> repository::Result<String> {
+            Ok(self.version.to_string())
+        }
+
+        async fn list(&self) -> repository::Result<HashMap<String, RemoteCard>> {
+            Ok(self
+                .cards
+                .iter()
+                .map(|(name, card)| (name.to_owned(), copy(card)))
+                .collect())
+        }
+
+        async fn fetch(&self, href: &str) -> repository::Result<RemoteCard> {
+            self.cards
+                .values()
+                .find(|card| card.href == href)
+                .map(copy)
+                .ok_or_else(|| format!("Card {} not found", href).into())
+        }
+
+        async fn put(
+            &mut self,
+            name: &str,
+            content: &str,
+            _prev: Option<&RemoteCard>,
+        ) -> repository::Result<RemoteCard> {
+            match self.is_people {
+                true => Ok(self.insert(name, &people::stored(content))),
+                false => Ok(self.insert(name, content)),
+            }
+        }
+
+        async fn delete(&mut self, card: &RemoteCard) -> repository::Result<()> {
+            self.version += 1;
+            self.cards.remove(&card.name);
+            Ok(())
+        }
+
+        fn merge(&self, local: &str, remote: &str) -> String {
+            match self.is_people {
+                true => people::merge(local, remote),
+                false => remote.to_owned(),
+            }
+        }
+    }
+
+    fn config(dir: &TempDir) -> Config {
+        toml::from_str(&format!("sync-dir = {:?}", dir.path())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sync_both_ways() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir);
+        let mut local_repo = MemoryRepository::new();
+        let mut remote_repo = MemoryRemote::default();
+        let cache = Cache::build(String::new(), &HashMap::new(), &HashMap::new());
+
+        let cards = vec![(String::from("a"), card("a", "Alice"))];
+        local_repo.write(&cards.into_iter().collect()).unwrap();
+        remote_repo.insert("b", &card("b", "Bob"));
+        sync(&config, &cache, &mut local_repo, &mut remote_repo)
+            .await
+            .unwrap();
+
+        let lcards = local_repo.list().unwrap();
+        assert_eq!(lcards["b"].content, card("b", "Bob"));
+        assert_eq!(remote_repo.cards["a"].content, card("a", "Alice"));
+        let cache = Cache::from_file(&config).unwrap();
+        assert_eq!(cache.ctag, remote_repo.version.to_string());
+        assert_eq!(cache.cards["a"].etag, remote_repo.cards["a"].etag);
+        assert_eq!(cache.cards["b"].etag, remote_repo.cards["b"].etag);
+
+        local_repo.delete(&[String::from("a")]).unwrap();
+        remote_repo.insert("b", &card("b", "Bob B"));
+        sync(&config, &cache, &mut local_repo, &mut remote_repo)
+            .await
+            .unwrap();
+
+        assert!(!remote_repo.cards.contains_key("a"));
+        let lcards = local_repo.list().unwrap();
+        assert_eq!(lcards.len(), 1);
+        assert_eq!(lcards["b"].content, card("b", "Bob B"));
+        let cache = Cache::from_file(&config).unwrap();
+        assert_eq!(cache.cards.keys().collect::<Vec<_>>(), vec!["b"]);
+    }
+
+    #[tokio::test]
+    async fn sync_keeps_local_props_with_people() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir);
+        let mut local_repo = MemoryRepository::new();
+        let mut remote_repo = MemoryRemote {
+            is_people: true,
+            ..MemoryRemote::default()
+        };
+        let cache = Cache::build(String::new(), &HashMap::new(), &HashMap::new());
+        let local = "BEGIN:VCARD\r\n\
+            VERSION:3.0\r\n\
+            UID:a\r\n\
+            FN:Alice\r\n\
+            ADR;TYPE=home:;;1 Main St;Paris;;75001;France\r\n\
+            BDAY:1990-01-02\r\n\
+            END:VCARD\r\n";
+
+        // First sync with an empty cache: the card is on both sides, the
+        // remote one without the address and the birthday.
+        let cards = vec![(String::from("a"), local.to_owned())];
+        local_repo.write(&cards.into_iter().collect()).unwrap();
+        remote_repo.insert("a", &people::stored(local));
+        sync(&config, &cache, &mut local_repo, &mut remote_repo)
+            .await
+            .unwrap();
+
+        let content = local_repo.list().unwrap()["a"].content.to_owned();
+        assert!(content.contains("ADR;TYPE=home:;;1 Main St;Paris;;75001;France\r\n"));
+        assert!(content.contains("BDAY:1990-01-02\r\n"));
+
+        // The person changes remotely, then is downloaded again.
+        remote_repo.insert("a", &people::stored(&card("a", "Alice B")));
+        let cache = Cache::from_file(&config).unwrap();
+        sync(&config, &cache, &mut local_repo, &mut remote_repo)
+            .await
+            .unwrap();
+
+        let content = local_repo.list().unwrap()["a"].content.to_owned();
+        assert!(content.contains("FN:Alice B\r\n"));
+        assert!(!content.contains("FN:Alice\r\n"));
+        assert!(content.contains("ADR;TYPE=home:;;1 Main St;Paris;;75001;France\r\n"));
+        assert!(content.contains("BDAY:1990-01-02\r\n"));
+        assert_eq!(vcard::uid(&content).as_deref(), Some("a"));
+    }
+}
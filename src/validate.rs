@@ -0,0 +1,474 @@
+//! Structural validation of `.vcf` files, so that malformed cards are
+//! reported (and fixed when possible) instead of being pushed blindly.
+
+use error_chain::error_chain;
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    contact::Contact,
+    local::repository::card_names,
+    vcard::{self, Property},
+};
+
+error_chain! {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Warning => write!(f, "warning"),
+            Self::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProblemKind {
+    InvalidUtf8,
+    OutsideCard,
+    MissingBegin,
+    MissingEnd,
+    InvalidLine,
+    MissingVersion,
+    InvalidVersion(String),
+    MissingFn,
+    MissingUid,
+    InvalidEncoding(String),
+    InvalidValue(String, String),
+    DuplicateUid(String, PathBuf, usize),
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidUtf8 => write!(f, "invalid UTF-8, read as Latin-1"),
+            Self::OutsideCard => write!(f, "content outside of BEGIN:VCARD…END:VCARD"),
+            Self::MissingBegin => write!(f, "missing BEGIN:VCARD"),
+            Self::MissingEnd => write!(f, "missing END:VCARD"),
+            Self::InvalidLine => write!(f, "invalid content line"),
+            Self::MissingVersion => write!(f, "missing VERSION"),
+            Self::InvalidVersion(version) => write!(f, "invalid VERSION {:?}", version),
+            Self::MissingFn => write!(f, "missing FN"),
+            Self::MissingUid => write!(f, "missing UID"),
+            Self::InvalidEncoding(encoding) => write!(f, "unknown ENCODING {:?}", encoding),
+            Self::InvalidValue(name, encoding) => {
+                write!(f, "value of {} is not valid {}", name, encoding)
+            }
+            Self::DuplicateUid(uid, path, line) => write!(
+                f,
+                "duplicate UID {:?}, first found in {:?} line {}",
+                uid, path, line
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    /// Line number, starting at 1.
+    pub line: usize,
+    pub kind: ProblemKind,
+    pub fixable: bool,
+}
+
+impl Problem {
+    fn new(line: usize, kind: ProblemKind, fixable: bool) -> Self {
+        Self {
+            line,
+            kind,
+            fixable,
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self.kind {
+            ProblemKind::InvalidUtf8 | ProblemKind::OutsideCard | ProblemKind::MissingUid => {
+                Severity::Warning
+            }
+            _ => Severity::Error,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}: {}", self.line, self.severity(), self.kind)?;
+        if self.fixable {
+            write!(f, " (fixable)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Card as found in a file, with the line numbers of its unfolded content
+/// lines.
+#[derive(Debug, Default)]
+struct Block {
+    line: usize,
+    props: Vec<(usize, String)>,
+}
+
+impl Block {
+    fn content(&self) -> String {
+        let props: Vec<_> = self.props.iter().map(|(_, prop)| prop.as_str()).collect();
+        format!("BEGIN:VCARD\r\n{}\r\nEND:VCARD\r\n", props.join("\r\n"))
+    }
+}
+
+/// Splits a content into blocks, reporting the structural problems along
+/// the way. Lines outside of blocks are dropped.
+fn scan(content: &str) -> (Vec<Block>, Vec<Problem>) {
+    let mut blocks = vec![];
+    let mut problems = vec![];
+    let mut block: Option<Block> = None;
+    let mut orphans: Vec<(usize, String)> = vec![];
+
+    for (i, line) in content.lines().enumerate() {
+        let line_nb = i + 1;
+        let line = line.trim_end_matches('\r');
+        let props = match block {
+            Some(ref mut block) => &mut block.props,
+            None => &mut orphans,
+        };
+
+        if let Some((_, last)) = props.last_mut() {
+            if line.starts_with(' ') || line.starts_with('\t') {
+                last.push_str(&line[1..]);
+                continue;
+            }
+            if vcard::has_soft_break(last) && !line.trim().eq_ignore_ascii_case("END:VCARD") {
+                last.pop();
+                last.push_str(line);
+                continue;
+            }
+        }
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if line.trim().eq_ignore_ascii_case("BEGIN:VCARD") {
+            if let Some(block) = block.take() {
+                problems.push(Problem::new(block.line, ProblemKind::MissingEnd, true));
+                blocks.push(block);
+            }
+            if let Some((line_nb, _)) = orphans.first() {
+                problems.push(Problem::new(*line_nb, ProblemKind::OutsideCard, true));
+                orphans.clear();
+            }
+            block = Some(Block {
+                line: line_nb,
+                props: vec![],
+            });
+        } else if line.trim().eq_ignore_ascii_case("END:VCARD") {
+            match block.take() {
+                Some(block) => blocks.push(block),
+                None => {
+                    let line = orphans.first().map(|(n, _)| *n).unwrap_or(line_nb);
+                    problems.push(Problem::new(line, ProblemKind::MissingBegin, true));
+                    blocks.push(Block {
+                        line,
+                        props: std::mem::take(&mut orphans),
+                    });
+                }
+            }
+        } else {
+            props.push((line_nb, line.to_owned()));
+        }
+    }
+
+    if let Some(block) = block.take() {
+        problems.push(Problem::new(block.line, ProblemKind::MissingEnd, true));
+        blocks.push(block);
+    }
+    if let Some((line_nb, _)) = orphans.first() {
+        problems.push(Problem::new(*line_nb, ProblemKind::OutsideCard, true));
+    }
+
+    (blocks, problems)
+}
+
+fn is_valid_quoted_printable(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'=' {
+            let is_hex = |i: usize| bytes.get(i).map(u8::is_ascii_hexdigit).unwrap_or(false);
+            if !is_hex(i + 1) || !is_hex(i + 2) {
+                return false;
+            }
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    true
+}
+
+fn check_block(block: &Block, problems: &mut Vec<Problem>) {
+    let mut props = vec![];
+    for (line, text) in block.props.iter() {
+        match Property::parse(text) {
+            Some(prop) => props.push((*line, prop)),
+            None => problems.push(Problem::new(*line, ProblemKind::InvalidLine, false)),
+        }
+    }
+    let find = |name: &str| props.iter().find(|(_, prop)| prop.name == name);
+
+    let version = find("VERSION").map(|(line, prop)| (*line, prop.value.trim()));
+    match version {
+        None => problems.push(Problem::new(block.line, ProblemKind::MissingVersion, true)),
+        Some((_, "2.1")) | Some((_, "3.0")) | Some((_, "4.0")) => (),
+        Some((line, version)) => problems.push(Problem::new(
+            line,
+            ProblemKind::InvalidVersion(version.to_owned()),
+            false,
+        )),
+    }
+
+    // `FN` is optional in vCard 2.1, as long as there is a `N`.
+    let has_fn = find("FN")
+        .map(|(_, prop)| !prop.text().trim().is_empty())
+        .unwrap_or(false);
+    let is_fn_optional = version.map(|(_, v)| v == "2.1").unwrap_or(false) && find("N").is_some();
+    if !has_fn && !is_fn_optional {
+        let fixable = !Contact::from_vcard(&block.content()).name.is_empty();
+        problems.push(Problem::new(block.line, ProblemKind::MissingFn, fixable));
+    }
+
+    if vcard::uid(&block.content()).is_none() {
+        problems.push(Problem::new(block.line, ProblemKind::MissingUid, true));
+    }
+
+    for (line, prop) in props.iter() {
+        let encoding = match prop.param("ENCODING") {
+            Some(encoding) => encoding,
+            None => continue,
+        };
+        let is_valid = match encoding.to_lowercase().as_str() {
+            "b" | "base64" => {
+                let value: String = prop.value.split_whitespace().collect();
+                base64::decode(value).is_ok()
+            }
+            "quoted-printable" => is_valid_quoted_printable(&prop.value),
+            "8bit" | "7bit" => true,
+            _ => {
+                problems.push(Problem::new(
+                    *line,
+                    ProblemKind::InvalidEncoding(encoding.to_owned()),
+                    false,
+                ));
+                continue;
+            }
+        };
+        if !is_valid {
+            problems.push(Problem::new(
+                *line,
+                ProblemKind::InvalidValue(prop.name.to_owned(), encoding.to_uppercase()),
+                false,
+            ));
+        }
+    }
+}
+
+/// Checks the content of a `.vcf` file, card by card.
+pub fn check(content: &str) -> Vec<Problem> {
+    let (blocks, mut problems) = scan(content);
+    for block in blocks.iter() {
+        check_block(block, &mut problems);
+    }
+    problems.sort_by_key(|problem| problem.line);
+
+    problems
+}
+
+/// Fixes what can be fixed: misplaced or missing `BEGIN`/`END` lines and
+/// missing `VERSION`, `FN` and `UID` properties. Folded lines are unfolded.
+///
+/// Missing UIDs are set to the current name of the card, so that fixed
+/// cards are not seen as new ones by the next sync.
+pub fn fix(path: &Path, content: &str) -> String {
+    let (blocks, _) = scan(content);
+    let contents: Vec<_> = blocks.iter().map(Block::content).collect();
+    let names = card_names(path, &contents);
+    let cards: Vec<String> = blocks
+        .into_iter()
+        .zip(names)
+        .map(|(mut block, name)| {
+            let has = |block: &Block, name: &str| {
+                block.props.iter().any(|(_, prop)| {
+                    Property::parse(prop)
+                        .map(|prop| prop.name == name && !prop.value.trim().is_empty())
+                        .unwrap_or(false)
+                })
+            };
+
+            if !has(&block, "VERSION") {
+                block.props.retain(|(_, prop)| {
+                    !Property::parse(prop)
+                        .map(|prop| prop.name == "VERSION")
+                        .unwrap_or(false)
+                });
+                block.props.insert(0, (0, String::from("VERSION:3.0")));
+            }
+            if !has(&block, "FN") {
+                let name = Contact::from_vcard(&block.content()).name;
+                if !name.is_empty() {
+                    let prop = Property::new("FN", &vcard::escape(&name));
+                    block.props.push((0, prop.to_string()));
+                }
+            }
+            if !has(&block, "UID") {
+                let prop = Property::new("UID", &vcard::escape(&name));
+                block.props.push((0, prop.to_string()));
+            }
+
+            block.content()
+        })
+        .collect();
+
+    vcard::join(&cards)
+}
+
+/// Problems found in one file.
+#[derive(Debug)]
+pub struct Report {
+    pub path: PathBuf,
+    /// Content of the file, decoded as Latin-1 if it is not valid UTF-8.
+    pub content: String,
+    pub problems: Vec<Problem>,
+}
+
+fn read_file(path: &Path) -> Result<(String, Option<Problem>)> {
+    let bytes = fs::read(path).chain_err(|| format!("Could not read {:?}", path))?;
+
+    match String::from_utf8(bytes) {
+        Ok(content) => Ok((content, None)),
+        Err(err) => {
+            let bytes = err.as_bytes();
+            let valid_up_to = err.utf8_error().valid_up_to();
+            let line = bytes[..valid_up_to].iter().filter(|b| **b == b'\n').count() + 1;
+            let content = bytes.iter().map(|b| *b as char).collect();
+            Ok((
+                content,
+                Some(Problem::new(line, ProblemKind::InvalidUtf8, true)),
+            ))
+        }
+    }
+}
+
+/// Checks the given files, including UIDs duplicated among them.
+pub fn check_files(paths: &[PathBuf]) -> Result<Vec<Report>> {
+    let mut reports = vec![];
+    let mut uids: HashMap<String, (PathBuf, usize)> = HashMap::new();
+
+    for path in paths {
+        let (content, utf8_problem) = read_file(path)?;
+        let mut problems = check(&content);
+        problems.extend(utf8_problem);
+
+        let (blocks, _) = scan(&content);
+        for block in blocks {
+            let uid = block
+                .props
+                .iter()
+                .filter_map(|(line, prop)| Some((line, Property::parse(prop)?)))
+                .find(|(_, prop)| prop.name == "UID" && !prop.value.trim().is_empty());
+            if let Some((line, prop)) = uid {
+                let uid = prop.value.trim().to_owned();
+                match uids.get(&uid) {
+                    Some((first_path, first_line)) => problems.push(Problem::new(
+                        *line,
+                        ProblemKind::DuplicateUid(uid, first_path.to_owned(), *first_line),
+                        false,
+                    )),
+                    None => {
+                        uids.insert(uid, (path.to_owned(), *line));
+                    }
+                }
+            }
+        }
+
+        problems.sort_by_key(|problem| problem.line);
+        reports.push(Report {
+            path: path.to_owned(),
+            content,
+            problems,
+        });
+    }
+
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(content: &str) -> Vec<ProblemKind> {
+        check(content).into_iter().map(|p| p.kind).collect()
+    }
+
+    #[test]
+    fn check_valid_card() {
+        let content = "BEGIN:VCARD\r\nVERSION:3.0\r\nUID:a\r\nFN:Alice\r\nEND:VCARD\r\n";
+        assert_eq!(check(content), vec![]);
+    }
+
+    #[test]
+    fn check_broken_card() {
+        let content = "FN:Orphan\nBEGIN:VCARD\nN:Doe;John;;;\nPHOTO;ENCODING=b:!!\n";
+        assert_eq!(
+            kinds(content),
+            vec![
+                ProblemKind::OutsideCard,
+                ProblemKind::MissingEnd,
+                ProblemKind::MissingVersion,
+                ProblemKind::MissingFn,
+                ProblemKind::MissingUid,
+                ProblemKind::InvalidValue(String::from("PHOTO"), String::from("B")),
+            ]
+        );
+    }
+
+    #[test]
+    fn fix_broken_card() {
+        let content = "BEGIN:VCARD\nN:Doe;John;;;\nNOTE:folded\n  line\n";
+        let fixed = fix(Path::new("/tmp/john.vcf"), content);
+
+        assert_eq!(
+            fixed,
+            "BEGIN:VCARD\r\nVERSION:3.0\r\nN:Doe;John;;;\r\nNOTE:folded line\r\nFN:John Doe\r\nUID:john\r\nEND:VCARD\r\n"
+        );
+        assert_eq!(check(&fixed), vec![]);
+    }
+
+    #[test]
+    fn fix_keeps_uids_and_names_cards_by_position() {
+        let content = "END:VCARD\nBEGIN:VCARD\nVERSION:4.0\nFN:B\nUID:b\nEND:VCARD\nBEGIN:VCARD\nFN:C\nEND:VCARD\n";
+        let fixed = fix(Path::new("/tmp/all.vcf"), content);
+        let uids: Vec<_> = vcard::split(&fixed)
+            .iter()
+            .map(|card| vcard::uid(card))
+            .collect();
+
+        assert_eq!(
+            uids,
+            vec![
+                Some(String::from("all-1")),
+                Some(String::from("b")),
+                Some(String::from("all-3"))
+            ]
+        );
+    }
+}
@@ -0,0 +1,346 @@
+//! Minimal helpers around the vCard text format.
+
+use serde::Deserialize;
+use std::{fmt, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Version {
+    #[serde(rename = "2.1")]
+    V21,
+    #[serde(rename = "3.0")]
+    V30,
+    #[serde(rename = "4.0")]
+    V40,
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "2.1" => Ok(Self::V21),
+            "3.0" => Ok(Self::V30),
+            "4.0" => Ok(Self::V40),
+            _ => Err(format!("Unknown vCard version {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::V21 => write!(f, "2.1"),
+            Self::V30 => write!(f, "3.0"),
+            Self::V40 => write!(f, "4.0"),
+        }
+    }
+}
+
+/// Splits a content containing one or many `BEGIN:VCARD`…`END:VCARD` blocks
+/// into the blocks themselves. Anything outside of a block is dropped.
+pub fn split(content: &str) -> Vec<String> {
+    let mut cards = vec![];
+    let mut card: Option<String> = None;
+
+    for line in content.split_inclusive('\n') {
+        let prop = line.trim_end();
+
+        if prop.eq_ignore_ascii_case("BEGIN:VCARD") {
+            card = Some(String::new());
+        }
+
+        if let Some(ref mut card) = card {
+            card.push_str(line);
+        }
+
+        if prop.eq_ignore_ascii_case("END:VCARD") {
+            if let Some(card) = card.take() {
+                cards.push(card);
+            }
+        }
+    }
+
+    cards
+}
+
+/// Joins multiple cards into one content, one block after the other.
+pub fn join<S: AsRef<str>>(cards: &[S]) -> String {
+    cards
+        .iter()
+        .map(|card| format!("{}\r\n", card.as_ref().trim_end()))
+        .collect()
+}
+
+/// Builds a card from its properties, wrapped between `BEGIN` and `END`.
+pub fn build(props: &[Property]) -> String {
+    let mut lines = vec![String::from("BEGIN:VCARD")];
+    lines.extend(props.iter().map(Property::to_string));
+    lines.push(String::from("END:VCARD"));
+
+    lines
+        .into_iter()
+        .map(|line| format!("{}\r\n", line))
+        .collect()
+}
+
+/// Checks if a content line ends with a quoted-printable soft line break, in
+/// which case the next line is its continuation (vCard 2.1).
+pub fn has_soft_break(line: &str) -> bool {
+    line.ends_with('=')
+        && Property::parse(line)
+            .and_then(|prop| prop.encoding())
+            .map(|encoding| encoding == "quoted-printable")
+            .unwrap_or(false)
+}
+
+/// Unfolds lines as described in RFC 6350 §3.2: a line starting with a space
+/// or a tab is the continuation of the previous one. Quoted-printable soft
+/// line breaks are joined as well.
+pub fn unfold(content: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+
+    for line in content.lines() {
+        let line = line.trim_end_matches('\r');
+        match (line.chars().next(), lines.last_mut()) {
+            (Some(' '), Some(last)) | (Some('\t'), Some(last)) => last.push_str(&line[1..]),
+            (_, Some(last)) if has_soft_break(last) && !line.eq_ignore_ascii_case("END:VCARD") => {
+                last.pop();
+                last.push_str(line);
+            }
+            _ => lines.push(line.to_owned()),
+        }
+    }
+
+    lines
+}
+
+/// Content line of a card: `[group.]NAME[;PARAM=VALUE…]:VALUE`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Property {
+    pub group: Option<String>,
+    /// Upper cased name.
+    pub name: String,
+    /// Upper cased names with their raw values. vCard 2.1 parameters
+    /// without name (like `EMAIL;WORK:…`) are stored as `TYPE` ones.
+    pub params: Vec<(String, String)>,
+    /// Raw value, still escaped.
+    pub value: String,
+}
+
+impl Property {
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            group: None,
+            name: name.to_uppercase(),
+            params: vec![],
+            value: value.to_owned(),
+        }
+    }
+
+    /// Parses an unfolded content line. Parameter values can be quoted, so
+    /// that they may contain `:` or `;`.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut in_quotes = false;
+        let mut parts = vec![];
+        let mut part = String::new();
+        let mut value = None;
+
+        for (i, c) in line.char_indices() {
+            match c {
+                '"' => {
+                    in_quotes = !in_quotes;
+                    part.push(c);
+                }
+                ';' if !in_quotes => parts.push(std::mem::take(&mut part)),
+                ':' if !in_quotes => {
+                    parts.push(std::mem::take(&mut part));
+                    value = Some(line[i + 1..].to_owned());
+                    break;
+                }
+                c => part.push(c),
+            }
+        }
+
+        let value = value?;
+        let mut parts = parts.into_iter();
+        let key = parts.next()?;
+        let (group, name) = match key.rfind('.') {
+            Some(i) => (Some(key[..i].to_owned()), key[i + 1..].to_owned()),
+            None => (None, key),
+        };
+        if name.is_empty() {
+            return None;
+        }
+
+        let params = parts
+            .filter(|param| !param.is_empty())
+            .map(|param| match param.find('=') {
+                Some(i) => (param[..i].to_uppercase(), param[i + 1..].to_owned()),
+                None => (String::from("TYPE"), param),
+            })
+            .collect();
+
+        Some(Self {
+            group,
+            name: name.to_uppercase(),
+            params,
+            value,
+        })
+    }
+
+    /// Finds the first value of a parameter, without its quotes.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, val)| val.trim_matches('"'))
+    }
+
+    /// Lists the types of the property, from all its `TYPE` parameters.
+    pub fn types(&self) -> Vec<String> {
+        self.params
+            .iter()
+            .filter(|(key, _)| key == "TYPE")
+            .flat_map(|(_, val)| val.trim_matches('"').split(','))
+            .map(|t| t.to_lowercase())
+            .collect()
+    }
+
+    /// Lower cased encoding, from the `ENCODING` parameter or from a vCard
+    /// 2.1 bare parameter (like `NOTE;QUOTED-PRINTABLE:…`).
+    pub fn encoding(&self) -> Option<String> {
+        const ENCODINGS: [&str; 5] = ["b", "base64", "quoted-printable", "8bit", "7bit"];
+
+        self.param("ENCODING").map(str::to_lowercase).or_else(|| {
+            self.types()
+                .into_iter()
+                .find(|t| ENCODINGS.contains(&t.as_str()))
+        })
+    }
+
+    /// Unescaped value.
+    pub fn text(&self) -> String {
+        unescape(&self.value)
+    }
+
+    /// Unescaped components of a structured value (like `N` or `ADR`).
+    pub fn components(&self) -> Vec<String> {
+        split_unescaped(&self.value, ';')
+    }
+}
+
+impl fmt::Display for Property {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref group) = self.group {
+            write!(f, "{}.", group)?;
+        }
+        write!(f, "{}", self.name)?;
+        for (key, val) in self.params.iter() {
+            write!(f, ";{}={}", key, val)?;
+        }
+        write!(f, ":{}", self.value)
+    }
+}
+
+pub fn unescape(value: &str) -> String {
+    let mut text = String::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') | Some('N') => text.push('\n'),
+                Some(c) => text.push(c),
+                None => text.push('\\'),
+            },
+            c => text.push(c),
+        }
+    }
+
+    text
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(';', "\\;")
+        .replace('\n', "\\n")
+}
+
+/// Splits a raw value on a separator that is not escaped. The resulting
+/// parts are still escaped.
+pub fn split_raw(value: &str, sep: char) -> Vec<String> {
+    let mut parts = vec![];
+    let mut part = String::new();
+    let mut is_escaped = false;
+
+    for c in value.chars() {
+        match c {
+            c if is_escaped => {
+                part.push('\\');
+                part.push(c);
+                is_escaped = false;
+            }
+            '\\' => is_escaped = true,
+            c if c == sep => parts.push(std::mem::take(&mut part)),
+            c => part.push(c),
+        }
+    }
+    if is_escaped {
+        part.push('\\');
+    }
+    parts.push(part);
+
+    parts
+}
+
+/// Splits a raw value on a separator that is not escaped, and unescapes the
+/// resulting components.
+fn split_unescaped(value: &str, sep: char) -> Vec<String> {
+    split_raw(value, sep)
+        .iter()
+        .map(|part| unescape(part))
+        .collect()
+}
+
+/// Parses all the properties of a card, including `BEGIN` and `END`.
+pub fn parse(content: &str) -> Vec<Property> {
+    unfold(content)
+        .iter()
+        .filter_map(|line| Property::parse(line))
+        .collect()
+}
+
+/// Finds the value of the first property matching the given name, ignoring
+/// its parameters and group.
+pub fn prop(content: &str, name: &str) -> Option<String> {
+    parse(content)
+        .into_iter()
+        .find(|prop| prop.name.eq_ignore_ascii_case(name))
+        .map(|prop| prop.value.trim().to_owned())
+}
+
+pub fn version(content: &str) -> Option<Version> {
+    prop(content, "VERSION")?.parse().ok()
+}
+
+pub fn uid(content: &str) -> Option<String> {
+    prop(content, "UID").filter(|uid| !uid.is_empty())
+}
+
+/// Replaces the UID property of a card, or adds one.
+pub fn replace_uid(content: &str, uid: &str) -> String {
+    let props: Vec<_> = parse(content)
+        .into_iter()
+        .filter(|prop| !["BEGIN", "END", "UID"].contains(&prop.name.as_str()))
+        .collect();
+    with_uid(&build(&props), uid)
+}
+
+/// Adds a UID property to a card, just before its `END:VCARD` line.
+pub fn with_uid(content: &str, uid: &str) -> String {
+    match content.rfind("END:VCARD") {
+        Some(i) => format!("{}UID:{}\r\n{}", &content[..i], uid, &content[i..]),
+        None => content.to_owned(),
+    }
+}
@@ -0,0 +1,218 @@
+use chrono::{DateTime, TimeZone, Utc};
+use error_chain::error_chain;
+use log::warn;
+use rusqlite::{Connection, NO_PARAMS};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::{
+    cache::{Cache, CacheItem},
+    local::{model::Card as LocalCard, repository::hash_card},
+};
+
+error_chain! {
+    foreign_links {
+        Json(serde_json::Error);
+        Sqlite(rusqlite::Error);
+    }
+}
+
+/// State of one item on one side of a vdirsyncer pair.
+#[derive(Debug, Default, Deserialize)]
+struct ItemState {
+    #[serde(default)]
+    href: String,
+    #[serde(default)]
+    etag: String,
+}
+
+/// State of one item on both sides of a vdirsyncer pair.
+#[derive(Debug, Default)]
+struct StatusItem {
+    a: ItemState,
+    b: ItemState,
+}
+
+/// Reads the status stored in SQLite, as done by vdirsyncer ≥ 0.16.
+fn read_sqlite_status(path: &Path) -> Result<Vec<StatusItem>> {
+    let conn = Connection::open(path)?;
+    let mut stmt = conn.prepare("SELECT href_a, etag_a, href_b, etag_b FROM status")?;
+    let items = stmt
+        .query_map(NO_PARAMS, |row| {
+            let get = |i| -> rusqlite::Result<String> {
+                Ok(row.get::<_, Option<String>>(i)?.unwrap_or_default())
+            };
+            Ok(StatusItem {
+                a: ItemState {
+                    href: get(0)?,
+                    etag: get(1)?,
+                },
+                b: ItemState {
+                    href: get(2)?,
+                    etag: get(3)?,
+                },
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(items)
+}
+
+/// Reads the legacy JSON status: one `[ident, {"a": {…}, "b": {…}}]` array
+/// per line.
+fn read_json_status(content: &str) -> Result<Vec<StatusItem>> {
+    let mut items = vec![];
+
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let (_, mut item): (String, HashMap<String, Value>) = serde_json::from_str(line)?;
+        let mut side = |key| -> Result<ItemState> {
+            let state = item
+                .remove(key)
+                .chain_err(|| format!("Missing side {:?}", key))?;
+            Ok(serde_json::from_value(state)?)
+        };
+        items.push(StatusItem {
+            a: side("a")?,
+            b: side("b")?,
+        });
+    }
+
+    Ok(items)
+}
+
+fn read_status(path: &Path) -> Result<Vec<StatusItem>> {
+    let content = fs::read(path).chain_err(|| format!("Could not read status file {:?}", path))?;
+
+    if content.starts_with(b"SQLite format 3\0") {
+        read_sqlite_status(path).chain_err(|| "Could not read SQLite status")
+    } else {
+        let content = String::from_utf8(content).chain_err(|| "Invalid utf8 status")?;
+        read_json_status(&content).chain_err(|| "Could not read JSON status")
+    }
+}
+
+/// Extracts the modification date from a vdirsyncer filesystem etag, which
+/// is formatted as `<mtime>;<inode>`. Depending on the version, the mtime is
+/// expressed in seconds or in nanoseconds.
+fn parse_fs_etag(etag: &str) -> Option<DateTime<Utc>> {
+    let mtime = etag.trim_matches('"').split(';').next()?;
+    let mut mtime = mtime.splitn(2, '.');
+    let secs: i64 = mtime.next()?.parse().ok()?;
+    let nsecs = format!("{:0<9}", mtime.next().unwrap_or_default());
+    let nsecs: u32 = nsecs.get(..9)?.parse().ok()?;
+
+    let date = if secs > 100_000_000_000 {
+        Utc.timestamp_opt(secs / 1_000_000_000, (secs % 1_000_000_000) as u32)
+    } else {
+        Utc.timestamp_opt(secs, nsecs)
+    };
+
+    // Out of range dates are unknown, like unparsable ones.
+    date.single()
+}
+
+/// Builds a cache from a vdirsyncer status file, so that the first sync
+/// after a migration does not download nor duplicate every card. By default
+/// the side `a` of the pair is considered local and `b` remote.
+///
+/// Local cards modified since the last vdirsyncer sync are cached without
+/// hash, so that the next sync considers them as changed.
+pub fn import_state(
+    sync_dir: &Path,
+    lcards: &HashMap<String, LocalCard>,
+    path: &Path,
+    swap: bool,
+) -> Result<Cache> {
+    let lcards: HashMap<_, &LocalCard> = lcards
+        .values()
+        .map(|card| (card.path.to_owned(), card))
+        .collect();
+    let mut cache = Cache {
+        ctag: String::new(),
+        cards: HashMap::new(),
+    };
+
+    for item in read_status(path)? {
+        let (local, remote) = if swap {
+            (item.b, item.a)
+        } else {
+            (item.a, item.b)
+        };
+
+        if local.href.is_empty() || remote.href.is_empty() {
+            continue;
+        }
+
+        let lcard = match lcards.get(&sync_dir.join(&local.href)) {
+            Some(lcard) => lcard,
+            None => {
+                warn!("Skipping {}: local card not found", local.href);
+                continue;
+            }
+        };
+
+        // Seconds based mtimes are floats, so they may lose some precision.
+        let is_unchanged = parse_fs_etag(&local.etag)
+            .and_then(|date| (date - lcard.date).num_microseconds())
+            .map(|diff| diff.abs() < 1000)
+            .unwrap_or(false);
+        let (local_date, local_hash) = if is_unchanged {
+            (lcard.date, hash_card(lcard))
+        } else {
+            (Utc.timestamp(0, 0), String::new())
+        };
+
+        let card = CacheItem {
+            name: lcard.name.to_owned(),
+            etag: remote.etag,
+            local_date,
+            remote_date: lcard.date,
+            local_hash,
+        };
+        cache.cards.insert(card.name.to_owned(), card);
+    }
+
+    Ok(cache)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fs_etag_in_seconds() {
+        let date = parse_fs_etag("\"1617181920.123456;1234\"").unwrap();
+        assert_eq!(date, Utc.timestamp_opt(1617181920, 123_456_000).unwrap());
+
+        let date = parse_fs_etag("1617181920;1234").unwrap();
+        assert_eq!(date, Utc.timestamp_opt(1617181920, 0).unwrap());
+    }
+
+    #[test]
+    fn fs_etag_in_nanoseconds() {
+        let date = parse_fs_etag("1617181920123456789;1234").unwrap();
+        assert_eq!(date, Utc.timestamp_opt(1617181920, 123_456_789).unwrap());
+    }
+
+    #[test]
+    fn invalid_fs_etag() {
+        assert_eq!(parse_fs_etag(""), None);
+        assert_eq!(parse_fs_etag("abc;1234"), None);
+        assert_eq!(parse_fs_etag("\"d41d8cd98f00b204e9800998ecf8427e\""), None);
+        assert_eq!(parse_fs_etag("-99999999999999;1"), None);
+        assert_eq!(parse_fs_etag(&format!("{};1", i64::MIN)), None);
+    }
+
+    #[test]
+    fn json_status() {
+        let status = r#"["a.vcf", {"a": {"href": "a.vcf", "etag": "1;2"}, "b": {"href": "/b/a.vcf", "etag": "\"x\""}}]"#;
+        let items = read_json_status(status).unwrap();
+
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].a.href, "a.vcf");
+        assert_eq!(items[0].a.etag, "1;2");
+        assert_eq!(items[0].b.href, "/b/a.vcf");
+        assert_eq!(items[0].b.etag, "\"x\"");
+    }
+}